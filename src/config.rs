@@ -0,0 +1,227 @@
+//! Settings read from `$XDG_CONFIG_HOME/remarkers/config.toml`, which defaults
+//! to `~/.config/remarkers/config.toml`.  They supply the defaults of command
+//! line options, which take precedence.
+//!
+//! Settings at the top level apply to every profile.  A profile, such as one
+//! per tablet, overrides them when selected with `--profile` or `profile`:
+//!
+//! ```toml
+//! profile = "home"
+//! output_dir = "~/Documents/reMarkable"
+//!
+//! [device]
+//! user = "root"
+//!
+//! [profiles.home]
+//! backup_dir = "~/backups/home"
+//! device = { host = "192.168.1.42", identity = "~/.ssh/id_remarkable" }
+//!
+//! [profiles.work]
+//! backup_dir = "~/backups/work"
+//! format = "svg"
+//! stream = { diagnostics = true }
+//! ```
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::device::Connection;
+use crate::OutputFormat;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(try_from = "toml::Table")]
+pub struct Config {
+    /// The profile to use unless `--profile` is given.
+    profile: Option<String>,
+    defaults: Settings,
+    profiles: BTreeMap<String, Settings>,
+}
+
+// the top-level settings are read apart from the profiles, rather than
+// flattened, so that unknown settings are still rejected
+impl TryFrom<toml::Table> for Config {
+    type Error = toml::de::Error;
+
+    fn try_from(mut table: toml::Table) -> Result<Self, Self::Error> {
+        let profile = table.remove("profile").map(|v| v.try_into()).transpose()?;
+        let profiles = table.remove("profiles").map(|v| v.try_into()).transpose()?;
+        Ok(Config {
+            profile,
+            defaults: toml::Value::Table(table).try_into()?,
+            profiles: profiles.unwrap_or_default(),
+        })
+    }
+}
+
+/// Defaults of command line options.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// Tracing directives, like `--log-directive`.
+    pub log_directive: Option<String>,
+    /// How to reach the device.
+    #[serde(default)]
+    pub device: Connection,
+    /// Directory that `sync` copies to, and that `convert` and `ls` read.
+    pub backup_dir: Option<PathBuf>,
+    /// Directory that `convert` writes to.
+    pub output_dir: Option<PathBuf>,
+    /// Format that `convert` writes.
+    pub format: Option<OutputFormat>,
+    /// Directory of the device's page templates.
+    pub templates_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub stream: StreamSettings,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StreamSettings {
+    /// Show diagnostics over the stream.
+    pub diagnostics: Option<bool>,
+}
+
+impl Settings {
+    /// Fills the settings that are unset with those of `fallback`.
+    fn or(self, fallback: Settings) -> Settings {
+        Settings {
+            log_directive: self.log_directive.or(fallback.log_directive),
+            device: self.device.or(fallback.device),
+            backup_dir: self.backup_dir.or(fallback.backup_dir),
+            output_dir: self.output_dir.or(fallback.output_dir),
+            format: self.format.or(fallback.format),
+            templates_dir: self.templates_dir.or(fallback.templates_dir),
+            stream: StreamSettings {
+                diagnostics: self.stream.diagnostics.or(fallback.stream.diagnostics),
+            },
+        }
+    }
+
+    /// Expands a leading `~` in paths, which TOML leaves to the reader.
+    fn expand_home(mut self) -> Settings {
+        let expand = |path: &mut Option<PathBuf>| {
+            if let Some(rest) = path.as_ref().and_then(|p| p.strip_prefix("~").ok()) {
+                *path = dirs::home_dir().map(|home| home.join(rest));
+            }
+        };
+        expand(&mut self.device.identity);
+        expand(&mut self.backup_dir);
+        expand(&mut self.output_dir);
+        expand(&mut self.templates_dir);
+        self
+    }
+}
+
+impl Config {
+    /// The settings of `profile`, or else of the config's default profile, on
+    /// top of the top-level settings.
+    pub fn settings(mut self, profile: Option<&str>) -> Result<Settings> {
+        let settings = match profile.or(self.profile.as_deref()) {
+            Some(name) => {
+                let profile = self.profiles.remove(name).ok_or_else(|| {
+                    let names: Vec<_> = self.profiles.keys().map(String::as_str).collect();
+                    anyhow!(
+                        "no profile {name:?} in the config file, which has: {}",
+                        names.join(", ")
+                    )
+                })?;
+                profile.or(self.defaults)
+            }
+            None => self.defaults,
+        };
+        Ok(settings.expand_home())
+    }
+}
+
+/// The default location of the config file.
+pub fn default_path() -> Option<PathBuf> {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => dirs::home_dir()?.join(".config"),
+    };
+    Some(config_dir.join("remarkers").join("config.toml"))
+}
+
+/// Loads the config file at `path`, or at the default location if `path`
+/// isn't given.  Only a missing default config file is treated as empty.
+pub fn load(path: Option<&Path>) -> Result<Config> {
+    let (path, required) = match path {
+        Some(path) => (path.to_path_buf(), true),
+        None => match default_path() {
+            Some(path) => (path, false),
+            None => return Ok(Config::default()),
+        },
+    };
+
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if !required && e.kind() == std::io::ErrorKind::NotFound => {
+            debug!("no config file at {path:?}");
+            return Ok(Config::default());
+        }
+        Err(e) => return Err(e).context(format!("failed to read config file {path:?}")),
+    };
+    debug!("loading config file {path:?}");
+    toml::from_str(&contents).context(format!("invalid config file {path:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let config = || -> Config {
+            toml::from_str(
+                r#"
+                profile = "home"
+                output_dir = "/output"
+                format = "png"
+                stream = { diagnostics = true }
+
+                [device]
+                host = "remarkable"
+                port = 2222
+
+                [profiles.home]
+                backup_dir = "/backups/home"
+                device = { identity = "/keys/home" }
+
+                [profiles.home.stream]
+                diagnostics = false
+
+                [profiles.work]
+                backup_dir = "/backups/work"
+                format = "svg"
+                device = { host = "192.168.1.42" }
+                "#,
+            )
+            .unwrap()
+        };
+
+        let home = config().settings(None).unwrap();
+        assert_eq!(home.backup_dir, Some(PathBuf::from("/backups/home")));
+        assert_eq!(home.output_dir, Some(PathBuf::from("/output")));
+        assert!(matches!(home.format, Some(OutputFormat::Png)));
+        assert_eq!(home.device.host.as_deref(), Some("remarkable"));
+        assert_eq!(home.device.port, Some(2222));
+        assert_eq!(home.device.identity, Some(PathBuf::from("/keys/home")));
+        assert_eq!(home.stream.diagnostics, Some(false));
+
+        let work = config().settings(Some("work")).unwrap();
+        assert_eq!(work.backup_dir, Some(PathBuf::from("/backups/work")));
+        assert!(matches!(work.format, Some(OutputFormat::Svg)));
+        assert_eq!(work.device.host.as_deref(), Some("192.168.1.42"));
+        assert_eq!(work.device.identity, None);
+        assert_eq!(work.stream.diagnostics, Some(true));
+
+        assert!(config().settings(Some("school")).is_err());
+        assert!(toml::from_str::<Config>("[device]\nhots = \"typo\"").is_err());
+        assert!(toml::from_str::<Config>("output = \"typo\"").is_err());
+        assert!(toml::from_str::<Config>("[profiles.home]\nbackup = \"typo\"").is_err());
+        assert!(load(Some(Path::new("/nonexistent/config.toml"))).is_err());
+    }
+}
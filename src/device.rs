@@ -1,19 +1,34 @@
 use anyhow::{anyhow, Context, Result};
-use ssh2::{Channel, Session};
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use base64::Engine;
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::Deserialize;
+use ssh2::{Channel, CheckResult, HashType, KnownHostFileKind, RenameFlags, Session, Sftp};
 use std::{
-    fs::File,
-    io::{Read, Write},
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
     net::TcpStream,
     path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
     sync::Mutex,
-    time::{Duration, UNIX_EPOCH},
+    time::Duration,
 };
 use tracing::{debug, info, trace, warn};
 
+use crate::sync::{self, Change, FileStat, RemoteFile, SyncOptions, SyncState};
+
 const USB_SOURCE_USER: &str = "root";
 const USB_SOURCE_HOST: &str = "10.11.99.1";
+const SSH_PORT: u16 = 22;
+
+/// Environment variable with the device's SSH password, which is shown in its
+/// settings, for when no key is set up.
+const PASSWORD_ENV: &str = "REMARKERS_PASSWORD";
 
 const USB_SOURCE_ROOT_PATH: &str = "/home/root/.local/share/remarkable/xochitl/";
+const USB_TEMPLATES_PATH: &str = "/usr/share/remarkable/templates/";
 
 const WIDTH: usize = 1872;
 const HEIGHT: usize = 1404;
@@ -39,28 +54,98 @@ const GZIP_ENABLED: bool = false;
 /// true  true  = 520-550 ms per frame
 const SSH2_ENABLED: bool = false;
 
+/// How to reach the device over SSH, as given on the command line or in the
+/// config file.  Unset fields fall back to `~/.ssh/config`, and then to the
+/// device's defaults over USB.
+#[derive(Clone, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Connection {
+    /// Host name or IP address, such as the device's Wi-Fi address, or a
+    /// `Host` alias from `~/.ssh/config`.
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    /// Private key to authenticate with, instead of those from ssh-agent.
+    pub identity: Option<PathBuf>,
+    /// Password to authenticate with if no key is accepted.
+    pub password: Option<String>,
+}
+
+// the password is left out of logs
+impl std::fmt::Debug for Connection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Connection")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("user", &self.user)
+            .field("identity", &self.identity)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+impl Connection {
+    /// Fills the fields that are unset with those of `fallback`.
+    pub fn or(self, fallback: Connection) -> Connection {
+        Connection {
+            host: self.host.or(fallback.host),
+            port: self.port.or(fallback.port),
+            user: self.user.or(fallback.user),
+            identity: self.identity.or(fallback.identity),
+            password: self.password.or(fallback.password),
+        }
+    }
+
+    fn resolve(self) -> Settings {
+        let alias = self.host.unwrap_or(USB_SOURCE_HOST.to_string());
+        let ssh_config = crate::ssh_config::load(&alias);
+        Settings {
+            host: ssh_config.host_name.unwrap_or(alias.clone()),
+            port: self.port.or(ssh_config.port).unwrap_or(SSH_PORT),
+            user: self
+                .user
+                .or(ssh_config.user)
+                .unwrap_or(USB_SOURCE_USER.to_string()),
+            identities: match self.identity {
+                Some(identity) => vec![crate::ssh_config::expand_path(
+                    &identity.to_string_lossy(),
+                    &alias,
+                )],
+                None => ssh_config.identity_files,
+            },
+            password: self.password.or(std::env::var(PASSWORD_ENV).ok()),
+        }
+    }
+}
+
+/// The settings that a connection was made with, shared by the `ssh2`
+/// session and the `ssh` commands.
+struct Settings {
+    host: String,
+    port: u16,
+    user: String,
+    identities: Vec<PathBuf>,
+    password: Option<String>,
+}
+
 /// Logical representation of the Remarkable, connected via SSH
 pub struct Remarkable {
     ssh_session: Session,
+    settings: Settings,
+    /// Whether the session was authenticated by password, which the `ssh`
+    /// binary can't be given, so that commands are run over the session.
+    password_auth: bool,
 }
 
 impl Remarkable {
-    pub fn open() -> Result<Self> {
-        trace!("Connecting to Remarkable at {USB_SOURCE_HOST}:22");
-        let tcp = TcpStream::connect(format!("{USB_SOURCE_HOST}:22"))?;
-        trace!("Established TCP connection to Remarkable");
-        let mut ssh_session = Session::new()?;
-        ssh_session.set_tcp_stream(tcp);
-        ssh_session.handshake()?;
-        ssh_session.userauth_pubkey_file(
-            USB_SOURCE_USER,
-            Some(&PathBuf::from("/Users/greenwd/.ssh/id_rsa_remarkable.pub")),
-            &PathBuf::from("/Users/greenwd/.ssh/id_rsa_remarkable"),
-            None,
-        )?;
-
-        trace!("Connected to Remarkable at {USB_SOURCE_HOST}:22");
-        Ok(Self { ssh_session })
+    pub fn open(connection: Connection) -> Result<Self> {
+        let settings = connection.resolve();
+        let (ssh_session, password_auth) = connect(&settings)?;
+        Ok(Self {
+            ssh_session,
+            settings,
+            password_auth,
+        })
     }
 
     async fn ssh_cmd_with_stdout<T: CmdOutput>(&self, cmd: &str, timeout: Duration) -> Result<T> {
@@ -68,7 +153,7 @@ impl Remarkable {
 
         let start = std::time::Instant::now();
 
-        if SSH2_ENABLED {
+        if SSH2_ENABLED || self.password_auth {
             let mut ssh_channel = self.ssh_session.channel_session()?;
             debug!("SSH channel opened in {:?}", start.elapsed());
             ssh_channel.exec(cmd)?;
@@ -82,10 +167,18 @@ impl Remarkable {
             debug!("SSH channel closed in {:?}", start.elapsed());
             Ok(output)
         } else {
-            let cmd = tokio::process::Command::new("ssh")
-                .arg(format!("{USB_SOURCE_USER}@{USB_SOURCE_HOST}"))
-                .arg(cmd)
-                .output();
+            let settings = &self.settings;
+            let mut ssh = tokio::process::Command::new("ssh");
+            // never prompt, which would only run into the timeout
+            ssh.args(["-o", "BatchMode=yes"])
+                .arg("-p")
+                .arg(settings.port.to_string())
+                .arg("-l")
+                .arg(&settings.user);
+            for identity in &settings.identities {
+                ssh.arg("-i").arg(identity);
+            }
+            let cmd = ssh.arg(&settings.host).arg(cmd).output();
 
             let output = tokio::time::timeout(timeout, cmd)
                 .await
@@ -96,90 +189,541 @@ impl Remarkable {
         }
     }
 
-    pub fn rsync_from_device_to<P: AsRef<Path>>(&self, to_local_dir: P) -> Result<()> {
-        self.rsync_from_device_dir_to(USB_SOURCE_ROOT_PATH, to_local_dir)
-            .map(|_stats| ())
+    pub fn rsync_from_device_to<P: AsRef<Path>>(
+        &self,
+        to_local_dir: P,
+        options: &SyncOptions,
+    ) -> Result<()> {
+        self.rsync_from_device_dir_to(USB_SOURCE_ROOT_PATH, to_local_dir, &|_| true, options)
+    }
+
+    /// Copies only the files that describe the notebooks on the device, without
+    /// their pages, which is enough to list them.
+    pub fn sync_metadata_to<P: AsRef<Path>>(&self, to_local_dir: P) -> Result<()> {
+        let is_metadata = |path: &Path| {
+            matches!(
+                path.extension().and_then(OsStr::to_str),
+                Some("metadata" | "content" | "pagedata")
+            )
+        };
+        self.rsync_from_device_dir_to(
+            USB_SOURCE_ROOT_PATH,
+            to_local_dir,
+            &is_metadata,
+            &SyncOptions::default(),
+        )
     }
 
+    /// Copies the page templates from the device, so that they can be drawn
+    /// under converted pages.
+    pub fn sync_templates_to<P: AsRef<Path>>(&self, to_local_dir: P) -> Result<()> {
+        self.rsync_from_device_dir_to(
+            USB_TEMPLATES_PATH,
+            to_local_dir,
+            &|_| true,
+            &SyncOptions::default(),
+        )
+    }
+
+    /// Copies the files in `from_device_dir` accepted by `filter`, and all
+    /// directories below it, to `to_local_dir`.
     fn rsync_from_device_dir_to<P0: AsRef<Path>, P1: AsRef<Path>>(
         &self,
         from_device_dir: P0,
         to_local_dir: P1,
-    ) -> Result<(u32, u32, u32)> {
+        filter: &dyn Fn(&Path) -> bool,
+        options: &SyncOptions,
+    ) -> Result<()> {
         let remote_dir = from_device_dir.as_ref();
         let local_dir = to_local_dir.as_ref();
         info!("syncing reMarkable tablet content to local directory: {local_dir:?}");
-        std::fs::create_dir_all(local_dir)?;
 
         let ftp = self.ssh_session.sftp()?;
-        let root_dir = ftp.readdir(&remote_dir)?;
-
-        let mut created = 0;
-        let mut updated = 0;
-        let mut skipped = 0;
-        for (path, stat) in root_dir {
-            debug!("Sync evaluating {path:?}");
-            let rel_path = path.strip_prefix(&remote_dir)?;
-            let local_path = local_dir.join(rel_path);
-
-            if stat.is_dir() {
-                debug!("Traversing remote directory {path:?}");
-                let (inner_created, inner_updated, inner_skipped) =
-                    self.rsync_from_device_dir_to(&path, local_path)?;
-                created += inner_created;
-                updated += inner_updated;
-                skipped += inner_skipped;
-            } else {
-                debug!("Encountered file, checking local filesystem for {local_path:?}");
-                match std::fs::metadata(&local_path) {
-                    Ok(meta) => {
-                        let remote_mod = Duration::from_secs(stat.mtime.ok_or(anyhow!(""))?);
-                        let local_mod = meta.modified()?.duration_since(UNIX_EPOCH)?;
-                        debug!(
-                            "Sync encountered remote_mod={remote_mod:?}, local_mod={local_mod:?}"
-                        );
-
-                        if remote_mod > local_mod {
-                            debug!("Syncing based on newer mtime: {rel_path:?} to {local_path:?}");
-                            let mut remote_file = ftp.open(&path)?;
-                            let mut local_file = File::create(&local_path)?;
-                            std::io::copy(&mut remote_file, &mut local_file)?;
-                            updated += 1;
-                        } else {
-                            debug!("Syncing based on older mtime: {rel_path:?} to {local_path:?}");
-                            skipped += 1;
-                        }
-                    }
-                    Err(e) => {
-                        debug!("Error fetching file metadata: {e:?}");
-                        if e.kind() == std::io::ErrorKind::NotFound {
-                            debug!(
-                                "Creating based on missing local file: {rel_path:?} to {local_path:?}"
-                            );
-                            let mut remote_file = ftp.open(&path)?;
-                            let mut local_file = File::create(&local_path)?;
-                            std::io::copy(&mut remote_file, &mut local_file).map_err(|e| {
-                                debug!("Error copying from remote to local: {e:?}");
-                                anyhow!("Error copying from remote to local: {e:?}")
-                            })?;
-                            created += 1;
-                        } else {
-                            return Err(e.into());
+        let mut remote_files = Vec::new();
+        let mut remote_dirs = Vec::new();
+        list_remote(
+            &ftp,
+            remote_dir,
+            remote_dir,
+            filter,
+            &mut remote_files,
+            &mut remote_dirs,
+        )?;
+        let mut state = SyncState::load(local_dir);
+        let changes = sync::plan(
+            &remote_files,
+            local_dir,
+            &state,
+            filter,
+            options.delete,
+            options.snapshot_dir.as_deref(),
+        )?;
+
+        sync::check_deletions(&changes, &remote_files, options.force)?;
+
+        if options.dry_run {
+            for change in &changes {
+                println!("{change}");
+            }
+            return Ok(());
+        }
+
+        // files are deleted first, since that removes the folders that they
+        // leave empty, which are then created again if they are on the device
+        let snapshot_dir = options.snapshot_dir.as_deref().map(sync::snapshot_dir);
+        let mut deleted = 0;
+        for change in &changes {
+            if let Change::Delete(path) = change {
+                sync::remove(local_dir, path, snapshot_dir.as_deref())?;
+                state.remove(path);
+                deleted += 1;
+            }
+        }
+
+        std::fs::create_dir_all(local_dir)?;
+        for dir in &remote_dirs {
+            std::fs::create_dir_all(local_dir.join(dir))?;
+        }
+
+        let remote_stats: HashMap<_, _> = remote_files
+            .iter()
+            .map(|f| (f.path.as_path(), f.stat))
+            .collect();
+        let downloads: Vec<_> = changes
+            .iter()
+            .filter_map(|change| match change {
+                Change::Create(path) | Change::Update(path) => {
+                    Some((change, path.as_path(), remote_stats[path.as_path()]))
+                }
+                Change::Delete(_) => None,
+            })
+            .collect();
+
+        let progress = if options.progress {
+            let total_bytes = downloads.iter().map(|(_, _, stat)| stat.size).sum();
+            ProgressBar::new(total_bytes).with_style(ProgressStyle::with_template(
+                "{wide_bar} {bytes}/{total_bytes} ({bytes_per_sec}), {msg}, ETA {eta}",
+            )?)
+        } else {
+            ProgressBar::hidden()
+        };
+        let done = AtomicUsize::new(0);
+        progress.set_message(format!("0/{} files", downloads.len()));
+        crate::progress::show(&progress);
+
+        // each connection takes the next file until none are left, and all
+        // stop at the first failure
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let state = Mutex::new(state);
+        let (created, updated) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        let worker = |ftp: Sftp| -> Result<()> {
+            while !failed.load(Ordering::Relaxed) {
+                let Some(&(change, path, stat)) =
+                    downloads.get(next.fetch_add(1, Ordering::Relaxed))
+                else {
+                    break;
+                };
+                debug!("Syncing {path:?} to {local_dir:?}");
+                let downloaded = download(&ftp, remote_dir, local_dir, path, stat, &progress)
+                    .context(format!("failed to download {path:?}"))
+                    .and_then(|()| state.lock().unwrap().record(local_dir, path, stat));
+                if let Err(e) = downloaded {
+                    failed.store(true, Ordering::Relaxed);
+                    return Err(e);
+                }
+                let count = match change {
+                    Change::Create(_) => &created,
+                    _ => &updated,
+                };
+                count.fetch_add(1, Ordering::Relaxed);
+                let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+                progress.set_message(format!("{done}/{} files", downloads.len()));
+            }
+            Ok(())
+        };
+
+        let jobs = options.jobs.clamp(1, downloads.len().max(1));
+        let connections = AtomicUsize::new(1);
+        let downloaded = std::thread::scope(|scope| {
+            let workers: Vec<_> = (1..jobs)
+                .map(|_| {
+                    scope.spawn(|| {
+                        // libssh2 serializes the channels of a session, so each
+                        // worker connects on its own
+                        let ftp =
+                            connect(&self.settings).and_then(|(session, _)| Ok(session.sftp()?));
+                        match ftp {
+                            Ok(ftp) => {
+                                connections.fetch_add(1, Ordering::Relaxed);
+                                worker(ftp)
+                            }
+                            Err(e) => {
+                                warn!("failed to open a download connection: {e:#}");
+                                Ok(())
+                            }
                         }
-                    }
+                    })
+                })
+                .collect();
+            let mut result = worker(ftp);
+            for handle in workers {
+                let worker_result = handle.join().expect("download worker panicked");
+                result = result.and(worker_result);
+            }
+            result
+        });
+        crate::progress::finish(&progress);
+        let connections = connections.into_inner();
+        if connections < jobs {
+            warn!("downloaded over {connections} of {jobs} connections");
+        } else if jobs > 1 {
+            info!("downloaded over {jobs} connections");
+        }
+
+        // the files downloaded so far are recorded even if the sync fails
+        let mut state = state.into_inner().unwrap();
+        state.retain(&remote_files);
+        state.save()?;
+        downloaded?;
+        sync::remove_partial(local_dir)?;
+
+        let (created, updated) = (created.into_inner(), updated.into_inner());
+        let skipped = remote_files.len() - created - updated;
+        info!(
+            "Sync created {created} files, updated {updated} files, deleted {deleted} files, \
+             skipped {skipped} files"
+        );
+        Ok(())
+    }
+
+    /// Removes `files`, relative to the directory of the device's documents,
+    /// skipping those that aren't there.
+    pub fn remove_document_files(&self, files: &[PathBuf]) -> Result<()> {
+        let root = Path::new(USB_SOURCE_ROOT_PATH);
+        let ftp = self.ssh_session.sftp()?;
+        for file in files {
+            let path = root.join(file);
+            if ftp.stat(&path).is_ok() {
+                debug!("removing {path:?}");
+                ftp.unlink(&path)
+                    .context(format!("failed to remove {path:?} from the device"))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists the files in the directory of the document `id` on the device,
+    /// which holds its pages, relative to that directory.
+    pub fn document_page_files(&self, id: &str) -> Result<Vec<RemoteFile>> {
+        let dir = Path::new(USB_SOURCE_ROOT_PATH).join(id);
+        let ftp = self.ssh_session.sftp()?;
+        let mut files = Vec::new();
+        if ftp.stat(&dir).is_ok_and(|stat| stat.is_dir()) {
+            list_remote(&ftp, &dir, &dir, &|_| true, &mut files, &mut Vec::new())?;
+        }
+        Ok(files)
+    }
+
+    /// Uploads `files`, relative to `local_dir`, into the directory of the
+    /// device's documents, creating the directories that they are in.  Each
+    /// file is written under a temporary name and then renamed, so that a
+    /// partial file never replaces a complete one.
+    pub fn upload_document_files(&self, local_dir: &Path, files: &[PathBuf]) -> Result<()> {
+        let root = Path::new(USB_SOURCE_ROOT_PATH);
+        let ftp = self.ssh_session.sftp()?;
+        let mut dirs = HashSet::new();
+        for file in files {
+            let mut parents: Vec<_> = file
+                .ancestors()
+                .skip(1)
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .collect();
+            parents.reverse();
+            for dir in parents {
+                let remote_dir = root.join(dir);
+                if dirs.insert(remote_dir.clone()) && ftp.stat(&remote_dir).is_err() {
+                    ftp.mkdir(&remote_dir, 0o755)
+                        .context(format!("failed to create {remote_dir:?} on the device"))?;
                 }
             }
+
+            debug!("uploading {file:?} to {:?}", root.join(file));
+            let mut local_file = File::open(local_dir.join(file))
+                .context(format!("failed to open {:?}", local_dir.join(file)))?;
+            upload(&ftp, &mut local_file, &root.join(file))?;
+        }
+        Ok(())
+    }
+
+    /// Runs a command on the device, failing if it exits with an error.
+    pub fn run(&self, cmd: &str) -> Result<String> {
+        debug!("Executing SSH cmd: {cmd}");
+        let mut ssh_channel = self.ssh_session.channel_session()?;
+        ssh_channel.exec(cmd)?;
+        let mut stdout = String::new();
+        ssh_channel.read_to_string(&mut stdout)?;
+        let mut stderr = String::new();
+        ssh_channel.stderr().read_to_string(&mut stderr)?;
+        ssh_channel.wait_close()?;
+
+        match ssh_channel.exit_status()? {
+            0 => Ok(stdout),
+            status => Err(anyhow!(
+                "{cmd:?} failed on the device with status {status}: {}",
+                stderr.trim()
+            )),
         }
+    }
+
+    /// Restarts the device's UI, which reads the documents when it starts.
+    pub fn restart_xochitl(&self) -> Result<()> {
+        info!("restarting xochitl");
+        self.run("systemctl restart xochitl").map(|_stdout| ())
+    }
 
-        info!("Sync created {created} files, updated {updated} files, skipped {skipped} files");
-        Ok((created, updated, skipped))
+    /// Stops the device's UI, so that it doesn't write the documents while
+    /// they are changed.  Fails unless it is stopped afterwards.
+    pub fn stop_xochitl(&self) -> Result<()> {
+        info!("stopping xochitl");
+        self.run("systemctl stop xochitl")?;
+        // is-active exits with an error for stopped units, which isn't one here
+        let state = self.run("systemctl is-active xochitl || true")?;
+        match state.trim() {
+            "inactive" | "failed" => Ok(()),
+            state => Err(anyhow!("xochitl is still {state} after stopping it")),
+        }
     }
 
-    pub async fn streamer(&self) -> Result<RemarkableStreamer> {
+    pub fn start_xochitl(&self) -> Result<()> {
+        info!("starting xochitl");
+        self.run("systemctl start xochitl").map(|_stdout| ())
+    }
+
+    pub async fn streamer(&self) -> Result<RemarkableStreamer<'_>> {
         RemarkableStreamer::new(self).await
     }
 }
 
+/// Opens an SSH session to the device.  Returns the session and whether it
+/// was authenticated by password.
+fn connect(settings: &Settings) -> Result<(Session, bool)> {
+    let address = format!("{}:{}", settings.host, settings.port);
+    trace!("Connecting to Remarkable at {address}");
+    let tcp = TcpStream::connect(&address)
+        .context(format!("failed to connect to the device at {address}"))?;
+    trace!("Established TCP connection to Remarkable");
+    let mut ssh_session = Session::new()?;
+    ssh_session.set_tcp_stream(tcp);
+    ssh_session.handshake()?;
+    check_host_key(&ssh_session, settings)?;
+    let password_auth = authenticate(&ssh_session, settings)?;
+
+    trace!("Connected to Remarkable at {address}");
+    Ok((ssh_session, password_auth))
+}
+
+/// Checks the device's host key against `~/.ssh/known_hosts`, as `ssh` does,
+/// so that neither keys nor the password are offered to another machine.
+fn check_host_key(session: &Session, settings: &Settings) -> Result<()> {
+    let (host, port) = (&settings.host, settings.port);
+    let (key, _) = session
+        .host_key()
+        .ok_or(anyhow!("{host} didn't send a host key"))?;
+    let fingerprint = session
+        .host_key_hash(HashType::Sha256)
+        .map(|hash| format!("SHA256:{}", STANDARD_NO_PAD.encode(hash)))
+        .unwrap_or_default();
+
+    let path = dirs::home_dir()
+        .unwrap_or_default()
+        .join(".ssh")
+        .join("known_hosts");
+    let mut known_hosts = session.known_hosts()?;
+    match std::fs::read_to_string(&path) {
+        // lines are read one by one, since libssh2 gives up on the whole file
+        // at the first key type that it doesn't know
+        Ok(contents) => {
+            for line in contents.lines() {
+                if let Err(e) = known_hosts.read_str(line, KnownHostFileKind::OpenSSH) {
+                    trace!("skipping line of {path:?}: {e}");
+                }
+            }
+        }
+        Err(e) => debug!("not reading {path:?}: {e}"),
+    }
+
+    match known_hosts.check_port(host, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(anyhow!(
+            "the host key of {host} ({fingerprint}) doesn't match the one in {path:?}, \
+             which may mean that another machine answers at its address.  If the device \
+             was reset, remove its old key with `ssh-keygen -R {host}`"
+        )),
+        CheckResult::NotFound => Err(anyhow!(
+            "{host} isn't a known host, its key is {fingerprint}.  Add it to {path:?} by \
+             connecting once with `ssh -p {port} {}@{host}`",
+            settings.user
+        )),
+        CheckResult::Failure => Err(anyhow!("failed to check the host key of {host}")),
+    }
+}
+
+/// Keys that `ssh` tries when none are configured.
+const DEFAULT_IDENTITIES: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+
+/// Authenticates with the configured keys, or else those of ssh-agent and the
+/// default keys, and then with the password.  Returns whether the password
+/// was used.
+fn authenticate(session: &Session, settings: &Settings) -> Result<bool> {
+    let user = &settings.user;
+    let mut tried = Vec::new();
+
+    let identities = if settings.identities.is_empty() {
+        match session.userauth_agent(user) {
+            Ok(()) => return Ok(false),
+            Err(e) => debug!("ssh-agent authentication failed: {e}"),
+        }
+        tried.push("ssh-agent".to_string());
+        let ssh_dir = dirs::home_dir().unwrap_or_default().join(".ssh");
+        DEFAULT_IDENTITIES
+            .iter()
+            .map(|name| ssh_dir.join(name))
+            .filter(|path| path.exists())
+            .collect()
+    } else {
+        settings.identities.clone()
+    };
+
+    for identity in identities {
+        match session.userauth_pubkey_file(user, None, &identity, None) {
+            Ok(()) => return Ok(false),
+            Err(e) => debug!("authentication with {identity:?} failed: {e}"),
+        }
+        tried.push(format!("{identity:?}"));
+    }
+
+    if let Some(password) = &settings.password {
+        match session.userauth_password(user, password) {
+            Ok(()) => return Ok(true),
+            Err(e) => debug!("password authentication failed: {e}"),
+        }
+        tried.push("password".to_string());
+    }
+
+    Err(anyhow!(
+        "failed to authenticate as {user}@{}, tried: {}.  Set a key with --identity, \
+         or the password in ${PASSWORD_ENV}",
+        settings.host,
+        if tried.is_empty() {
+            "nothing".to_string()
+        } else {
+            tried.join(", ")
+        },
+    ))
+}
+
+/// Writes `contents` to `path` on the device under a temporary name, which
+/// is then renamed over it.  The temporary file is removed if that fails.
+fn upload(ftp: &Sftp, contents: &mut dyn Read, path: &Path) -> Result<()> {
+    let mut partial_path = path.as_os_str().to_owned();
+    partial_path.push(".part");
+    let partial_path = PathBuf::from(partial_path);
+
+    let uploaded = (|| -> Result<()> {
+        let mut remote_file = ftp
+            .create(&partial_path)
+            .context(format!("failed to create {partial_path:?} on the device"))?;
+        std::io::copy(contents, &mut remote_file)
+            .context(format!("failed to write {partial_path:?} on the device"))?;
+        drop(remote_file);
+
+        let flags = RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE;
+        if ftp.rename(&partial_path, path, Some(flags)).is_err() {
+            // servers without POSIX renames can't rename over a file
+            let _ = ftp.unlink(path);
+            ftp.rename(&partial_path, path, Some(flags))
+                .context(format!("failed to move {partial_path:?} into place"))?;
+        }
+        Ok(())
+    })();
+    if uploaded.is_err() && ftp.unlink(&partial_path).is_ok() {
+        debug!("removed {partial_path:?} from the device");
+    }
+    uploaded
+}
+
+/// Downloads `path` from below `remote_dir` to below `local_dir`, through a
+/// partial file that is only moved into place once complete.  A partial file
+/// of the same version left by an interrupted sync is resumed.
+fn download(
+    ftp: &Sftp,
+    remote_dir: &Path,
+    local_dir: &Path,
+    path: &Path,
+    stat: FileStat,
+    progress: &ProgressBar,
+) -> Result<()> {
+    let partial_path = sync::partial_path(local_dir, path, stat);
+    if let Some(parent) = partial_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut partial = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&partial_path)
+        .context(format!("failed to open {partial_path:?}"))?;
+    let mut offset = partial.metadata()?.len();
+    if offset > stat.size {
+        partial.set_len(0)?;
+        offset = 0;
+    } else if offset > 0 {
+        debug!("Resuming {path:?} at byte {offset}");
+    }
+    progress.inc(offset);
+
+    let mut remote_file = ftp.open(remote_dir.join(path))?;
+    remote_file.seek(SeekFrom::Start(offset))?;
+    std::io::copy(&mut progress.wrap_read(remote_file), &mut partial)?;
+    drop(partial);
+    std::fs::rename(&partial_path, local_dir.join(path))
+        .context(format!("failed to move {partial_path:?} into place"))?;
+    Ok(())
+}
+
+/// Lists the files below `dir` accepted by `filter`, and all directories,
+/// relative to `root`.
+fn list_remote(
+    ftp: &Sftp,
+    root: &Path,
+    dir: &Path,
+    filter: &dyn Fn(&Path) -> bool,
+    out: &mut Vec<RemoteFile>,
+    dirs: &mut Vec<PathBuf>,
+) -> Result<()> {
+    for (path, stat) in ftp.readdir(dir)? {
+        trace!("Sync evaluating {path:?}");
+        if stat.is_dir() {
+            debug!("Traversing remote directory {path:?}");
+            dirs.push(path.strip_prefix(root)?.to_path_buf());
+            list_remote(ftp, root, &path, filter, out, dirs)?;
+        } else if !filter(&path) {
+            trace!("Sync ignoring {path:?}");
+        } else {
+            out.push(RemoteFile {
+                path: path.strip_prefix(root)?.to_path_buf(),
+                stat: FileStat {
+                    size: stat.size.unwrap_or_default(),
+                    mtime: stat
+                        .mtime
+                        .ok_or(anyhow!("no modification time for {path:?}"))?,
+                },
+            });
+        }
+    }
+    Ok(())
+}
+
 trait CmdOutput: Default {
     fn from_vec(vec: Vec<u8>) -> Self;
     fn read_from_channel(channel: &mut Channel) -> Result<Self>;
@@ -251,17 +795,13 @@ impl<'a> RemarkableStreamer<'a> {
             .ssh_cmd_with_stdout(&cmd, METADATA_COMMAND_TIMEOUT)
             .await?;
 
-        let fb0_line = stdout
-            .split('\n')
-            .filter(|line| line.contains("/dev/fb0"))
-            .next();
+        let fb0_line = stdout.split('\n').find(|line| line.contains("/dev/fb0"));
         debug!("line containing /dev/fb0: {fb0_line:?}");
 
         let addr = fb0_line
             .ok_or(anyhow!("failed to find /dev/fb0 in /proc/{pid}/maps"))?
             .split(['-', ' '])
-            .skip(1)
-            .next()
+            .nth(1)
             .ok_or(anyhow!(
                 "failed to find frame buffer offset in [{fb0_line:?}]"
             ))?;
@@ -323,9 +863,8 @@ impl<'a> RemarkableStreamer<'a> {
                 // in the case of a failure to complete the dd command
                 // we want to refresh the pid and frame buffer offset
                 // just in case they've changed
-                let mut cached_stream_info =
-                    self.stream_info.lock().expect("failed to lock stream info");
-                *cached_stream_info = RemarkableStreamer::stream_info(&self.remarkable).await?;
+                let stream_info = RemarkableStreamer::stream_info(self.remarkable).await?;
+                *self.stream_info.lock().expect("failed to lock stream info") = stream_info;
 
                 Err(e)
             }
@@ -1,17 +1,30 @@
 //! Scans a directory for notebooks and pages
-use std::{ffi::OsStr, fs::File, io::BufReader, path::Path};
+use std::{collections::HashMap, ffi::OsStr, fs::File, io::BufReader, path::Path};
 
 use anyhow::{Context, Result};
-use tracing::trace;
+use tracing::{trace, warn};
 
 use crate::model::fs::{
-    serde::{ElementType, NotebookContent, NotebookContentRaw, NotebookMetadata},
-    Notebook, Notebooks, Page,
+    serde::{
+        template, ElementType, FileType, NotebookContent, NotebookContentRaw, NotebookMetadata,
+        PageContent,
+    },
+    Collection, Notebook, Notebooks, Page, Parent, ScanError,
 };
 
+/// What a `.metadata` file describes.
+enum Entry {
+    Collection(String, Collection),
+    Notebook(Notebook),
+}
+
+/// Reads the notebooks and folders in `root`.  Notebooks whose files can't be
+/// read are left out, and returned as errors alongside the others.
 pub fn scan<T: AsRef<Path>>(root: T) -> Result<Notebooks> {
     let mut notebooks = Vec::new();
-    let entries = std::fs::read_dir(root)?;
+    let mut collections = HashMap::new();
+    let mut failed = Vec::new();
+    let entries = std::fs::read_dir(root.as_ref())?;
     for entry in entries {
         let meta_path = match entry {
             Ok(e) => {
@@ -24,56 +37,227 @@ pub fn scan<T: AsRef<Path>>(root: T) -> Result<Notebooks> {
             Err(_) => continue,
         };
 
-        let mut dir_path = meta_path.clone();
-        dir_path.set_extension("");
-
-        // Open the file in read-only mode with buffer.
-        let meta_file = File::open(&meta_path)
-            .context(format!("failed to open .metadata file at {meta_path:?}"))?;
-        let meta_reader = BufReader::new(meta_file);
-
-        let meta: NotebookMetadata = serde_json::from_reader(meta_reader)?;
-        if meta.element_type == ElementType::CollectionType {
-            continue;
-        }
-
-        // read the associated .content file
-        let mut content_path = meta_path.clone();
-        content_path.set_extension("content");
-        let content_file = File::open(&content_path)
-            .context(format!("failed to open .content file at {content_path:?}"))?;
-        let content_reader = BufReader::new(content_file);
-
-        let content: NotebookContentRaw = serde_json::from_reader(content_reader)?;
-        let content: NotebookContent = content.into();
-
-        let pages = match &content.pages {
-            Some(pages) => pages.clone(),
-            None => {
-                // if there's no pages declared in metadata then we assume
-                // that there's a single .rm file in the associated directory
-                trace!("looking for single page in {dir_path:?}");
-                std::fs::read_dir(&dir_path)
-                    .context(format!("failed to read directory at {dir_path:?}"))?
-                    .flat_map(|e| e.ok())
-                    .map(|e| e.path())
-                    .filter(|p| p.extension().and_then(OsStr::to_str) == Some("rm"))
-                    .map(|p| p.to_str().unwrap().to_string())
-                    .collect()
+        let id = meta_path
+            .file_stem()
+            .and_then(OsStr::to_str)
+            .unwrap_or_default()
+            .to_string();
+        let meta = match read_metadata(&meta_path) {
+            Ok(meta) => meta,
+            Err(error) => {
+                warn!("skipping {meta_path:?}: {error:#}");
+                failed.push(ScanError {
+                    name: id.clone(),
+                    id,
+                    parent: Parent::Root,
+                    error,
+                });
+                continue;
             }
         };
 
-        let pages: Vec<_> = pages.into_iter().map(|p| Page { id: p }).collect();
-
-        notebooks.push(Notebook {
-            name: meta.visible_name,
-            root: dir_path,
-            pages,
-        });
+        match read_entry(&meta_path, id.clone(), &meta) {
+            Ok(Entry::Collection(id, collection)) => {
+                collections.insert(id, collection);
+            }
+            Ok(Entry::Notebook(notebook)) => notebooks.push(notebook),
+            Err(error) => {
+                warn!("skipping {:?}: {error:#}", meta.visible_name);
+                failed.push(ScanError {
+                    id,
+                    name: meta.visible_name,
+                    parent: meta.parent.as_str().into(),
+                    error,
+                });
+            }
+        }
     }
 
+    // directory order is arbitrary, so sort to visit notebooks the same way
+    // on every run
+    notebooks.sort_by(|a, b| (&a.name, &a.id).cmp(&(&b.name, &b.id)));
+    failed.sort_by(|a, b| (&a.name, &a.id).cmp(&(&b.name, &b.id)));
+
     Ok(Notebooks {
-        root: "".into(),
+        root: root.as_ref().to_path_buf(),
+        collections,
         notebooks,
+        failed,
     })
 }
+
+fn read_metadata(meta_path: &Path) -> Result<NotebookMetadata> {
+    // Open the file in read-only mode with buffer.
+    let meta_file =
+        File::open(meta_path).context(format!("failed to open .metadata file at {meta_path:?}"))?;
+    let meta_reader = BufReader::new(meta_file);
+    serde_json::from_reader(meta_reader).context(format!("invalid .metadata file at {meta_path:?}"))
+}
+
+/// Reads the folder or notebook described by the `.metadata` file at `meta_path`.
+fn read_entry(meta_path: &Path, id: String, meta: &NotebookMetadata) -> Result<Entry> {
+    let dir_path = meta_path.with_extension("");
+    if meta.element_type == ElementType::CollectionType {
+        return Ok(Entry::Collection(
+            id,
+            Collection {
+                name: meta.visible_name.clone(),
+                parent: meta.parent.as_str().into(),
+                last_modified: meta.last_modified.as_ref().and_then(|m| m.to_system_time()),
+                pinned: meta.pinned,
+            },
+        ));
+    }
+
+    // read the associated .content file
+    let content_path = meta_path.with_extension("content");
+    let content_file = File::open(&content_path)
+        .context(format!("failed to open .content file at {content_path:?}"))?;
+    let content_reader = BufReader::new(content_file);
+
+    let content: NotebookContentRaw = serde_json::from_reader(content_reader)
+        .context(format!("invalid .content file at {content_path:?}"))?;
+    let content: NotebookContent = content.into();
+
+    let pages = match &content.pages {
+        Some(pages) => pages.clone(),
+        None => {
+            // if there's no pages declared in metadata then we assume
+            // that there's a single .rm file in the associated directory.
+            // documents that haven't been opened yet have no directory
+            trace!("looking for single page in {dir_path:?}");
+            let entries = match std::fs::read_dir(&dir_path) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+                entries => entries
+                    .context(format!("failed to read directory at {dir_path:?}"))?
+                    .collect(),
+            };
+            entries
+                .into_iter()
+                .flat_map(|e| e.ok())
+                .map(|e| e.path())
+                .filter(|p| p.extension().and_then(OsStr::to_str) == Some("rm"))
+                .map(|p| PageContent {
+                    id: p.to_str().unwrap().to_string(),
+                    source_page: None,
+                    template: None,
+                })
+                .collect()
+        }
+    };
+
+    // older versions keep the page templates in a .pagedata file, one
+    // line per page
+    let legacy_templates = if pages.iter().all(|p| p.template.is_none()) {
+        let pagedata_path = meta_path.with_extension("pagedata");
+        match std::fs::read_to_string(&pagedata_path) {
+            Ok(pagedata) => pagedata.lines().map(template).collect(),
+            Err(_) => Vec::new(),
+        }
+    } else {
+        Vec::new()
+    };
+
+    let pages: Vec<_> = pages
+        .into_iter()
+        .enumerate()
+        .map(|(idx, p)| Page {
+            id: p.id,
+            source_page: p.source_page,
+            template: p
+                .template
+                .or_else(|| legacy_templates.get(idx).cloned().flatten()),
+        })
+        .collect();
+
+    // annotated PDFs and EPUBs are stored as a PDF next to the notebook
+    let base_document = match content.file_type {
+        FileType::Notebook => None,
+        FileType::Pdf | FileType::Epub => {
+            let pdf_path = dir_path.with_extension("pdf");
+            if pdf_path.exists() {
+                Some(pdf_path)
+            } else {
+                warn!(
+                    "base document {pdf_path:?} of {:?} is missing",
+                    meta.visible_name
+                );
+                None
+            }
+        }
+    };
+
+    Ok(Entry::Notebook(Notebook {
+        id,
+        name: meta.visible_name.clone(),
+        parent: meta.parent.as_str().into(),
+        file_type: content.file_type,
+        last_modified: meta.last_modified.as_ref().and_then(|m| m.to_system_time()),
+        pinned: meta.pinned,
+        tags: content.tags,
+        root: dir_path,
+        pages,
+        base_document,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_scan_corrupt_notebook() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        for (name, contents) in [
+            (
+                "good.metadata",
+                r#"{"visibleName": "Good", "type": "DocumentType"}"#,
+            ),
+            ("good.content", r#"{"pages": ["p1"]}"#),
+            // as pushed, before the device has opened it
+            (
+                "pushed.metadata",
+                r#"{"visibleName": "Pushed", "type": "DocumentType"}"#,
+            ),
+            ("pushed.content", r#"{"fileType": "pdf", "tags": []}"#),
+            (
+                "bad.metadata",
+                r#"{"visibleName": "Bad", "type": "DocumentType", "parent": "f"}"#,
+            ),
+            ("bad.content", r#"{"pages": ["p1""#),
+            ("unreadable.metadata", "{"),
+            (
+                "f.metadata",
+                r#"{"visibleName": "Folder", "type": "CollectionType"}"#,
+            ),
+        ] {
+            std::fs::write(dir.join(name), contents).unwrap();
+        }
+
+        let library = scan(dir).unwrap();
+        let mut notebooks: Vec<_> = library
+            .notebooks
+            .iter()
+            .map(|n| (n.name.as_str(), n.pages.len()))
+            .collect();
+        notebooks.sort();
+        assert_eq!(notebooks, [("Good", 1), ("Pushed", 0)]);
+        assert_eq!(library.collections["f"].name, "Folder");
+
+        let failed: Vec<_> = library
+            .failed
+            .iter()
+            .map(|f| (f.id.as_str(), library.path_in(&f.parent, &f.name)))
+            .collect();
+        assert_eq!(
+            failed,
+            [
+                ("bad", PathBuf::from("Folder/Bad")),
+                ("unreadable", PathBuf::from("unreadable")),
+            ]
+        );
+        assert!(format!("{:#}", library.failed[0].error).contains("bad.content"));
+    }
+}
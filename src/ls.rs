@@ -0,0 +1,244 @@
+//! Lists the folders and notebooks of a library as a tree
+use std::time::SystemTime;
+
+use anyhow::Result;
+use chrono::{DateTime, Local, SecondsFormat};
+use serde::Serialize;
+
+use crate::model::fs::{serde::FileType, Notebooks, Parent, TRASH_FOLDER};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryType {
+    Folder,
+    Notebook,
+    Pdf,
+    Epub,
+}
+
+impl From<FileType> for EntryType {
+    fn from(value: FileType) -> Self {
+        match value {
+            FileType::Notebook => EntryType::Notebook,
+            FileType::Pdf => EntryType::Pdf,
+            FileType::Epub => EntryType::Epub,
+        }
+    }
+}
+
+/// A folder or notebook in the library tree.
+#[derive(Debug, Serialize)]
+pub struct Entry {
+    /// The UUID that the entry's files are named by, empty for the trash.
+    pub id: String,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub entry_type: EntryType,
+    /// Number of pages of a notebook.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pages: Option<usize>,
+    /// Last modification as an RFC 3339 timestamp.
+    pub last_modified: Option<String>,
+    pub pinned: bool,
+    pub tags: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<Entry>,
+}
+
+fn timestamp(time: Option<SystemTime>) -> Option<String> {
+    time.map(|t| DateTime::<Local>::from(t).to_rfc3339_opts(SecondsFormat::Secs, false))
+}
+
+/// Whether an item placed in `item_parent` is listed in the folder `parent`.
+/// Items in folders that don't exist are listed at the root.
+fn listed_in(library: &Notebooks, item_parent: &Parent, parent: &Parent) -> bool {
+    match item_parent {
+        Parent::Collection(id) if !library.collections.contains_key(id) => *parent == Parent::Root,
+        item_parent => item_parent == parent,
+    }
+}
+
+/// Builds the entries in the folder `parent`, folders first and each sorted
+/// by name.  Folders that are their own ancestors are never reached.
+fn children(library: &Notebooks, parent: &Parent) -> Vec<Entry> {
+    let mut folders: Vec<_> = library
+        .collections
+        .iter()
+        .filter(|(_, c)| listed_in(library, &c.parent, parent))
+        .map(|(id, c)| Entry {
+            id: id.clone(),
+            name: c.name.clone(),
+            entry_type: EntryType::Folder,
+            pages: None,
+            last_modified: timestamp(c.last_modified),
+            pinned: c.pinned,
+            tags: Vec::new(),
+            children: children(library, &Parent::Collection(id.clone())),
+        })
+        .collect();
+    folders.sort_by(|a, b| (&a.name, &a.id).cmp(&(&b.name, &b.id)));
+
+    // notebooks are already sorted by name
+    let notebooks = library
+        .notebooks
+        .iter()
+        .filter(|n| listed_in(library, &n.parent, parent))
+        .map(|n| Entry {
+            id: n.id.clone(),
+            name: n.name.clone(),
+            entry_type: n.file_type.into(),
+            pages: Some(n.pages.len()),
+            last_modified: timestamp(n.last_modified),
+            pinned: n.pinned,
+            tags: n.tags.clone(),
+            children: Vec::new(),
+        });
+
+    folders.into_iter().chain(notebooks).collect()
+}
+
+/// Builds the library tree, with the trash as a last folder at the root if
+/// `include_trash` is set.
+pub fn tree(library: &Notebooks, include_trash: bool) -> Vec<Entry> {
+    let mut entries = children(library, &Parent::Root);
+    if include_trash {
+        entries.push(Entry {
+            id: String::new(),
+            name: TRASH_FOLDER.to_string(),
+            entry_type: EntryType::Folder,
+            pages: None,
+            last_modified: None,
+            pinned: false,
+            tags: Vec::new(),
+            children: children(library, &Parent::Trash),
+        });
+    }
+    entries
+}
+
+fn format_entry(out: &mut Vec<String>, entry: &Entry, depth: usize) {
+    let indent = "  ".repeat(depth);
+    let mut line = match entry.entry_type {
+        EntryType::Folder => format!("{indent}{}/", entry.name),
+        _ => format!("{indent}{}", entry.name),
+    };
+
+    let entry_type = match entry.entry_type {
+        EntryType::Folder => "folder",
+        EntryType::Notebook => "notebook",
+        EntryType::Pdf => "pdf",
+        EntryType::Epub => "epub",
+    };
+    line.push_str(&format!("  [{entry_type}"));
+    if let Some(pages) = entry.pages {
+        let plural = if pages == 1 { "" } else { "s" };
+        line.push_str(&format!(", {pages} page{plural}"));
+    }
+    if let Some(last_modified) = &entry.last_modified {
+        line.push_str(&format!(", {last_modified}"));
+    }
+    line.push(']');
+
+    if entry.pinned {
+        line.push_str(" *");
+    }
+    for tag in &entry.tags {
+        line.push_str(&format!(" #{tag}"));
+    }
+    out.push(line);
+
+    for child in &entry.children {
+        format_entry(out, child, depth + 1);
+    }
+}
+
+/// Formats the tree with one indented line per entry.  Folders end in a
+/// slash, and pinned entries are marked with an asterisk.
+pub fn format_tree(entries: &[Entry]) -> String {
+    let mut out = Vec::new();
+    for entry in entries {
+        format_entry(&mut out, entry, 0);
+    }
+    out.join("\n")
+}
+
+/// Prints the library tree to stdout, as JSON if `json` is set.
+pub fn print(library: &Notebooks, include_trash: bool, json: bool) -> Result<()> {
+    let entries = tree(library, include_trash);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+    } else if !entries.is_empty() {
+        println!("{}", format_tree(&entries));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::fs::{Collection, Notebook};
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    fn notebook(name: &str, parent: &str, file_type: FileType, pages: usize) -> Notebook {
+        Notebook {
+            id: format!("{name}-id"),
+            name: name.to_string(),
+            parent: parent.into(),
+            file_type,
+            last_modified: None,
+            pinned: name == "Todo",
+            tags: vec!["work".to_string()],
+            root: PathBuf::new(),
+            pages: (0..pages)
+                .map(|i| crate::model::fs::Page {
+                    id: i.to_string(),
+                    source_page: None,
+                    template: None,
+                })
+                .collect(),
+            base_document: None,
+        }
+    }
+
+    #[test]
+    fn test_tree() {
+        let library = Notebooks {
+            root: PathBuf::new(),
+            collections: HashMap::from([(
+                "work".to_string(),
+                Collection {
+                    name: "Work".to_string(),
+                    parent: Parent::Root,
+                    last_modified: None,
+                    pinned: false,
+                },
+            )]),
+            notebooks: vec![
+                notebook("Paper", "work", FileType::Pdf, 12),
+                notebook("Scratch", "trash", FileType::Notebook, 2),
+                notebook("Todo", "", FileType::Notebook, 1),
+                notebook("Zombie", "gone", FileType::Epub, 0),
+            ],
+            failed: Vec::new(),
+        };
+
+        assert_eq!(
+            format_tree(&tree(&library, false)),
+            "Work/  [folder]\n  \
+             Paper  [pdf, 12 pages] #work\n\
+             Todo  [notebook, 1 page] * #work\n\
+             Zombie  [epub, 0 pages] #work"
+        );
+
+        let entries = tree(&library, true);
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[3].name, "Trash");
+        assert_eq!(entries[3].children[0].name, "Scratch");
+
+        let json = serde_json::to_value(&entries).unwrap();
+        assert_eq!(json[0]["children"][0]["type"], "pdf");
+        assert_eq!(json[0]["children"][0]["pages"], 12);
+        assert_eq!(json[1]["pinned"], true);
+    }
+}
@@ -1,16 +1,31 @@
-use anyhow::Result;
-use clap::{Parser, Subcommand};
-use tracing::info;
+use anyhow::{anyhow, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use image::ImageFormat;
+use rayon::prelude::*;
+use serde::Deserialize;
+use tracing::{debug, error, info};
 use tracing_subscriber::EnvFilter;
 
+use std::collections::HashSet;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
+mod config;
 mod device;
 mod fs;
+mod ls;
+mod manifest;
 mod model;
 mod parser;
+mod progress;
+mod push;
 mod render;
+mod report;
+mod restore;
+mod ssh_config;
 mod stream;
+mod sync;
 
 const DEFAULT_LOG_DIRECTIVE: [&str; 3] = ["warn", "naga=error", "remarkers=info"];
 
@@ -20,30 +35,191 @@ struct Cli {
     #[arg(short, long)]
     log_directive: Option<String>,
 
+    /// Config file to read instead of `~/.config/remarkers/config.toml`.
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Profile of the config file to take defaults from, instead of the one
+    /// named by its `profile` setting.
+    #[arg(long, global = true)]
+    profile: Option<String>,
+
+    #[command(flatten)]
+    device: DeviceArgs,
+
     #[command(subcommand)]
     command: Command,
 }
 
+/// How to reach the device, overriding the `[device]` section of the config
+/// file.  The password can be set in the config file or `$REMARKERS_PASSWORD`.
+#[derive(Args, Debug)]
+struct DeviceArgs {
+    /// Host name or IP address of the device, e.g. its Wi-Fi address, or a
+    /// host alias from `~/.ssh/config`.  Defaults to its USB address.
+    #[arg(long, global = true)]
+    host: Option<String>,
+    /// SSH port of the device.
+    #[arg(long, global = true)]
+    port: Option<u16>,
+    /// User to log in to the device as.
+    #[arg(long, global = true)]
+    user: Option<String>,
+    /// Private key to log in with, instead of those from ssh-agent.
+    #[arg(long, global = true)]
+    identity: Option<PathBuf>,
+}
+
+impl From<DeviceArgs> for device::Connection {
+    fn from(args: DeviceArgs) -> Self {
+        device::Connection {
+            host: args.host,
+            port: args.port,
+            user: args.user,
+            identity: args.identity,
+            password: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum OutputFormat {
+    /// One PDF document per notebook.
+    Pdf,
+    /// One SVG document per page, in a directory per notebook.
+    Svg,
+    /// One PNG image per page, in a directory per notebook.
+    Png,
+    /// One JPEG image per page, in a directory per notebook.
+    Jpeg,
+    /// One lossless WebP image per page, in a directory per notebook.
+    Webp,
+}
+
 #[derive(Debug, Subcommand)]
 enum Command {
     Sync {
+        /// Defaults to `backup_dir` from the config file.
         #[arg(short, long)]
-        dest_dir: PathBuf,
+        dest_dir: Option<PathBuf>,
+        /// Also copy the device's page templates into this directory.
+        #[arg(long)]
+        templates_dir: Option<PathBuf>,
+        /// Remove files from the backup that were removed from the device.
+        #[arg(long)]
+        delete: bool,
+        /// Print the changes to the backup instead of making them.
+        #[arg(long)]
+        dry_run: bool,
+        /// Move files removed by `--delete` into a directory named after the
+        /// time of the sync in here, instead of deleting them.
+        #[arg(long, requires = "delete")]
+        snapshot_dir: Option<PathBuf>,
+        /// Delete files even when most of the backup would be deleted, or
+        /// nothing was found on the device, which is refused otherwise.
+        #[arg(long, requires = "delete")]
+        force: bool,
+        /// Number of connections to the device to download files over.
+        #[arg(short, long, default_value_t = 4)]
+        jobs: usize,
     },
     Convert {
+        /// Defaults to `backup_dir` from the config file.
         #[arg(short, long)]
-        source_dir: PathBuf,
+        source_dir: Option<PathBuf>,
+        /// Defaults to `output_dir` from the config file, or else `./output`.
         #[arg(short, long)]
         dest_dir: Option<PathBuf>,
         #[arg(short, long)]
         notebook_filter: Option<String>,
         #[arg(short, long)]
         page_filter: Option<String>,
+        /// Also render lines that were erased or undone, to recover deleted content.
+        #[arg(long)]
+        include_deleted: bool,
+        /// Format of the rendered output.  Defaults to `format` from the
+        /// config file, or else PDF.
+        #[arg(short, long, value_enum)]
+        format: Option<OutputFormat>,
+        /// Resolution of raster output.  The default matches the tablet's display.
+        #[arg(long, default_value_t = render::DEVICE_DPI, value_parser = parse_dpi)]
+        dpi: f32,
+        /// Render raster output on a transparent instead of a white background.
+        #[arg(long)]
+        transparent: bool,
+        /// Directory with the device's page templates, as copied by `sync
+        /// --templates-dir`, to draw under the pages.
+        #[arg(long)]
+        templates_dir: Option<PathBuf>,
+        /// Also convert notebooks that were moved to the trash, into a `Trash` folder.
+        #[arg(long)]
+        include_trash: bool,
+        /// Convert every notebook, even those whose output is up to date.
+        #[arg(long)]
+        force: bool,
+        /// Number of threads to convert notebooks and pages on.  Defaults to
+        /// the number of CPUs.
+        #[arg(short, long, default_value_t = default_jobs())]
+        jobs: usize,
+        /// Stop at the first notebook or page that fails to convert, instead
+        /// of converting what can be and reporting the failures at the end.
+        #[arg(long)]
+        strict: bool,
+    },
+    /// List the folders and notebooks of a backup, or of the device.
+    Ls {
+        /// Backup directory to list, as written by `sync`.  Defaults to
+        /// `backup_dir` from the config file.
+        #[arg(short, long)]
+        source_dir: Option<PathBuf>,
+        /// List the notebooks on the device over SSH instead of a backup.
+        #[arg(long, conflicts_with = "source_dir")]
+        device: bool,
+        /// Also list notebooks that were moved to the trash.
+        #[arg(long)]
+        include_trash: bool,
+        /// Print the tree as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Upload a PDF or EPUB document to the device.
+    Push {
+        /// The document, named on the device after its file name.
+        file: PathBuf,
+        /// Folder to put the document in, as a `/`-separated path of folder
+        /// names.  Folders that don't exist are created.
+        #[arg(long)]
+        folder: Option<String>,
+        /// Don't restart the device's UI, which is needed for the document to
+        /// show up, e.g. to restart it once after pushing several.
+        #[arg(long)]
+        no_restart: bool,
+    },
+    /// Upload notebooks from a backup to the device, e.g. after it was reset.
+    Restore {
+        /// Backup directory to restore from, as written by `sync`.  Defaults
+        /// to `backup_dir` from the config file.
+        #[arg(long)]
+        from: Option<PathBuf>,
+        /// Restore only the notebooks of this name, or at this path of
+        /// folders, e.g. `Work/Notes`, instead of all that aren't in the trash.
+        #[arg(long)]
+        notebook: Option<String>,
+        /// Replace notebooks that were changed on the device since the backup.
+        #[arg(long)]
+        overwrite: bool,
+        /// Print the notebooks and folders to restore instead of restoring them.
+        #[arg(long)]
+        dry_run: bool,
     },
     Stream {
         /// Enable diagnostics as an overlay, including frame latency and frame rate.
-        #[arg(short, long)]
+        #[arg(short, long, overrides_with = "no_diagnostics")]
         diagnostics: bool,
+        /// Disable the diagnostics overlay, even if the config file enables it.
+        #[arg(long, overrides_with = "diagnostics")]
+        no_diagnostics: bool,
     },
     Screengrab {
         #[arg(short, long, default_value = "remarkable-frame.png")]
@@ -51,6 +227,39 @@ enum Command {
     },
 }
 
+fn default_jobs() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+fn parse_dpi(dpi: &str) -> Result<f32, String> {
+    let dpi: f32 = dpi.parse().map_err(|e| format!("{e}"))?;
+    if dpi.is_finite() && dpi > 0.0 && dpi <= render::MAX_DPI {
+        Ok(dpi)
+    } else {
+        Err(format!("must be above 0 and at most {}", render::MAX_DPI))
+    }
+}
+
+/// Parses a page filter, which is either a page index or a `start:end` range
+/// of page indices that excludes `end`.
+fn parse_page_filter(
+    page_filter: Option<&str>,
+) -> Result<Arc<dyn Fn(usize) -> bool + Send + Sync>> {
+    Ok(match page_filter {
+        Some(page_filter) if page_filter.contains(":") => {
+            let elems: Vec<_> = page_filter.split(":").collect();
+            let start: usize = elems[0].parse()?;
+            let end: usize = elems[1].parse()?;
+            Arc::new(move |p| p >= start && p < end)
+        }
+        Some(page_filter) => {
+            let page_num: usize = page_filter.parse()?;
+            Arc::new(move |p| p == page_num)
+        }
+        None => Arc::new(|_p| true),
+    })
+}
+
 fn env_filter_from_directives<'a>(
     directives: impl IntoIterator<Item = &'a str>,
 ) -> Result<EnvFilter> {
@@ -61,10 +270,14 @@ fn env_filter_from_directives<'a>(
     Ok(filter)
 }
 
-fn build_env_filter(cli: &Cli) -> Result<EnvFilter> {
+fn build_env_filter(cli: &Cli, settings: &config::Settings) -> Result<EnvFilter> {
     if let Ok(env_var) = std::env::var("RUST_LOG") {
         env_filter_from_directives(env_var.split(","))
-    } else if let Some(directives) = &cli.log_directive {
+    } else if let Some(directives) = cli
+        .log_directive
+        .as_ref()
+        .or(settings.log_directive.as_ref())
+    {
         env_filter_from_directives(directives.split(","))
     } else {
         env_filter_from_directives(DEFAULT_LOG_DIRECTIVE)
@@ -76,60 +289,321 @@ fn build_env_filter(cli: &Cli) -> Result<EnvFilter> {
 async fn main() -> Result<()> {
     let cli = Cli::parse();
 
+    // options that aren't given on the command line are taken from the config
+    // file, which is read first since it can set the log directives
+    let settings = config::load(cli.config.as_deref())?.settings(cli.profile.as_deref())?;
+
+    // logs go to stderr so that they don't mix with output meant for scripts
     tracing_subscriber::fmt()
-        .with_env_filter(build_env_filter(&cli)?)
+        .with_env_filter(build_env_filter(&cli, &settings)?)
+        .with_writer(|| progress::LogWriter)
         .init();
 
     info!("Parsed CLI command: {:?}", cli);
+    debug!("settings from the config file: {settings:?}");
+
+    let connection = device::Connection::from(cli.device).or(settings.device);
+    let backup_dir = |dir: Option<PathBuf>, flag: &str| {
+        dir.or(settings.backup_dir.clone()).ok_or(anyhow!(
+            "{flag} is required unless backup_dir is set in the config file"
+        ))
+    };
 
     match cli.command {
-        Command::Sync { dest_dir } => {
-            let rem = crate::device::Remarkable::open()?;
-            rem.rsync_from_device_to(dest_dir)?;
+        Command::Sync {
+            dest_dir,
+            templates_dir,
+            delete,
+            dry_run,
+            snapshot_dir,
+            force,
+            jobs,
+        } => {
+            let dest_dir = backup_dir(dest_dir, "--dest-dir")?;
+            let options = sync::SyncOptions {
+                delete,
+                dry_run,
+                snapshot_dir,
+                force,
+                jobs,
+                progress: true,
+            };
+            let rem = crate::device::Remarkable::open(connection)?;
+            rem.rsync_from_device_to(dest_dir, &options)?;
+            if let Some(templates_dir) = templates_dir.or(settings.templates_dir) {
+                if !dry_run {
+                    rem.sync_templates_to(templates_dir)?;
+                }
+            }
         }
         Command::Convert {
             source_dir,
             dest_dir,
             notebook_filter,
             page_filter,
+            include_deleted,
+            format,
+            dpi,
+            transparent,
+            templates_dir,
+            include_trash,
+            force,
+            jobs: jobs_limit,
+            strict,
         } => {
-            let notebooks = fs::scan(source_dir)?;
+            let source_dir = backup_dir(source_dir, "--source-dir")?;
+            let format = format.or(settings.format).unwrap_or(OutputFormat::Pdf);
+            let templates_dir = templates_dir.or(settings.templates_dir);
+            let mut library = fs::scan(&source_dir)?;
+            let notebooks = std::mem::take(&mut library.notebooks);
+            let failed_scans = std::mem::take(&mut library.failed);
+
+            // everything that changes the output of an unchanged notebook
+            let output_settings = format!(
+                "{format:?} {page_filter:?} {include_deleted} {dpi} {transparent} {templates_dir:?}"
+            );
+            let render_options = render::RenderOptions {
+                include_deleted,
+                templates_dir,
+            };
 
-            let dest_dir = dest_dir.unwrap_or(PathBuf::from(".").join("output"));
+            let dest_dir = dest_dir
+                .or(settings.output_dir)
+                .unwrap_or(PathBuf::from(".").join("output"));
             info!("writing output to directory: {:?}", &dest_dir);
 
-            for notebook in notebooks.notebooks {
+            // outputs of notebooks that are gone since the last run are removed
+            let kind = format.to_possible_value().unwrap();
+            let mut manifest = manifest::Manifest::load(&dest_dir, kind.get_name(), &source_dir);
+            let converted_ids: HashSet<_> = notebooks
+                .iter()
+                .filter(|n| include_trash || !library.is_trashed(n))
+                .map(|n| n.id.clone())
+                .collect();
+            // notebooks that couldn't be read keep their outputs
+            manifest.remove_stale(|id| {
+                converted_ids.contains(id) || failed_scans.iter().any(|f| f.id == id)
+            })?;
+            manifest.save()?;
+
+            // output paths taken so far, to tell apart notebooks of the same
+            // name in the same folder
+            let mut output_paths = HashSet::new();
+
+            // the notebooks that need converting, with their output path and
+            // source hash, in the order that they are reported in
+            let mut jobs = Vec::new();
+            let mut report = report::Report::default();
+            for failed in failed_scans {
+                let label = library.path_in(&failed.parent, &failed.name);
+                let trashed = library.is_trashed_folder(&failed.parent);
+                let filtered = notebook_filter
+                    .as_ref()
+                    .is_some_and(|filter| failed.name != *filter);
+                if (include_trash || !trashed) && !filtered {
+                    let error = failed.error.context("failed to read the notebook");
+                    report.add(label.display().to_string(), report::Outcome::Failed(error));
+                }
+            }
+            for notebook in notebooks {
+                if !converted_ids.contains(&notebook.id) {
+                    debug!("skipping trashed notebook: {}", &notebook.name);
+                    continue;
+                }
+
+                // notebooks are placed in the same folders as on the device
+                let mut output_path = library.path(&notebook);
+                if !output_paths.insert(output_path.clone()) {
+                    let mut file_name = output_path.file_name().unwrap_or_default().to_owned();
+                    file_name.push(format!(" ({})", notebook.id));
+                    output_path.set_file_name(file_name);
+                    output_paths.insert(output_path.clone());
+                }
+                let label = output_path.display().to_string();
+                if let OutputFormat::Pdf = format {
+                    let mut output_file = output_path.into_os_string();
+                    output_file.push(".pdf");
+                    output_path = output_file.into();
+                }
+
                 if let Some(ref notebook_filter) = notebook_filter {
                     if notebook.name != *notebook_filter {
                         continue;
                     }
                 }
 
-                let page_range: Box<dyn Fn(usize) -> bool> = match &page_filter {
-                    Some(page_filter) if page_filter.contains(":") => {
-                        let elems: Vec<_> = page_filter.split(":").collect();
-                        let start: usize = elems[0].parse()?;
-                        let end: usize = elems[1].parse()?;
-                        Box::new(move |p| p >= start && p < end)
+                let hash = manifest::source_hash(&notebook, &output_settings)?;
+                if !force && manifest.is_current(&notebook.id, &hash, &output_path) {
+                    debug!("skipping unchanged notebook: {}", &notebook.name);
+                    report.unchanged += 1;
+                    continue;
+                }
+
+                jobs.push((notebook, label, output_path, hash));
+            }
+
+            let page_range = parse_page_filter(page_filter.as_deref())?;
+            let render = |notebook: model::content::Notebook, output: PathBuf| -> Result<()> {
+                let page_range = page_range.clone();
+                let page_range: Box<dyn Fn(usize) -> bool> = Box::new(move |p| page_range(p));
+                match format {
+                    OutputFormat::Pdf => {
+                        render::render_pdf(notebook, page_range, &render_options, output)?;
                     }
-                    Some(page_filter) => {
-                        let page_num: usize = page_filter.parse()?;
-                        Box::new(move |p| p == page_num)
+                    OutputFormat::Svg => {
+                        render::render_svg(notebook, page_range, &render_options, output)?;
                     }
-                    None => Box::new(|_p| true),
+                    OutputFormat::Png | OutputFormat::Jpeg | OutputFormat::Webp => {
+                        let raster_options = render::RasterOptions {
+                            format: match format {
+                                OutputFormat::Jpeg => ImageFormat::Jpeg,
+                                OutputFormat::Webp => ImageFormat::WebP,
+                                _ => ImageFormat::Png,
+                            },
+                            dpi,
+                            transparent,
+                        };
+                        render::render_raster(
+                            notebook,
+                            page_range,
+                            &render_options,
+                            &raster_options,
+                            output,
+                        )?;
+                    }
+                }
+                Ok(())
+            };
+            let convert = |notebook: model::fs::Notebook, output: PathBuf| -> report::Outcome {
+                info!("converting notebook: {}", &notebook.name);
+                let (parsed_notebook, page_errors) = match parser::parse_notebook(notebook) {
+                    Ok(parsed) => parsed,
+                    Err(e) => return report::Outcome::Failed(e),
                 };
+                // in strict mode, a notebook is only written if all its pages are
+                if strict {
+                    if let Some(e) = page_errors.into_iter().next() {
+                        let page = e.index + 1;
+                        return report::Outcome::Failed(
+                            e.error.context(format!("failed to read page {page}")),
+                        );
+                    }
+                    return match render(parsed_notebook, output) {
+                        Ok(()) => report::Outcome::Converted,
+                        Err(e) => report::Outcome::Failed(e),
+                    };
+                }
+                match render(parsed_notebook, output) {
+                    Ok(()) if page_errors.is_empty() => report::Outcome::Converted,
+                    Ok(()) => report::Outcome::Partial(page_errors),
+                    Err(e) => report::Outcome::Failed(e),
+                }
+            };
 
-                info!("converting notebook: {}", &notebook.name);
-                let output_path = dest_dir.join(format!("{}.pdf", &notebook.name));
-                let parsed_notebook = parser::parse_notebook(notebook)?;
-                render::render_pdf(parsed_notebook, page_range, output_path);
+            // notebooks, and the pages within them, are converted in parallel,
+            // and the results collected back in order.  In strict mode, no
+            // notebooks are started once one has failed.
+            let stop = AtomicBool::new(strict && report.failed() > 0);
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(jobs_limit)
+                .build()?;
+            let results: Vec<_> = pool.install(|| {
+                jobs.into_par_iter()
+                    .map(|(notebook, label, output_path, hash)| {
+                        if stop.load(Ordering::Relaxed) {
+                            return (
+                                notebook.id,
+                                label,
+                                output_path,
+                                hash,
+                                report::Outcome::Skipped,
+                            );
+                        }
+                        let id = notebook.id.clone();
+                        let outcome = convert(notebook, dest_dir.join(&output_path));
+                        if strict && !matches!(outcome, report::Outcome::Converted) {
+                            stop.store(true, Ordering::Relaxed);
+                        }
+                        (id, label, output_path, hash, outcome)
+                    })
+                    .collect()
+            });
+
+            // notebooks with failed pages aren't recorded, so that they are
+            // converted again on the next run
+            for (id, label, output_path, hash, outcome) in results {
+                match &outcome {
+                    report::Outcome::Converted => manifest.record(&id, hash, output_path)?,
+                    report::Outcome::Partial(errors) => {
+                        error!("failed to convert {} pages of {label:?}", errors.len())
+                    }
+                    report::Outcome::Failed(e) => error!("failed to convert {label:?}: {e:#}"),
+                    report::Outcome::Skipped => {}
+                }
+                report.add(label, outcome);
+            }
+            manifest.save()?;
+            println!("{report}");
+
+            let failed = report.failed();
+            if failed > 0 {
+                return Err(anyhow!("{failed} notebooks failed to convert"));
             }
         }
-        Command::Stream { diagnostics } => {
-            crate::stream::stream(diagnostics).await.unwrap();
+        Command::Ls {
+            source_dir,
+            device,
+            include_trash,
+            json,
+        } => {
+            let library = if device {
+                // only the notebook metadata is needed, which is copied to
+                // a scratch directory so that it can be read like a backup
+                let rem = crate::device::Remarkable::open(connection)?;
+                let scratch_dir = tempfile::tempdir()?;
+                rem.sync_metadata_to(scratch_dir.path())?;
+                fs::scan(scratch_dir.path())?
+            } else {
+                fs::scan(backup_dir(source_dir, "--source-dir")?)?
+            };
+            ls::print(&library, include_trash, json)?;
+        }
+        Command::Push {
+            file,
+            folder,
+            no_restart,
+        } => {
+            let rem = crate::device::Remarkable::open(connection)?;
+            let id = push::push(&rem, &file, folder.as_deref(), !no_restart)?;
+            println!("{id}");
+        }
+        Command::Restore {
+            from,
+            notebook,
+            overwrite,
+            dry_run,
+        } => {
+            let from = backup_dir(from, "--from")?;
+            let options = restore::RestoreOptions { overwrite, dry_run };
+            let rem = crate::device::Remarkable::open(connection)?;
+            restore::restore(&rem, &from, notebook.as_deref(), &options)?;
+        }
+        Command::Stream {
+            diagnostics,
+            no_diagnostics,
+        } => {
+            // the flags override each other, so at most one of them is set
+            let diagnostics = if diagnostics || no_diagnostics {
+                diagnostics
+            } else {
+                settings.stream.diagnostics.unwrap_or(false)
+            };
+            crate::stream::stream(connection, diagnostics)
+                .await
+                .unwrap();
         }
         Command::Screengrab { dest_file } => {
-            crate::stream::grab_frame(&dest_file).await?;
+            crate::stream::grab_frame(connection, &dest_file).await?;
         }
     }
 
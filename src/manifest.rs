@@ -0,0 +1,327 @@
+//! Tracks what was converted into an output directory, so that notebooks
+//! whose sources have not changed since are not converted again.
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, info, warn};
+
+use crate::model::fs::Notebook;
+
+/// What was last converted for a notebook.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct Entry {
+    /// Hash of the notebook's sources and the settings it was converted with.
+    pub hash: String,
+    /// The output file or directory, relative to the output directory.
+    pub output: PathBuf,
+    /// The backup directory that the notebook was converted from, since
+    /// several can be converted into the same output directory.
+    #[serde(default)]
+    pub source: Option<PathBuf>,
+}
+
+/// Whether `output` stays inside the output directory, which a manifest that
+/// was edited by hand may not.
+fn is_relative(output: &Path) -> bool {
+    output.components().next().is_some()
+        && output
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+/// Whether `name` is that of a page written into an output directory, like
+/// `page-001.svg`.
+pub fn is_page_file(name: &str) -> bool {
+    name.strip_prefix("page-")
+        .and_then(|rest| rest.split_once('.'))
+        .is_some_and(|(number, _)| !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()))
+}
+
+#[derive(Debug)]
+pub struct Manifest {
+    dest_dir: PathBuf,
+    /// The backup directory that is being converted.
+    source: PathBuf,
+    /// Name of the manifest file in the output directory.
+    file_name: String,
+    /// Entries by notebook UUID.
+    entries: BTreeMap<String, Entry>,
+}
+
+impl Manifest {
+    /// Loads the manifest of the outputs in `kind`, e.g. `pdf`, in `dest_dir`,
+    /// for converting the backup in `source_dir`.  Each kind of output has its
+    /// own manifest, so that converting to one doesn't replace the others.  A
+    /// missing or unreadable manifest is treated as empty, so that everything
+    /// is converted.
+    pub fn load<P: AsRef<Path>>(dest_dir: P, kind: &str, source_dir: &Path) -> Self {
+        let dest_dir = dest_dir.as_ref().to_path_buf();
+        let source = source_dir
+            .canonicalize()
+            .unwrap_or_else(|_| source_dir.to_path_buf());
+        let file_name = format!(".remarkers-manifest.{kind}.json");
+        let path = dest_dir.join(&file_name);
+        let mut entries: BTreeMap<String, Entry> = match std::fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents).unwrap_or_else(|e| {
+                warn!("ignoring invalid manifest {path:?}: {e}");
+                BTreeMap::new()
+            }),
+            Err(_) => BTreeMap::new(),
+        };
+        entries.retain(|id, entry| {
+            let valid = is_relative(&entry.output);
+            if !valid {
+                warn!("ignoring output {:?} of {id} in {path:?}", entry.output);
+            }
+            valid
+        });
+
+        Self {
+            dest_dir,
+            source,
+            file_name,
+            entries,
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = self.dest_dir.join(&self.file_name);
+        std::fs::create_dir_all(&self.dest_dir)?;
+        std::fs::write(&path, serde_json::to_vec_pretty(&self.entries)?)
+            .context(format!("failed to write manifest {path:?}"))
+    }
+
+    /// Whether the notebook `id` was converted to `output` from this backup
+    /// and sources with the given hash, and the output still exists.
+    pub fn is_current(&self, id: &str, hash: &str, output: &Path) -> bool {
+        self.entries.get(id).is_some_and(|entry| {
+            entry.hash == hash
+                && entry.output == output
+                && entry.source.as_ref() == Some(&self.source)
+                && self.dest_dir.join(output).exists()
+        })
+    }
+
+    /// Records that the notebook `id` was converted to `output`, removing its
+    /// previous output if it was written elsewhere, e.g. before a rename.
+    pub fn record(&mut self, id: &str, hash: String, output: PathBuf) -> Result<()> {
+        if let Some(previous) = self.entries.get(id) {
+            if previous.output != output {
+                self.remove_output(&previous.output)?;
+            }
+        }
+
+        let source = Some(self.source.clone());
+        self.entries.insert(
+            id.to_string(),
+            Entry {
+                hash,
+                output,
+                source,
+            },
+        );
+        Ok(())
+    }
+
+    /// Removes the outputs of the notebooks of this backup for which `keep` is
+    /// false, such as those that were deleted from the library.  Those of other
+    /// backups converted into the same directory are left alone.
+    pub fn remove_stale(&mut self, keep: impl Fn(&str) -> bool) -> Result<()> {
+        let stale: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(id, entry)| entry.source.as_ref() == Some(&self.source) && !keep(id))
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in stale {
+            if let Some(entry) = self.entries.remove(&id) {
+                info!(
+                    "removing output of deleted notebook {id}: {:?}",
+                    entry.output
+                );
+                self.remove_output(&entry.output)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes an output file, or the pages in an output directory, and any
+    /// folders left empty by it.
+    fn remove_output(&self, output: &Path) -> Result<()> {
+        if !is_relative(output) {
+            warn!(
+                "not removing {output:?}, which is outside of {:?}",
+                self.dest_dir
+            );
+            return Ok(());
+        }
+        let path = self.dest_dir.join(output);
+        let removed = if path.is_dir() {
+            remove_pages(&path)
+        } else {
+            std::fs::remove_file(&path)
+        };
+        match removed {
+            Ok(()) => debug!("removed {path:?}"),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context(format!("failed to remove {path:?}")),
+        }
+
+        // removing a folder fails while it still has content
+        for folder in output.ancestors().skip(1) {
+            if folder.as_os_str().is_empty()
+                || std::fs::remove_dir(self.dest_dir.join(folder)).is_err()
+            {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Removes the pages that were written into `dir`, and then `dir` unless it
+/// has other files in it.
+fn remove_pages(dir: &Path) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let is_page = entry.file_name().to_str().is_some_and(is_page_file);
+        if is_page && entry.file_type()?.is_file() {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    if std::fs::remove_dir(dir).is_err() {
+        warn!("leaving {dir:?}, which has files in it that weren't converted");
+    }
+    Ok(())
+}
+
+/// Hashes the sources of a notebook together with `settings`, which describe
+/// how it is converted.  The `.metadata` file is left out, because the device
+/// updates it whenever the notebook is merely opened; a rename or move shows
+/// up as a new output path instead.
+pub fn source_hash(notebook: &Notebook, settings: &str) -> Result<String> {
+    let mut files = vec![
+        notebook.root.with_extension("content"),
+        notebook.root.with_extension("pagedata"),
+    ];
+    files.extend(notebook.base_document.iter().cloned());
+    if let Ok(entries) = std::fs::read_dir(&notebook.root) {
+        files.extend(entries.flatten().map(|e| e.path()));
+    }
+    files.sort();
+
+    let mut hasher = Sha256::new();
+    hasher.update(settings.as_bytes());
+    for file in files.iter().filter(|f| f.is_file()) {
+        let contents = std::fs::read(file).context(format!("failed to read {file:?}"))?;
+        hasher.update(file.file_name().unwrap_or_default().as_encoded_bytes());
+        hasher.update((contents.len() as u64).to_le_bytes());
+        hasher.update(&contents);
+    }
+
+    let mut hash = String::new();
+    for byte in hasher.finalize() {
+        write!(hash, "{byte:02x}").unwrap();
+    }
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::fs::{serde::FileType, Parent};
+
+    #[test]
+    fn test_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let dest = dir.path().join("dest");
+        std::fs::create_dir_all(source.join("nb")).unwrap();
+        std::fs::write(source.join("nb.content"), "{}").unwrap();
+        std::fs::write(source.join("nb").join("page.rm"), "v1").unwrap();
+
+        let notebook = Notebook {
+            id: "nb".to_string(),
+            name: "Notes".to_string(),
+            parent: Parent::Root,
+            file_type: FileType::Notebook,
+            last_modified: None,
+            pinned: false,
+            tags: Vec::new(),
+            root: source.join("nb"),
+            pages: Vec::new(),
+            base_document: None,
+        };
+        let hash = source_hash(&notebook, "pdf").unwrap();
+        assert_ne!(hash, source_hash(&notebook, "svg").unwrap());
+
+        let output = PathBuf::from("Work/Notes.pdf");
+        let mut manifest = Manifest::load(&dest, "pdf", &source);
+        assert!(!manifest.is_current("nb", &hash, &output));
+
+        std::fs::create_dir_all(dest.join("Work")).unwrap();
+        std::fs::write(dest.join(&output), "pdf").unwrap();
+        manifest.record("nb", hash.clone(), output.clone()).unwrap();
+        manifest.save().unwrap();
+
+        let mut manifest = Manifest::load(&dest, "pdf", &source);
+        assert!(manifest.is_current("nb", &hash, &output));
+
+        // editing a page changes the hash
+        std::fs::write(source.join("nb").join("page.rm"), "v2").unwrap();
+        assert_ne!(hash, source_hash(&notebook, "pdf").unwrap());
+
+        // the outputs of another backup in the same directory are kept
+        let other = dir.path().join("other");
+        std::fs::create_dir_all(&other).unwrap();
+        let mut other_manifest = Manifest::load(&dest, "pdf", &other);
+        assert!(!other_manifest.is_current("nb", &hash, &output));
+        other_manifest.remove_stale(|_| false).unwrap();
+        assert!(dest.join(&output).exists());
+
+        // the output and its folder go once the notebook is deleted
+        manifest.remove_stale(|_| false).unwrap();
+        assert!(!dest.join("Work").exists());
+        assert!(!manifest.is_current("nb", &hash, &output));
+    }
+
+    #[test]
+    fn test_remove_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("dest");
+        let pages = dest.join("Work").join("Notes");
+        std::fs::create_dir_all(&pages).unwrap();
+        std::fs::write(pages.join("page-001.png"), "png").unwrap();
+        std::fs::write(pages.join("page-002.png"), "png").unwrap();
+        std::fs::write(pages.join("notes.txt"), "mine").unwrap();
+        std::fs::write(dir.path().join("outside.pdf"), "pdf").unwrap();
+        std::fs::write(
+            dest.join(".remarkers-manifest.png.json"),
+            r#"{
+                "a": {"hash": "", "output": "Work/Notes", "source": "/backup"},
+                "b": {"hash": "", "output": "../outside.pdf", "source": "/backup"},
+                "c": {"hash": "", "output": "/etc", "source": "/backup"}
+            }"#,
+        )
+        .unwrap();
+
+        // only the pages are removed, and entries outside of the output
+        // directory are dropped
+        let mut manifest = Manifest::load(&dest, "png", Path::new("/backup"));
+        assert_eq!(manifest.entries.keys().collect::<Vec<_>>(), ["a"]);
+        manifest.remove_stale(|_| false).unwrap();
+        assert!(!pages.join("page-001.png").exists());
+        assert!(pages.join("notes.txt").exists());
+        assert!(dir.path().join("outside.pdf").exists());
+
+        assert!(manifest.remove_output(Path::new("../outside.pdf")).is_ok());
+        assert!(dir.path().join("outside.pdf").exists());
+        assert!(is_page_file("page-010.jpg"));
+        assert!(!is_page_file("page-.svg"));
+        assert!(!is_page_file("cover.png"));
+    }
+}
@@ -1,4 +1,6 @@
-#[derive(Debug, PartialEq)]
+use std::path::PathBuf;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Version {
     V3,
     V5,
@@ -22,6 +24,8 @@ impl TryFrom<char> for Version {
 pub struct Notebook {
     pub id: String,
     pub pages: Vec<Page>,
+    /// The PDF that the notebook annotates, if any.
+    pub base_document: Option<PathBuf>,
 }
 
 #[derive(Debug)]
@@ -29,14 +33,24 @@ pub struct Page {
     pub id: String,
     pub version: Version,
     pub layers: Vec<Layer>,
+    /// Typed text on the page, only present for v6 pages.
+    pub text: Option<Text>,
+    /// Index of the page of the base document shown under this page, if any.
+    pub source_page: Option<usize>,
+    /// Name of the template the page is drawn on, if any.
+    pub template: Option<String>,
 }
 
 #[derive(Debug)]
 pub struct Layer {
+    /// The layer name, only recorded in the page itself for v6 pages.
+    pub name: Option<String>,
+    pub visible: bool,
+    /// The lines of the layer in z-order, bottom first.
     pub lines: Vec<Line>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Color {
     Black,
     Grey,
@@ -47,6 +61,32 @@ pub enum Color {
     Blue,
     Red,
     GreyOverlap,
+    /// Generic highlighter colour.  Newer firmware stores the actual shade as a custom colour.
+    Highlight,
+    Green2,
+    Cyan,
+    Magenta,
+    Yellow2,
+    /// A custom colour, as red, green, blue and alpha components.
+    Rgba([u8; 4]),
+}
+
+impl Color {
+    /// Whether the colour is only used for highlighting, in which case it is
+    /// drawn translucently regardless of the brush.
+    pub fn is_highlight(&self) -> bool {
+        match self {
+            Color::GreyOverlap | Color::Highlight => true,
+            Color::Rgba([_, _, _, a]) => *a < u8::MAX,
+            _ => false,
+        }
+    }
+
+    /// Converts a colour stored as a packed ARGB value.
+    pub fn from_argb(argb: u32) -> Self {
+        let [a, r, g, b] = argb.to_be_bytes();
+        Color::Rgba([r, g, b, a])
+    }
 }
 
 impl TryFrom<u32> for Color {
@@ -63,12 +103,17 @@ impl TryFrom<u32> for Color {
             6 => Ok(Color::Blue),
             7 => Ok(Color::Red),
             8 => Ok(Color::GreyOverlap),
+            9 => Ok(Color::Highlight),
+            10 => Ok(Color::Green2),
+            11 => Ok(Color::Cyan),
+            12 => Ok(Color::Magenta),
+            13 => Ok(Color::Yellow2),
             _ => Err(()),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum BrushType {
     Eraser,
     EraserArea,
@@ -109,17 +154,69 @@ pub struct Line {
     #[allow(unused)]
     pub brush_size: f32,
     pub points: Vec<Point>,
+    /// Whether the line was erased or undone.  Only v6 pages retain deleted lines.
+    pub deleted: bool,
 }
 
-#[derive(Debug)]
+/// A sampled point of a stroke.  The parsers normalize every format version to
+/// the units below.
+#[derive(Clone, Copy, Debug)]
 pub struct Point {
     pub x: f32,
     pub y: f32,
-    #[allow(unused)]
+    /// Speed of the stylus when the point was sampled.
     pub speed: f32,
-    #[allow(unused)]
+    /// Tilt direction of the stylus, in radians.
     pub direction: f32,
+    /// Width of the stroke at this point, in page pixels.
     pub width: f32,
-    #[allow(unused)]
+    /// Stylus pressure, from 0 to 1.
     pub pressure: f32,
 }
+
+/// A block of typed text, anchored at a position on the page.
+#[derive(Debug)]
+pub struct Text {
+    /// Position of the top left corner of the text box, in page pixels.
+    pub x: f32,
+    pub y: f32,
+    /// Width of the text box in page pixels, used to wrap paragraphs.
+    pub width: f32,
+    pub paragraphs: Vec<Paragraph>,
+}
+
+#[derive(Debug)]
+pub struct Paragraph {
+    pub style: ParagraphStyle,
+    pub contents: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParagraphStyle {
+    Basic,
+    Plain,
+    Heading,
+    Bold,
+    Bullet,
+    Bullet2,
+    Checkbox,
+    CheckboxChecked,
+}
+
+impl TryFrom<u8> for ParagraphStyle {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Basic),
+            1 => Ok(Self::Plain),
+            2 => Ok(Self::Heading),
+            3 => Ok(Self::Bold),
+            4 => Ok(Self::Bullet),
+            5 => Ok(Self::Bullet2),
+            6 => Ok(Self::Checkbox),
+            7 => Ok(Self::CheckboxChecked),
+            _ => Err(()),
+        }
+    }
+}
@@ -1,25 +1,212 @@
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
+use std::time::SystemTime;
+
+use self::serde::FileType;
+
+/// Name of the folder that trashed items are placed in.
+pub const TRASH_FOLDER: &str = "Trash";
 
 #[derive(Debug)]
 pub struct Notebooks {
     #[allow(unused)]
     pub root: PathBuf,
+    /// The folders of the library, by ID.
+    pub collections: HashMap<String, Collection>,
     pub notebooks: Vec<Notebook>,
+    /// The notebooks whose files couldn't be read.
+    pub failed: Vec<ScanError>,
+}
+
+/// A notebook or folder that couldn't be read.
+#[derive(Debug)]
+pub struct ScanError {
+    pub id: String,
+    /// The name of the notebook, or its ID if its metadata couldn't be read.
+    pub name: String,
+    pub parent: Parent,
+    pub error: anyhow::Error,
+}
+
+/// Where a notebook or folder is placed in the library.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Parent {
+    Root,
+    Trash,
+    Collection(String),
+}
+
+impl From<&str> for Parent {
+    fn from(value: &str) -> Self {
+        match value {
+            "" => Parent::Root,
+            "trash" => Parent::Trash,
+            id => Parent::Collection(id.to_string()),
+        }
+    }
+}
+
+/// A folder of the library.
+#[derive(Debug)]
+pub struct Collection {
+    pub name: String,
+    pub parent: Parent,
+    pub last_modified: Option<SystemTime>,
+    pub pinned: bool,
+}
+
+impl Notebooks {
+    /// Walks up from `parent` to the root, returning the names of the folders
+    /// passed, innermost first, and whether the walk ended in the trash.
+    fn ancestors<'a>(&'a self, mut parent: &'a Parent) -> (Vec<&'a str>, bool) {
+        let mut names = Vec::new();
+        let mut visited = HashSet::new();
+        loop {
+            match parent {
+                Parent::Root => return (names, false),
+                Parent::Trash => return (names, true),
+                Parent::Collection(id) => match self.collections.get(id) {
+                    // a cycle can only come from corrupt metadata, but would
+                    // otherwise never end
+                    Some(collection) if visited.insert(id) => {
+                        names.push(collection.name.as_str());
+                        parent = &collection.parent;
+                    }
+                    _ => return (names, false),
+                },
+            }
+        }
+    }
+
+    /// Path of the folder `parent`, relative to the library root, with one
+    /// component per folder.  Trashed items are placed in [`TRASH_FOLDER`].
+    pub fn folder(&self, parent: &Parent) -> PathBuf {
+        let (mut names, trashed) = self.ancestors(parent);
+        if trashed {
+            names.push(TRASH_FOLDER);
+        }
+        names.into_iter().rev().map(path_component).collect()
+    }
+
+    /// Path of a notebook relative to the library root, without an extension.
+    pub fn path(&self, notebook: &Notebook) -> PathBuf {
+        self.path_in(&notebook.parent, &notebook.name)
+    }
+
+    /// Path of the item `name` in the folder `parent`, relative to the library root.
+    pub fn path_in(&self, parent: &Parent, name: &str) -> PathBuf {
+        self.folder(parent).join(path_component(name))
+    }
+
+    /// Whether a notebook is in the trash, possibly inside a trashed folder.
+    pub fn is_trashed(&self, notebook: &Notebook) -> bool {
+        self.is_trashed_folder(&notebook.parent)
+    }
+
+    /// Whether the folder `parent` is the trash or inside it.
+    pub fn is_trashed_folder(&self, parent: &Parent) -> bool {
+        self.ancestors(parent).1
+    }
+}
+
+/// Makes a notebook or folder name safe to use as a single path component.
+fn path_component(name: &str) -> String {
+    match name.trim() {
+        "" | "." | ".." => "_".to_string(),
+        name => name.replace(['/', '\\'], "_"),
+    }
 }
 
 #[derive(Debug)]
 pub struct Notebook {
+    /// The UUID that the notebook's files are named by.
+    pub id: String,
     pub name: String,
+    pub parent: Parent,
+    pub file_type: FileType,
+    pub last_modified: Option<SystemTime>,
+    /// Whether the notebook is marked as a favourite.
+    pub pinned: bool,
+    pub tags: Vec<String>,
     pub root: PathBuf,
     pub pages: Vec<Page>,
+    /// The PDF that an annotated PDF or EPUB was imported as.
+    pub base_document: Option<PathBuf>,
 }
 
 #[derive(Debug)]
 pub struct Page {
     pub id: String,
+    /// Index of the page of the base document shown under this page, if any.
+    pub source_page: Option<usize>,
+    /// Name of the template the page is drawn on, e.g. `P Lines medium`.
+    pub template: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    fn library() -> Notebooks {
+        let collection = |name: &str, parent: &str| Collection {
+            name: name.to_string(),
+            parent: parent.into(),
+            last_modified: None,
+            pinned: false,
+        };
+        Notebooks {
+            root: PathBuf::new(),
+            collections: HashMap::from([
+                ("work".to_string(), collection("Work", "")),
+                ("meetings".to_string(), collection("Meetings/1:1s", "work")),
+                ("old".to_string(), collection("Old", "trash")),
+                ("loop".to_string(), collection("Loop", "loop")),
+            ]),
+            notebooks: Vec::new(),
+            failed: Vec::new(),
+        }
+    }
+
+    fn notebook(name: &str, parent: &str) -> Notebook {
+        Notebook {
+            id: "id".to_string(),
+            name: name.to_string(),
+            parent: parent.into(),
+            file_type: FileType::Notebook,
+            last_modified: None,
+            pinned: false,
+            tags: Vec::new(),
+            root: PathBuf::new(),
+            pages: Vec::new(),
+            base_document: None,
+        }
+    }
+
+    #[rstest]
+    #[case::root("Notes", "", "Notes", false)]
+    #[case::nested("Notes", "meetings", "Work/Meetings_1:1s/Notes", false)]
+    #[case::trash("Notes", "trash", "Trash/Notes", true)]
+    #[case::trashed_folder("Notes", "old", "Trash/Old/Notes", true)]
+    #[case::missing_folder("Notes", "gone", "Notes", false)]
+    #[case::cycle("Notes", "loop", "Loop/Notes", false)]
+    #[case::unsafe_name("..", "", "_", false)]
+    fn test_notebook_path(
+        #[case] name: &str,
+        #[case] parent: &str,
+        #[case] path: &str,
+        #[case] trashed: bool,
+    ) {
+        let library = library();
+        let notebook = notebook(name, parent);
+        assert_eq!(library.path(&notebook), PathBuf::from(path));
+        assert_eq!(library.is_trashed(&notebook), trashed);
+    }
 }
 
 pub mod serde {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
     use serde::Deserialize;
 
     #[derive(Debug, Deserialize)]
@@ -28,6 +215,31 @@ pub mod serde {
         pub visible_name: String,
         #[serde(rename = "type")]
         pub element_type: ElementType,
+        /// ID of the containing folder, empty at the root and `trash` once deleted.
+        #[serde(default)]
+        pub parent: String,
+        #[serde(rename = "lastModified")]
+        pub last_modified: Option<Millis>,
+        #[serde(default)]
+        pub pinned: bool,
+    }
+
+    /// Milliseconds since the Unix epoch, which are usually written as a string.
+    #[derive(Debug, Deserialize)]
+    #[serde(untagged)]
+    pub enum Millis {
+        Text(String),
+        Number(u64),
+    }
+
+    impl Millis {
+        pub fn to_system_time(&self) -> Option<SystemTime> {
+            let millis = match self {
+                Millis::Text(text) => text.parse().ok()?,
+                Millis::Number(millis) => *millis,
+            };
+            UNIX_EPOCH.checked_add(Duration::from_millis(millis))
+        }
     }
 
     #[derive(Debug, Deserialize, PartialEq)]
@@ -36,29 +248,101 @@ pub mod serde {
         CollectionType,
     }
 
+    #[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum FileType {
+        #[default]
+        Notebook,
+        Pdf,
+        Epub,
+    }
+
     #[derive(Debug)]
     pub struct NotebookContent {
-        pub pages: Option<Vec<String>>,
+        pub file_type: FileType,
+        pub pages: Option<Vec<PageContent>>,
+        pub tags: Vec<String>,
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct PageContent {
+        pub id: String,
+        /// Index of the page of the base document shown under this page.
+        pub source_page: Option<usize>,
+        /// Name of the template the page is drawn on.
+        pub template: Option<String>,
+    }
+
+    /// Converts an entry of a redirection map, where negative values mark
+    /// pages that were inserted into the document, to a page index.
+    fn source_page(redirect: i64) -> Option<usize> {
+        usize::try_from(redirect).ok()
+    }
+
+    /// Converts a template name to a template to draw, where `Blank` is used
+    /// for pages without one.
+    pub fn template(name: &str) -> Option<String> {
+        let name = name.trim();
+        (!name.is_empty() && name != "Blank").then(|| name.to_string())
     }
 
     impl From<NotebookContentRaw> for NotebookContent {
         fn from(value: NotebookContentRaw) -> Self {
-            let pages = value.pages.or_else(|| {
-                value
-                    .c_pages
-                    .and_then(|v| v.pages)
-                    .map(|pages| pages.into_iter().map(|p| p.id).collect())
-            });
-
-            NotebookContent { pages }
+            // older versions list the pages and their redirections separately,
+            // while newer ones keep both in the cPages CRDT
+            let redirections = value.redirection_page_map.unwrap_or_default();
+            let pages = value
+                .pages
+                .map(|pages| {
+                    pages
+                        .into_iter()
+                        .enumerate()
+                        .map(|(idx, id)| PageContent {
+                            id,
+                            source_page: redirections.get(idx).copied().and_then(source_page),
+                            template: None,
+                        })
+                        .collect()
+                })
+                .or_else(|| {
+                    value.c_pages.and_then(|v| v.pages).map(|pages| {
+                        // deleted pages stay in the CRDT, so that the deletion syncs
+                        pages
+                            .into_iter()
+                            .filter(|p| !p.is_deleted())
+                            .map(|p| PageContent {
+                                id: p.id,
+                                source_page: p.redir.and_then(|r| source_page(r.value)),
+                                template: p.template.and_then(|t| template(&t.value)),
+                            })
+                            .collect()
+                    })
+                });
+
+            NotebookContent {
+                file_type: value.file_type,
+                pages,
+                tags: value.tags.into_iter().map(|t| t.name).collect(),
+            }
         }
     }
 
     #[derive(Debug, Deserialize)]
     pub struct NotebookContentRaw {
+        #[serde(rename = "fileType", default)]
+        file_type: FileType,
         #[serde(rename = "cPages")]
         c_pages: Option<CPages>,
         pages: Option<Vec<String>>,
+        #[serde(rename = "redirectionPageMap")]
+        redirection_page_map: Option<Vec<i64>>,
+        #[serde(default)]
+        tags: Vec<Tag>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct Tag {
+        name: String,
     }
 
     #[derive(Debug, Deserialize)]
@@ -69,5 +353,84 @@ pub mod serde {
     #[derive(Debug, Deserialize)]
     pub struct CPagesPage {
         id: String,
+        redir: Option<Timestamped<i64>>,
+        template: Option<Timestamped<String>>,
+        deleted: Option<Timestamped<i64>>,
+    }
+
+    impl CPagesPage {
+        fn is_deleted(&self) -> bool {
+            self.deleted.as_ref().is_some_and(|d| d.value != 0)
+        }
+    }
+
+    /// A last-writer-wins value of the cPages CRDT.
+    #[derive(Debug, Deserialize)]
+    pub struct Timestamped<T> {
+        value: T,
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use rstest::rstest;
+
+        fn page(id: &str, source_page: Option<usize>) -> PageContent {
+            PageContent {
+                id: id.to_string(),
+                source_page,
+                template: None,
+            }
+        }
+
+        fn templated(id: &str, template: &str) -> PageContent {
+            PageContent {
+                template: Some(template.to_string()),
+                ..page(id, None)
+            }
+        }
+
+        #[rstest]
+        #[case::notebook(r#"{"pages": ["a", "b"]}"#, FileType::Notebook, vec![page("a", None), page("b", None)])]
+        #[case::redirection_map(
+            r#"{"fileType": "pdf", "pages": ["a", "b", "c"], "redirectionPageMap": [0, -1, 1]}"#,
+            FileType::Pdf,
+            vec![page("a", Some(0)), page("b", None), page("c", Some(1))],
+        )]
+        #[case::c_pages(
+            r#"{"fileType": "epub", "cPages": {"pages": [
+                {"id": "a", "redir": {"timestamp": "1:2", "value": 3}},
+                {"id": "b"}
+            ]}}"#,
+            FileType::Epub,
+            vec![page("a", Some(3)), page("b", None)],
+        )]
+        #[case::templates(
+            r#"{"cPages": {"pages": [
+                {"id": "a", "template": {"timestamp": "1:2", "value": "P Lines medium"}},
+                {"id": "b", "template": {"timestamp": "1:3", "value": "Blank"}}
+            ]}}"#,
+            FileType::Notebook,
+            vec![templated("a", "P Lines medium"), page("b", None)],
+        )]
+        #[case::deleted_pages(
+            r#"{"fileType": "pdf", "cPages": {"pages": [
+                {"id": "a", "redir": {"timestamp": "1:2", "value": 0}},
+                {"id": "b", "redir": {"timestamp": "1:3", "value": 1}, "deleted": {"timestamp": "1:4", "value": 1}},
+                {"id": "c", "redir": {"timestamp": "1:5", "value": 2}, "deleted": {"timestamp": "1:6", "value": 0}}
+            ]}}"#,
+            FileType::Pdf,
+            vec![page("a", Some(0)), page("c", Some(2))],
+        )]
+        fn test_notebook_content(
+            #[case] json: &str,
+            #[case] file_type: FileType,
+            #[case] pages: Vec<PageContent>,
+        ) {
+            let raw: NotebookContentRaw = serde_json::from_str(json).unwrap();
+            let content = NotebookContent::from(raw);
+            assert_eq!(content.file_type, file_type);
+            assert_eq!(content.pages, Some(pages));
+        }
     }
 }
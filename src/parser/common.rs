@@ -16,6 +16,11 @@ pub fn error(s: ParserAtom, k: ErrorKind) -> Err<ParserError> {
     Err::Error(VerboseError::from_error_kind(s, k))
 }
 
+/// Like `error`, but stops parsing rather than letting alternatives be tried.
+pub fn failure(s: ParserAtom, k: ErrorKind) -> Err<ParserError> {
+    Err::Failure(VerboseError::from_error_kind(s, k))
+}
+
 pub fn u8(s: ParserInput) -> ParserResult<u8> {
     nom::number::complete::u8(s)
 }
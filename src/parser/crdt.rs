@@ -0,0 +1,147 @@
+//! CRDT sequence support for the v6 format.
+//!
+//! Several v6 structures (text runs, scene tree children) are stored as an
+//! unordered set of items where each item records the IDs of its left and
+//! right neighbours at the time it was inserted.  The real order is recovered
+//! by a topological sort over those neighbour constraints.
+use std::collections::{BTreeSet, HashMap};
+
+use tracing::warn;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct CrdtId {
+    pub part1: u8,
+    pub part2: u64,
+}
+
+impl CrdtId {
+    pub const fn new(part1: u8, part2: u64) -> Self {
+        Self { part1, part2 }
+    }
+}
+
+/// The ID used for "no neighbour", i.e. the start or end of a sequence.
+pub const END_MARKER: CrdtId = CrdtId::new(0, 0);
+
+#[derive(Debug)]
+pub struct SequenceItem<T> {
+    pub item_id: CrdtId,
+    pub left_id: CrdtId,
+    pub right_id: CrdtId,
+    pub deleted_length: u32,
+    pub value: T,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum Node {
+    Start,
+    End,
+    Id(CrdtId),
+}
+
+/// Orders sequence items according to their left/right neighbour constraints.
+///
+/// This mirrors the reference implementation in `rmscene`: items are emitted
+/// in rounds, where each round contains every item whose dependencies have all
+/// been emitted, sorted by item ID.  Items involved in a cycle (which should not
+/// occur in well-formed files) are appended at the end in ID order.
+pub fn toposort<T>(items: Vec<SequenceItem<T>>) -> Vec<SequenceItem<T>> {
+    let side = |id: CrdtId, default: Node| {
+        if id == END_MARKER {
+            default
+        } else {
+            Node::Id(id)
+        }
+    };
+
+    // for every node, the number of nodes that must come before it, and the
+    // nodes that are waiting on it
+    let mut pending: HashMap<Node, usize> = HashMap::new();
+    let mut dependents: HashMap<Node, Vec<Node>> = HashMap::new();
+    let mut add_edge = |before: Node, after: Node| {
+        pending.entry(before).or_default();
+        *pending.entry(after).or_default() += 1;
+        dependents.entry(before).or_default().push(after);
+    };
+
+    for item in &items {
+        let id = Node::Id(item.item_id);
+        add_edge(side(item.left_id, Node::Start), id);
+        add_edge(id, side(item.right_id, Node::End));
+    }
+
+    let mut by_id: HashMap<CrdtId, SequenceItem<T>> =
+        items.into_iter().map(|i| (i.item_id, i)).collect();
+    let mut sorted = Vec::with_capacity(by_id.len());
+
+    let mut ready: Vec<Node> = pending
+        .iter()
+        .filter(|(_, count)| **count == 0)
+        .map(|(node, _)| *node)
+        .collect();
+
+    while !ready.is_empty() {
+        let round: BTreeSet<CrdtId> = ready
+            .iter()
+            .filter_map(|n| match n {
+                Node::Id(id) => Some(*id),
+                _ => None,
+            })
+            .collect();
+        sorted.extend(round.iter().filter_map(|id| by_id.remove(id)));
+
+        let mut next = Vec::new();
+        for node in ready {
+            for dependent in dependents.remove(&node).unwrap_or_default() {
+                let count = pending.get_mut(&dependent).expect("dependent is tracked");
+                *count -= 1;
+                if *count == 0 {
+                    next.push(dependent);
+                }
+            }
+        }
+        ready = next;
+    }
+
+    if !by_id.is_empty() {
+        warn!(
+            "cyclic dependency between {} CRDT sequence items, appending in ID order",
+            by_id.len()
+        );
+        let mut remaining: Vec<_> = by_id.into_values().collect();
+        remaining.sort_by_key(|i| i.item_id);
+        sorted.extend(remaining);
+    }
+
+    sorted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    fn item(id: u64, left: u64, right: u64) -> SequenceItem<u64> {
+        SequenceItem {
+            item_id: CrdtId::new(1, id),
+            left_id: CrdtId::new(if left == 0 { 0 } else { 1 }, left),
+            right_id: CrdtId::new(if right == 0 { 0 } else { 1 }, right),
+            deleted_length: 0,
+            value: id,
+        }
+    }
+
+    #[rstest]
+    // appended in order
+    #[case(vec![item(1, 0, 0), item(2, 1, 0), item(3, 2, 0)], vec![1, 2, 3])]
+    // inserted at the front
+    #[case(vec![item(1, 0, 0), item(2, 0, 1), item(3, 0, 2)], vec![3, 2, 1])]
+    // inserted in the middle
+    #[case(vec![item(1, 0, 0), item(2, 1, 0), item(3, 1, 2)], vec![1, 3, 2])]
+    // input order does not matter
+    #[case(vec![item(3, 1, 2), item(2, 1, 0), item(1, 0, 0)], vec![1, 3, 2])]
+    fn test_toposort(#[case] items: Vec<SequenceItem<u64>>, #[case] expected: Vec<u64>) {
+        let sorted: Vec<_> = toposort(items).into_iter().map(|i| i.value).collect();
+        assert_eq!(sorted, expected);
+    }
+}
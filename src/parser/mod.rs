@@ -1,64 +1,143 @@
 mod common;
+mod crdt;
+mod scene_tree;
+mod v3;
 mod v5;
 mod v6;
 
 use crate::model::content::*;
 use common::*;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use rayon::prelude::*;
 use std::fs::read;
 use tracing::{error, info, trace};
 
-pub fn parse(s: ParserInput) -> ParserResult<(Version, Vec<Layer>)> {
+pub fn parse(s: ParserInput) -> ParserResult<(Version, Vec<Layer>, Option<Text>)> {
     let (s, version) = header(s)?;
     trace!("parsed header version {version:?}");
 
-    let (s, page) = match version {
-        Version::V3 => panic!("can't handle v3"),
-        Version::V5 => v5::read_page_v5(s)?,
+    let (s, (layers, text)) = match version {
+        Version::V3 => {
+            let (s, layers) = v3::read_page_v3(s)?;
+            (s, (layers, None))
+        }
+        Version::V5 => {
+            let (s, layers) = v5::read_page_v5(s)?;
+            (s, (layers, None))
+        }
         Version::V6 => v6::read_page_v6(s)?,
     };
 
-    Ok((s, (version, page)))
+    Ok((s, (version, layers, text)))
+}
+
+/// A page that could not be read, and is left out of its notebook.
+#[derive(Debug)]
+pub struct PageError {
+    /// Index of the page in the notebook.
+    pub index: usize,
+    pub id: String,
+    pub error: anyhow::Error,
+}
+
+/// Describes a parser error by where it happened in `contents`, rather than
+/// by the unparsed bytes that it holds.
+fn describe_error(contents: &[u8], error: nom::Err<ParserError>) -> anyhow::Error {
+    match error {
+        nom::Err::Incomplete(_) => anyhow!("unexpected end of page data"),
+        nom::Err::Error(e) | nom::Err::Failure(e) => match e.errors.first() {
+            Some((remaining, kind)) => anyhow!(
+                "invalid page data at byte {}: {kind:?}",
+                contents.len() - remaining.len()
+            ),
+            None => anyhow!("invalid page data"),
+        },
+    }
 }
 
-pub fn parse_notebook(notebook: crate::model::fs::Notebook) -> Result<Notebook> {
+/// Parses the pages of a notebook.  Pages that can't be read are left out,
+/// and returned as errors alongside the notebook.
+pub fn parse_notebook(notebook: crate::model::fs::Notebook) -> Result<(Notebook, Vec<PageError>)> {
     info!("parsing notebook: {notebook:?}");
-    let mut pages = Vec::new();
 
-    for page in notebook.pages.iter() {
-        trace!("processing page: {}", page.id);
+    // pages are parsed in parallel, and collected back in order
+    let results: Vec<_> = notebook
+        .pages
+        .par_iter()
+        .enumerate()
+        .map(|(index, page)| {
+            trace!("processing page: {}", page.id);
+            let page_error = |error| PageError {
+                index,
+                id: page.id.clone(),
+                error,
+            };
 
-        let page_path = notebook.root.join(format!("{}.rm", page.id));
+            let page_path = notebook.root.join(format!("{}.rm", page.id));
 
-        let contents = match read(&page_path) {
-            Ok(contents) => contents,
-            Err(_e) => {
-                error!("failed to open file at {page_path:?}");
-                continue;
-            }
-        };
+            let contents = match read(&page_path) {
+                Ok(contents) => contents,
+                // pages of an annotated document are only written once they are
+                // drawn on, but the document's page is still shown
+                Err(_e) if page.source_page.is_some() && notebook.base_document.is_some() => {
+                    trace!("page {} has no annotations", page.id);
+                    return Ok(Page {
+                        id: page.id.clone(),
+                        version: Version::V6,
+                        layers: Vec::new(),
+                        text: None,
+                        source_page: page.source_page,
+                        template: page.template.clone(),
+                    });
+                }
+                Err(e) => {
+                    error!("failed to open file at {page_path:?}");
+                    return Err(page_error(
+                        anyhow::Error::new(e).context(format!("failed to open {page_path:?}")),
+                    ));
+                }
+            };
 
-        match parse(&contents) {
-            Ok((_, (version, layers))) => {
-                trace!(
-                    "Parsed page {} successfully with version {version:?}",
-                    page.id
-                );
-                pages.push(Page {
-                    id: page.id.clone(),
-                    version,
-                    layers,
-                });
-            }
-            Err(e) => {
-                error!("Failed to parse {:?}: {}", &notebook.name, e);
+            match parse(&contents) {
+                Ok((_, (version, layers, text))) => {
+                    trace!(
+                        "Parsed page {} successfully with version {version:?}",
+                        page.id
+                    );
+                    Ok(Page {
+                        id: page.id.clone(),
+                        version,
+                        layers,
+                        text,
+                        source_page: page.source_page,
+                        template: page.template.clone(),
+                    })
+                }
+                Err(e) => {
+                    let e = describe_error(&contents, e);
+                    error!("Failed to parse {:?}: {}", &notebook.name, e);
+                    Err(page_error(e))
+                }
             }
+        })
+        .collect();
+
+    let mut pages = Vec::new();
+    let mut errors = Vec::new();
+    for result in results {
+        match result {
+            Ok(page) => pages.push(page),
+            Err(e) => errors.push(e),
         }
     }
 
-    Ok(Notebook {
-        id: notebook.name,
-        pages,
-    })
+    Ok((
+        Notebook {
+            id: notebook.name,
+            pages,
+            base_document: notebook.base_document,
+        },
+        errors,
+    ))
 }
@@ -0,0 +1,294 @@
+//! Resolution of the v6 scene tree into ordered, named layers.
+//!
+//! A v6 page is a tree of groups.  The children of the root group are the
+//! page's layers, and each group's children (lines or nested groups) form a
+//! CRDT sequence whose order is the z-order of the items.
+use std::collections::HashMap;
+
+use tracing::{trace, warn};
+
+use super::crdt::{self, CrdtId, SequenceItem};
+use crate::model::content::{Layer, Line};
+
+/// The ID of the root group of every v6 scene tree.
+pub const ROOT_ID: CrdtId = CrdtId::new(0, 1);
+
+#[derive(Debug)]
+pub enum SceneChild {
+    Group(CrdtId),
+    Line(Line),
+    /// A deleted item whose value is no longer stored.  It is kept in the sequence
+    /// so that its neighbours are still ordered correctly.
+    Tombstone,
+}
+
+#[derive(Debug)]
+struct Group {
+    label: Option<String>,
+    visible: bool,
+}
+
+impl Default for Group {
+    fn default() -> Self {
+        Self {
+            label: None,
+            visible: true,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SceneTree {
+    groups: HashMap<CrdtId, Group>,
+    parents: HashMap<CrdtId, CrdtId>,
+    children: HashMap<CrdtId, Vec<SequenceItem<SceneChild>>>,
+}
+
+impl SceneTree {
+    /// Records that `node_id` is a group nested under `parent_id`.
+    pub fn add_node(&mut self, node_id: CrdtId, parent_id: CrdtId) {
+        self.groups.entry(node_id).or_default();
+        self.parents.insert(node_id, parent_id);
+    }
+
+    /// Records the properties of a group.
+    pub fn set_node_properties(&mut self, node_id: CrdtId, label: String, visible: bool) {
+        let group = self.groups.entry(node_id).or_default();
+        group.label = Some(label);
+        group.visible = visible;
+    }
+
+    /// Adds an item to the CRDT sequence of children of `parent_id`.
+    pub fn add_child(&mut self, parent_id: CrdtId, mut child: SequenceItem<SceneChild>) {
+        if child.deleted_length > 0 {
+            mark_deleted(&mut child);
+        }
+
+        self.children.entry(parent_id).or_default().push(child);
+    }
+
+    /// Takes the children of `group_id` in z-order.  When an item was written
+    /// both with its value and as a tombstone, the value is kept and marked deleted.
+    fn take_children(&mut self, group_id: CrdtId) -> Vec<SequenceItem<SceneChild>> {
+        let mut by_id: HashMap<CrdtId, SequenceItem<SceneChild>> = HashMap::new();
+        for mut child in self.children.remove(&group_id).unwrap_or_default() {
+            if let Some(existing) = by_id.remove(&child.item_id) {
+                let tombstoned = matches!(existing.value, SceneChild::Tombstone)
+                    || matches!(child.value, SceneChild::Tombstone);
+                if matches!(child.value, SceneChild::Tombstone) {
+                    child = existing;
+                }
+                if tombstoned {
+                    mark_deleted(&mut child);
+                }
+            }
+            by_id.insert(child.item_id, child);
+        }
+
+        crdt::toposort(by_id.into_values().collect())
+    }
+
+    /// Resolves the tree into layers in z-order.  Every group directly under the
+    /// root becomes a layer, with the lines of any nested groups flattened into it.
+    pub fn into_layers(mut self) -> Vec<Layer> {
+        let mut layers = Vec::new();
+        let mut root_lines = Vec::new();
+
+        for child in self.take_children(ROOT_ID) {
+            match child.value {
+                SceneChild::Group(group_id) => {
+                    let mut lines = Vec::new();
+                    self.collect_lines(group_id, child.deleted_length > 0, &mut lines);
+
+                    let group = self.groups.remove(&group_id).unwrap_or_default();
+                    trace!(
+                        "resolved layer {:?} ({group_id:?}) with {} lines",
+                        group.label,
+                        lines.len()
+                    );
+                    layers.push(Layer {
+                        name: group.label,
+                        visible: group.visible,
+                        lines,
+                    });
+                }
+                SceneChild::Line(line) => root_lines.push(line),
+                SceneChild::Tombstone => {}
+            }
+        }
+
+        // lines whose group could not be reached from the root, e.g. because the
+        // group item itself was never written, are kept rather than dropped
+        let mut orphans: Vec<_> = self.children.into_iter().collect();
+        orphans.sort_by_key(|(parent_id, _)| *parent_id);
+        for (parent_id, children) in orphans {
+            warn!(
+                "found {} scene items under unreachable group {parent_id:?} (parent {:?})",
+                children.len(),
+                self.parents.get(&parent_id)
+            );
+            root_lines.extend(
+                crdt::toposort(children)
+                    .into_iter()
+                    .filter_map(|c| match c.value {
+                        SceneChild::Line(line) => Some(line),
+                        SceneChild::Group(_) | SceneChild::Tombstone => None,
+                    }),
+            );
+        }
+
+        if !root_lines.is_empty() {
+            layers.push(Layer {
+                name: None,
+                visible: true,
+                lines: root_lines,
+            });
+        }
+
+        layers
+    }
+
+    /// Collects the lines of a group and its nested groups.  Lines in a deleted
+    /// group are deleted along with it.
+    fn collect_lines(&mut self, group_id: CrdtId, deleted: bool, lines: &mut Vec<Line>) {
+        for child in self.take_children(group_id) {
+            match child.value {
+                SceneChild::Group(nested_id) => {
+                    self.collect_lines(nested_id, deleted || child.deleted_length > 0, lines)
+                }
+                SceneChild::Line(mut line) => {
+                    line.deleted |= deleted;
+                    lines.push(line);
+                }
+                SceneChild::Tombstone => {}
+            }
+        }
+    }
+}
+
+fn mark_deleted(item: &mut SequenceItem<SceneChild>) {
+    item.deleted_length = item.deleted_length.max(1);
+    if let SceneChild::Line(line) = &mut item.value {
+        line.deleted = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::content::{BrushType, Color};
+
+    fn item(id: u64, left: u64, value: SceneChild) -> SequenceItem<SceneChild> {
+        SequenceItem {
+            item_id: CrdtId::new(2, id),
+            left_id: if left == 0 {
+                crdt::END_MARKER
+            } else {
+                CrdtId::new(2, left)
+            },
+            right_id: crdt::END_MARKER,
+            deleted_length: 0,
+            value,
+        }
+    }
+
+    fn line(brush_size: f32) -> SceneChild {
+        SceneChild::Line(Line {
+            brush_type: BrushType::Fineliner,
+            color: Color::Black,
+            brush_size,
+            points: Vec::new(),
+            deleted: false,
+        })
+    }
+
+    fn layer_summary(layer: &Layer) -> (Option<&str>, bool, Vec<f32>) {
+        (
+            layer.name.as_deref(),
+            layer.visible,
+            layer.lines.iter().map(|l| l.brush_size).collect(),
+        )
+    }
+
+    #[test]
+    fn test_into_layers() {
+        let layer1 = CrdtId::new(0, 11);
+        let layer2 = CrdtId::new(2, 100);
+        let nested = CrdtId::new(2, 200);
+
+        let mut tree = SceneTree::default();
+        tree.add_node(layer1, ROOT_ID);
+        tree.add_node(layer2, ROOT_ID);
+        tree.add_node(nested, layer2);
+        tree.set_node_properties(layer1, "Layer 1".to_string(), true);
+        tree.set_node_properties(layer2, "Sketch".to_string(), false);
+
+        tree.add_child(ROOT_ID, item(2, 1, SceneChild::Group(layer2)));
+        tree.add_child(ROOT_ID, item(1, 0, SceneChild::Group(layer1)));
+        tree.add_child(layer1, item(10, 0, line(1.0)));
+        tree.add_child(layer1, item(12, 11, line(3.0)));
+        tree.add_child(layer1, item(11, 10, line(2.0)));
+        tree.add_child(layer2, item(20, 0, line(4.0)));
+        tree.add_child(layer2, item(21, 20, SceneChild::Group(nested)));
+        tree.add_child(nested, item(30, 0, line(5.0)));
+        // a line whose group is not reachable from the root
+        tree.add_child(CrdtId::new(9, 9), item(40, 0, line(6.0)));
+
+        let layers = tree.into_layers();
+        let summary: Vec<_> = layers.iter().map(layer_summary).collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Some("Layer 1"), true, vec![1.0, 2.0, 3.0]),
+                (Some("Sketch"), false, vec![4.0, 5.0]),
+                (None, true, vec![6.0]),
+            ]
+        );
+    }
+
+    fn deleted_lines(layer: &Layer) -> Vec<(f32, bool)> {
+        layer
+            .lines
+            .iter()
+            .map(|l| (l.brush_size, l.deleted))
+            .collect()
+    }
+
+    #[test]
+    fn test_deleted_items() {
+        let layer1 = CrdtId::new(0, 11);
+        let removed = CrdtId::new(2, 200);
+
+        let mut tree = SceneTree::default();
+        tree.add_child(ROOT_ID, item(1, 0, SceneChild::Group(layer1)));
+        tree.add_child(layer1, item(10, 0, line(1.0)));
+        // deleted in place
+        let mut undone = item(11, 10, line(2.0));
+        undone.deleted_length = 1;
+        tree.add_child(layer1, undone);
+        // deleted by a later tombstone for the same item
+        tree.add_child(layer1, item(12, 11, line(3.0)));
+        tree.add_child(layer1, item(12, 11, SceneChild::Tombstone));
+        // a tombstone without a value still orders its neighbours
+        tree.add_child(layer1, item(13, 12, SceneChild::Tombstone));
+        tree.add_child(layer1, item(14, 13, line(4.0)));
+        // lines inside a deleted group
+        let mut removed_group = item(15, 14, SceneChild::Group(removed));
+        removed_group.deleted_length = 1;
+        tree.add_child(layer1, removed_group);
+        tree.add_child(removed, item(20, 0, line(5.0)));
+
+        let layers = tree.into_layers();
+        assert_eq!(layers.len(), 1);
+        assert_eq!(
+            deleted_lines(&layers[0]),
+            vec![
+                (1.0, false),
+                (2.0, true),
+                (3.0, true),
+                (4.0, false),
+                (5.0, true)
+            ]
+        );
+    }
+}
@@ -0,0 +1,53 @@
+//! Parser for the v3 `.lines` format written by first generation firmware.
+//!
+//! The layout is the same as v5, except that each line header lacks the
+//! trailing padding field that v5 added after the brush size.
+use super::common::*;
+use super::v5::read_page;
+use crate::model::content::*;
+
+pub fn read_page_v3(s: ParserInput) -> ParserResult<Vec<Layer>> {
+    read_page(Version::V3)(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::content::*;
+    use crate::parser::parse;
+
+    const TWO_LAYERS: &[u8] = include_bytes!("../../tests/fixtures/v3_two_layers.rm");
+
+    #[test]
+    fn test_parse_v3_fixture() {
+        let (rem, (version, layers, text)) = parse(TWO_LAYERS).unwrap();
+        assert!(rem.is_empty());
+        assert_eq!(version, Version::V3);
+        assert!(text.is_none());
+
+        assert_eq!(layers.len(), 2);
+        assert_eq!(layers[0].lines.len(), 2);
+        assert_eq!(layers[1].lines.len(), 1);
+
+        let pen = &layers[0].lines[0];
+        assert!(matches!(pen.brush_type, BrushType::Ballpoint));
+        assert!(matches!(pen.color, Color::Black));
+        assert_eq!(pen.brush_size, 2.0);
+        assert_eq!(pen.points.len(), 3);
+        assert_eq!((pen.points[1].x, pen.points[1].y), (110.0, 205.0));
+        assert_eq!(pen.points[1].width, 2.5);
+        assert_eq!(pen.points[2].pressure, 1.0);
+
+        let highlighter = &layers[0].lines[1];
+        assert!(matches!(highlighter.brush_type, BrushType::Highlighter));
+        assert!(matches!(highlighter.color, Color::Grey));
+
+        let eraser = &layers[1].lines[0];
+        assert!(matches!(eraser.brush_type, BrushType::Eraser));
+        assert!(matches!(eraser.color, Color::White));
+    }
+
+    #[test]
+    fn test_parse_v3_truncated() {
+        assert!(parse(&TWO_LAYERS[..TWO_LAYERS.len() - 4]).is_err());
+    }
+}
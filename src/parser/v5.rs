@@ -7,39 +7,54 @@ fn num_layers(s: ParserInput) -> ParserResult<u32> {
     u32(s)
 }
 
-fn layer(s: ParserInput) -> ParserResult<Layer> {
-    let (s, num_lines) = u32(s)?;
-    let (rem, lines) = count(line, num_lines as _)(s)?;
-    Ok((rem, Layer { lines }))
+fn layer(version: Version) -> impl Fn(ParserInput) -> ParserResult<Layer> {
+    move |s| {
+        let (s, num_lines) = u32(s)?;
+        let (rem, lines) = count(line(version), num_lines as _)(s)?;
+        Ok((
+            rem,
+            Layer {
+                name: None,
+                visible: true,
+                lines,
+            },
+        ))
+    }
 }
 
-fn line(s: ParserInput) -> ParserResult<Line> {
-    let (s, brush_type) = u32(s)?;
-    let brush_type: BrushType = brush_type
-        .try_into()
-        .map_err(|_| error(s, nom::error::ErrorKind::NoneOf))?;
+fn line(version: Version) -> impl Fn(ParserInput) -> ParserResult<Line> {
+    move |s| {
+        let (s, brush_type) = u32(s)?;
+        let brush_type: BrushType = brush_type
+            .try_into()
+            .map_err(|_| error(s, nom::error::ErrorKind::NoneOf))?;
 
-    let (s, color) = u32(s)?;
-    let color: Color = color
-        .try_into()
-        .map_err(|_| error(s, nom::error::ErrorKind::NoneOf))?;
+        let (s, color) = u32(s)?;
+        let color: Color = color
+            .try_into()
+            .map_err(|_| error(s, nom::error::ErrorKind::NoneOf))?;
 
-    let (s, _padding) = u32(s)?;
-    let (s, brush_size) = f32(s)?;
-    // second padding only included for v5
-    let (s, _padding) = u32(s)?;
-    let (s, num_points) = u32(s)?;
-    let (rem, points) = count(point, num_points as _)(s)?;
+        let (s, _padding) = u32(s)?;
+        let (s, brush_size) = f32(s)?;
+        // second padding only included for v5
+        let (s, _padding) = match version {
+            Version::V3 => (s, 0),
+            _ => u32(s)?,
+        };
+        let (s, num_points) = u32(s)?;
+        let (rem, points) = count(point, num_points as _)(s)?;
 
-    Ok((
-        rem,
-        Line {
-            brush_type,
-            color,
-            brush_size,
-            points,
-        },
-    ))
+        Ok((
+            rem,
+            Line {
+                brush_type,
+                color,
+                brush_size,
+                points,
+                deleted: false,
+            },
+        ))
+    }
 }
 
 fn point(s: ParserInput) -> ParserResult<Point> {
@@ -63,8 +78,15 @@ fn point(s: ParserInput) -> ParserResult<Point> {
     ))
 }
 
+/// Parses the layers of a v5 page, or of a v3 page, which has the same
+/// layout apart from the line headers.
+pub fn read_page(version: Version) -> impl Fn(ParserInput) -> ParserResult<Vec<Layer>> {
+    move |s| {
+        let (s, num_layers) = num_layers(s)?;
+        count(layer(version), num_layers as _)(s)
+    }
+}
+
 pub fn read_page_v5(s: ParserInput) -> ParserResult<Vec<Layer>> {
-    let (s, num_layers) = num_layers(s)?;
-    let (s, layers) = count(layer, num_layers as _)(s)?;
-    Ok((s, layers))
+    read_page(Version::V5)(s)
 }
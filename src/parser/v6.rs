@@ -1,12 +1,16 @@
 use std::collections::HashMap;
 
 use super::common::*;
+use super::crdt::{self, CrdtId, SequenceItem, END_MARKER};
+use super::scene_tree::{SceneChild, SceneTree};
 use crate::model::{self, content::*};
 
 use nom::{
     bytes::complete::take,
+    combinator::opt,
     error::ErrorKind,
     multi::{count, many1},
+    sequence::tuple,
 };
 use tracing::{info, trace, warn};
 
@@ -107,10 +111,12 @@ fn tagged_f64(expected_index: u64) -> impl Fn(ParserInput) -> ParserResult<f64>
     }
 }
 
-#[derive(Debug, Eq, Hash, PartialEq)]
-struct CrdtId {
-    part1: u8,
-    part2: u64,
+fn tagged_bool(expected_index: u64) -> impl Fn(ParserInput) -> ParserResult<bool> {
+    move |s| {
+        let (s, _) = stream_tag(expected_index, TagType::Byte1)(s)?;
+        let (s, value) = u8(s)?;
+        Ok((s, value != 0))
+    }
 }
 
 fn tagged_id(expected_index: u64) -> impl Fn(ParserInput) -> ParserResult<CrdtId> {
@@ -131,6 +137,10 @@ fn fixed_length_segment<T>(
     mut parser: impl FnMut(ParserInput) -> ParserResult<T>,
 ) -> impl FnMut(ParserInput) -> ParserResult<T> {
     move |s| {
+        if s.len() < len as usize {
+            return Err(error(s, ErrorKind::Eof));
+        }
+
         let (head, tail) = s.split_at(len as _);
         let (head, parsed) = parser(head)?;
 
@@ -147,9 +157,26 @@ fn fixed_length_segment<T>(
     }
 }
 
+/// Parses a tagged, length-prefixed subblock with `parser`, leaving the stream aligned after the subblock.
+fn subblock<T>(
+    expected_index: u64,
+    mut parser: impl FnMut(ParserInput) -> ParserResult<T>,
+) -> impl FnMut(ParserInput) -> ParserResult<T> {
+    move |s| {
+        let (s, _) = stream_tag(expected_index, TagType::Length4)(s)?;
+        let (s, len) = u32(s)?;
+        fixed_length_segment(len, &mut parser)(s)
+    }
+}
+
 #[derive(Debug, PartialEq)]
 enum BlockType {
-    SceneItem,
+    SceneTree,
+    TreeNode,
+    SceneGroupItem,
+    SceneLineItem,
+    RootText,
+    SceneTombstoneItem,
     AuthorInfo,
     PageInfo,
 }
@@ -159,7 +186,12 @@ impl TryFrom<u8> for BlockType {
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            0x05 => Ok(BlockType::SceneItem),
+            0x01 => Ok(BlockType::SceneTree),
+            0x02 => Ok(BlockType::TreeNode),
+            0x04 => Ok(BlockType::SceneGroupItem),
+            0x05 => Ok(BlockType::SceneLineItem),
+            0x07 => Ok(BlockType::RootText),
+            0x08 => Ok(BlockType::SceneTombstoneItem),
             0x09 => Ok(BlockType::AuthorInfo),
             0x0A => Ok(BlockType::PageInfo),
             _ => Err(()),
@@ -169,7 +201,9 @@ impl TryFrom<u8> for BlockType {
 
 #[derive(Debug, PartialEq)]
 enum ItemType {
+    Group,
     Line,
+    Tombstone,
 }
 
 impl TryFrom<u8> for ItemType {
@@ -177,7 +211,9 @@ impl TryFrom<u8> for ItemType {
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
+            0x02 => Ok(ItemType::Group),
             0x03 => Ok(ItemType::Line),
+            0x08 => Ok(ItemType::Tombstone),
             _ => Err(()),
         }
     }
@@ -197,10 +233,32 @@ struct Block<T> {
     item_id: CrdtId,
     left_id: CrdtId,
     right_id: CrdtId,
-    subblock: T,
+    /// Non-zero when the item has been deleted.
+    deleted_length: u32,
+    /// The item's value, which is usually dropped once the item is deleted.
+    subblock: Option<T>,
 }
 
 type LineItemBlock = Block<LineItemSubblock>;
+type GroupItemBlock = Block<CrdtId>;
+type TombstoneItemBlock = Block<()>;
+
+impl<T> Block<T> {
+    /// Converts the block to an item of its parent's children sequence.  Items
+    /// without a value are kept as tombstones to preserve the sequence order.
+    fn into_child(self, value: impl FnOnce(T) -> SceneChild) -> (CrdtId, SequenceItem<SceneChild>) {
+        (
+            self.parent_id,
+            SequenceItem {
+                item_id: self.item_id,
+                left_id: self.left_id,
+                right_id: self.right_id,
+                deleted_length: self.deleted_length,
+                value: self.subblock.map(value).unwrap_or(SceneChild::Tombstone),
+            },
+        )
+    }
+}
 
 fn point(version: u8) -> impl Fn(ParserInput) -> ParserResult<Point> {
     move |s| {
@@ -209,39 +267,36 @@ fn point(version: u8) -> impl Fn(ParserInput) -> ParserResult<Point> {
 
         let (s, y) = f32(s)?;
         info!("using version: {version}");
+        // normalize to the units used by v5 pages: widths in pixels, pressure in
+        // [0, 1] and the stylus direction in radians
         let (s, speed, direction, width, pressure) = match version {
             1 => {
                 let (s, speed) = f32(s)?;
-                let (s, width) = f32(s)?;
                 let (s, direction) = f32(s)?;
+                let (s, width) = f32(s)?;
                 let (s, pressure) = f32(s)?;
-                (
-                    s,
-                    speed * 4.0,
-                    255.0 * width / (std::f32::consts::PI * 2.0),
-                    direction * 4.0,
-                    pressure * 255.0,
-                )
+                (s, speed, direction, width, pressure)
             }
             2 => {
+                // speed and width are stored in quarter units
                 let (s, speed) = u16(s)?;
                 let (s, width) = u16(s)?;
                 let (s, direction) = u8(s)?;
                 let (s, pressure) = u8(s)?;
                 (
                     s,
-                    speed as f32,
-                    width as f32,
-                    direction as f32,
-                    pressure as f32,
+                    speed as f32 / 4.0,
+                    direction as f32 * std::f32::consts::TAU / 255.0,
+                    width as f32 / 4.0,
+                    pressure as f32 / 255.0,
                 )
             }
-            other => panic!("unrecognized version {other}"),
+            other => {
+                warn!("unrecognized line version {other}");
+                return Err(failure(s, ErrorKind::NoneOf));
+            }
         };
 
-        // adjust width
-        trace!("point: {x}, {y}, {speed}, {direction}, {width}, {pressure}");
-        let width = width * 2.0 / 255.0;
         trace!("point: {x}, {y}, {speed}, {direction}, {width}, {pressure}");
         Ok((
             s,
@@ -265,13 +320,10 @@ fn line_item_subblock(version: u8) -> impl Fn(ParserInput) -> ParserResult<LineI
             .try_into()
             .map_err(|_| error(s, ErrorKind::NoneOf))?;
         let (s, color_id) = tagged_u32(2)(s)?;
-        let color: Color = color_id
-            .try_into()
-            .map_err(|_| error(s, ErrorKind::NoneOf))?;
         let (s, thickness_scale) = tagged_f64(3)(s)?;
         let (s, starting_length) = tagged_f32(4)(s)?;
 
-        trace!("brush type: {brush_type:?}, color id: {color_id}, color: {color:?}, thickness scale: {thickness_scale}, starting len: {starting_length}");
+        trace!("brush type: {brush_type:?}, color id: {color_id}, thickness scale: {thickness_scale}, starting len: {starting_length}");
 
         // read another subblock for the point vector
         let (s, _) = stream_tag(5, TagType::Length4)(s)?;
@@ -282,7 +334,10 @@ fn line_item_subblock(version: u8) -> impl Fn(ParserInput) -> ParserResult<LineI
         let point_size = match version {
             1 => 0x18,
             2 => 0x0E,
-            other => panic!("unrecognized version {other}"),
+            other => {
+                warn!("unrecognized line version {other}");
+                return Err(failure(s, ErrorKind::NoneOf));
+            }
         };
 
         if subsubblock_len % point_size != 0 {
@@ -296,6 +351,21 @@ fn line_item_subblock(version: u8) -> impl Fn(ParserInput) -> ParserResult<LineI
             count(point(version), point_count as _)(s)
         })(s)?;
 
+        // newer firmware follows the points with a timestamp, a move ID and, for
+        // custom colours, the colour as a packed ARGB value
+        let (s, _timestamp) = opt(tagged_id(6))(s)?;
+        let (s, _move_id) = opt(tagged_id(7))(s)?;
+        let (s, argb) = opt(tagged_u32(8))(s)?;
+
+        let color = match argb {
+            Some(argb) => Color::from_argb(argb),
+            None => Color::try_from(color_id).unwrap_or_else(|_| {
+                warn!("unknown color id {color_id}, falling back to black");
+                Color::Black
+            }),
+        };
+        trace!("color: {color:?}");
+
         Ok((
             s,
             LineItemSubblock {
@@ -312,7 +382,7 @@ fn line_item_subblock(version: u8) -> impl Fn(ParserInput) -> ParserResult<LineI
 fn scene_item_block<T, S>(
     expected_item_type: ItemType,
     subblock_parser: T,
-) -> impl Fn(ParserInput) -> ParserResult<Option<Block<S>>>
+) -> impl Fn(ParserInput) -> ParserResult<Block<S>>
 where
     T: Fn(ParserInput) -> ParserResult<S>,
 {
@@ -321,14 +391,23 @@ where
         let (s, item_id) = tagged_id(2)(s)?;
         let (s, left_id) = tagged_id(3)(s)?;
         let (s, right_id) = tagged_id(4)(s)?;
-        let (s, deleted_len) = tagged_u32(5)(s)?;
-        trace!("parsed block level meta: parent {parent_id:?}, item {item_id:?}, left {left_id:?}, right {right_id:?}, deleted len {deleted_len}");
+        let (s, deleted_length) = tagged_u32(5)(s)?;
+        trace!("parsed block level meta: parent {parent_id:?}, item {item_id:?}, left {left_id:?}, right {right_id:?}, deleted len {deleted_length}");
+
+        let block = |subblock| Block {
+            parent_id,
+            item_id,
+            left_id,
+            right_id,
+            deleted_length,
+            subblock,
+        };
 
-        // in some cases the block header is not followed by a corresponding subblock tag and length.
-        // in these cases we just proceed and ignore the block.
+        // in some cases the block header is not followed by a corresponding subblock tag and length,
+        // typically because the item was deleted.  in these cases the block carries no value.
         let (s, _) = match stream_tag(6, TagType::Length4)(s) {
             Ok(val) => val,
-            Err(_) => return Ok((s, None)),
+            Err(_) => return Ok((s, block(None))),
         };
 
         let (s, subblock_len) = u32(s)?;
@@ -346,16 +425,7 @@ where
             subblock_parser(s)
         })(s)?;
 
-        Ok((
-            s,
-            Some(Block {
-                parent_id,
-                item_id,
-                left_id,
-                right_id,
-                subblock,
-            }),
-        ))
+        Ok((s, block(Some(subblock))))
     }
 }
 
@@ -407,7 +477,253 @@ fn page_info_block(s: ParserInput) -> ParserResult<()> {
     Ok((s, ()))
 }
 
-fn read_block_v6(s: ParserInput) -> ParserResult<Option<LineItemBlock>> {
+fn group_item_subblock(s: ParserInput) -> ParserResult<CrdtId> {
+    tagged_id(2)(s)
+}
+
+/// Declares a group node and its parent in the scene tree.
+struct SceneTreeNode {
+    node_id: CrdtId,
+    parent_id: CrdtId,
+}
+
+fn scene_tree_block(s: ParserInput) -> ParserResult<SceneTreeNode> {
+    let (s, _tree_id) = tagged_id(1)(s)?;
+    let (s, node_id) = tagged_id(2)(s)?;
+    let (s, _is_update) = tagged_bool(3)(s)?;
+    let (s, parent_id) = subblock(4, tagged_id(1))(s)?;
+    trace!("scene tree node {node_id:?} with parent {parent_id:?}");
+
+    Ok((s, SceneTreeNode { node_id, parent_id }))
+}
+
+/// The properties of a group node.  The label of a layer is the name shown in the layers menu.
+struct TreeNode {
+    node_id: CrdtId,
+    label: String,
+    visible: bool,
+}
+
+/// Parses a last-write-wins register, which pairs a timestamp with a value.
+fn lww<T>(
+    expected_index: u64,
+    value: impl Fn(ParserInput) -> ParserResult<T>,
+) -> impl FnMut(ParserInput) -> ParserResult<T> {
+    subblock(expected_index, move |s| {
+        let (s, _timestamp) = tagged_id(1)(s)?;
+        value(s)
+    })
+}
+
+fn tree_node_block(s: ParserInput) -> ParserResult<TreeNode> {
+    let (s, node_id) = tagged_id(1)(s)?;
+    let (s, label) = lww(2, |s| subblock(2, string)(s))(s)?;
+    let (s, visible) = lww(3, tagged_bool(2))(s)?;
+    trace!("tree node {node_id:?}: label {label:?}, visible {visible}");
+
+    // newer versions follow with anchor information for groups attached to text, which
+    // is not used and is skipped by the fixed length block
+    Ok((
+        s,
+        TreeNode {
+            node_id,
+            label,
+            visible,
+        },
+    ))
+}
+
+/// A run of characters in a text CRDT sequence.  Formatting runs carry an inline
+/// formatting code in place of text but still occupy one character ID.
+#[derive(Debug, PartialEq)]
+enum TextRun {
+    Text(String),
+    Format(u32),
+}
+
+fn string(s: ParserInput) -> ParserResult<String> {
+    let (s, string_len) = varuint(s)?;
+    let (s, _is_ascii) = u8(s)?;
+    let (s, bytes) = take(string_len as usize)(s)?;
+    Ok((s, String::from_utf8_lossy(bytes).into_owned()))
+}
+
+fn string_with_format(s: ParserInput) -> ParserResult<TextRun> {
+    let (s, text) = string(s)?;
+
+    if s.is_empty() {
+        return Ok((s, TextRun::Text(text)));
+    }
+
+    let (s, format) = tagged_u32(2)(s)?;
+    Ok((s, TextRun::Format(format)))
+}
+
+fn text_item(s: ParserInput) -> ParserResult<SequenceItem<TextRun>> {
+    subblock(0, |s| {
+        let (s, item_id) = tagged_id(2)(s)?;
+        let (s, left_id) = tagged_id(3)(s)?;
+        let (s, right_id) = tagged_id(4)(s)?;
+        let (s, deleted_length) = tagged_u32(5)(s)?;
+
+        let (s, value) = if s.is_empty() {
+            (s, TextRun::Text(String::new()))
+        } else {
+            subblock(6, string_with_format)(s)?
+        };
+        trace!("text item {item_id:?} (left {left_id:?}, right {right_id:?}, deleted {deleted_length}): {value:?}");
+
+        Ok((
+            s,
+            SequenceItem {
+                item_id,
+                left_id,
+                right_id,
+                deleted_length,
+                value,
+            },
+        ))
+    })(s)
+}
+
+/// A paragraph style assignment, keyed by the ID of the character that starts the paragraph.
+/// The timestamp resolves conflicting assignments (last writer wins).
+struct TextFormat {
+    char_id: CrdtId,
+    timestamp: CrdtId,
+    style: ParagraphStyle,
+}
+
+fn text_format(s: ParserInput) -> ParserResult<TextFormat> {
+    // character IDs in the format table are not preceded by a tag
+    let (s, part1) = u8(s)?;
+    let (s, part2) = varuint(s)?;
+    let char_id = CrdtId::new(part1, part2);
+
+    let (s, timestamp) = tagged_id(1)(s)?;
+    let (s, style) = subblock(2, |s| {
+        let (s, _unknown) = u8(s)?;
+        let (s, style_code) = u8(s)?;
+        let style = style_code.try_into().unwrap_or_else(|_| {
+            warn!("unknown paragraph style {style_code}, falling back to plain");
+            ParagraphStyle::Plain
+        });
+        Ok((s, style))
+    })(s)?;
+
+    Ok((
+        s,
+        TextFormat {
+            char_id,
+            timestamp,
+            style,
+        },
+    ))
+}
+
+fn counted<T>(
+    parser: impl Fn(ParserInput) -> ParserResult<T>,
+) -> impl Fn(ParserInput) -> ParserResult<Vec<T>> {
+    move |s| {
+        let (s, num_items) = varuint(s)?;
+        count(&parser, num_items as _)(s)
+    }
+}
+
+fn root_text_block(s: ParserInput) -> ParserResult<Text> {
+    trace!("parsing root text block");
+    let (s, _block_id) = tagged_id(1)(s)?;
+
+    let (s, (items, formats)) = subblock(2, |s| {
+        let (s, items) = subblock(1, subblock(1, counted(text_item)))(s)?;
+        let (s, formats) = subblock(2, subblock(1, counted(text_format)))(s)?;
+        Ok((s, (items, formats)))
+    })(s)?;
+
+    let (s, (x, y)) = subblock(3, |s| tuple((f64, f64))(s))(s)?;
+    let (s, width) = tagged_f32(4)(s)?;
+    trace!(
+        "text at ({x}, {y}) with width {width}: {} items, {} formats",
+        items.len(),
+        formats.len()
+    );
+
+    Ok((
+        s,
+        Text {
+            x: x as f32 + (model::WIDTH_PIXELS / 2) as f32,
+            y: y as f32,
+            width,
+            paragraphs: text_paragraphs(items, formats),
+        },
+    ))
+}
+
+/// Resolves the CRDT ordered text items into paragraphs.  The first paragraph's
+/// style is keyed by the end marker, and each subsequent paragraph's style by the
+/// ID of the newline character that starts it.
+fn text_paragraphs(items: Vec<SequenceItem<TextRun>>, formats: Vec<TextFormat>) -> Vec<Paragraph> {
+    let mut styles: HashMap<CrdtId, (CrdtId, ParagraphStyle)> = HashMap::new();
+    for format in formats {
+        match styles.get(&format.char_id) {
+            Some((timestamp, _)) if *timestamp > format.timestamp => {}
+            _ => {
+                styles.insert(format.char_id, (format.timestamp, format.style));
+            }
+        }
+    }
+    let style_of = |id: &CrdtId| {
+        styles
+            .get(id)
+            .map(|(_, style)| *style)
+            .unwrap_or(ParagraphStyle::Plain)
+    };
+
+    let mut paragraphs = Vec::new();
+    let mut start_id = END_MARKER;
+    let mut contents = String::new();
+    for item in crdt::toposort(items) {
+        if item.deleted_length > 0 {
+            continue;
+        }
+
+        let text = match item.value {
+            TextRun::Text(text) => text,
+            // inline formatting is not rendered, only paragraph styles are
+            TextRun::Format(_) => continue,
+        };
+
+        for (offset, c) in text.chars().enumerate() {
+            if c == '\n' {
+                paragraphs.push(Paragraph {
+                    style: style_of(&start_id),
+                    contents: std::mem::take(&mut contents),
+                });
+                start_id = CrdtId::new(item.item_id.part1, item.item_id.part2 + offset as u64);
+            } else {
+                contents.push(c);
+            }
+        }
+    }
+
+    paragraphs.push(Paragraph {
+        style: style_of(&start_id),
+        contents,
+    });
+
+    paragraphs
+}
+
+enum ParsedBlock {
+    SceneTree(SceneTreeNode),
+    TreeNode(TreeNode),
+    Group(GroupItemBlock),
+    Line(LineItemBlock),
+    Tombstone(TombstoneItemBlock),
+    Text(Text),
+}
+
+fn read_block_v6(s: ParserInput) -> ParserResult<Option<ParsedBlock>> {
     let (s, block_len) = u32(s)?;
     trace!("read block length: {block_len}");
 
@@ -419,15 +735,39 @@ fn read_block_v6(s: ParserInput) -> ParserResult<Option<LineItemBlock>> {
     trace!("block meta: {min_version}, {current_version}, {block_type:?}");
 
     let (s, block) = fixed_length_segment(block_len, |b| match block_type {
-        Ok(BlockType::SceneItem) => {
+        Ok(BlockType::SceneTree) => {
+            let (b, node) = scene_tree_block(b)?;
+            Ok((b, Some(ParsedBlock::SceneTree(node))))
+        }
+        Ok(BlockType::TreeNode) => {
+            let (b, node) = tree_node_block(b)?;
+            Ok((b, Some(ParsedBlock::TreeNode(node))))
+        }
+        Ok(BlockType::SceneGroupItem) => {
+            trace!("reading group item");
+            let block_parser = scene_item_block(ItemType::Group, group_item_subblock);
+
+            let (b, block) = block_parser(b)?;
+            Ok((b, Some(ParsedBlock::Group(block))))
+        }
+        Ok(BlockType::SceneLineItem) => {
             trace!("reading line item");
             let item_parser = line_item_subblock(current_version);
             let block_parser = scene_item_block(ItemType::Line, item_parser);
 
-            let (sb, subblock) = block_parser(b)?;
-            warn!("remaining subblock len: {}", sb.len());
+            let (b, block) = block_parser(b)?;
+            Ok((b, Some(ParsedBlock::Line(block))))
+        }
+        Ok(BlockType::SceneTombstoneItem) => {
+            trace!("reading tombstone item");
+            let block_parser = scene_item_block(ItemType::Tombstone, |s| Ok((s, ())));
 
-            Ok((b, subblock))
+            let (b, block) = block_parser(b)?;
+            Ok((b, Some(ParsedBlock::Tombstone(block))))
+        }
+        Ok(BlockType::RootText) => {
+            let (b, text) = root_text_block(b)?;
+            Ok((b, Some(ParsedBlock::Text(text))))
         }
         Ok(BlockType::AuthorInfo) => {
             let (b, _) = author_ids_block(b)?;
@@ -446,36 +786,54 @@ fn read_block_v6(s: ParserInput) -> ParserResult<Option<LineItemBlock>> {
     Ok((s, block))
 }
 
-pub fn read_page_v6(s: ParserInput) -> ParserResult<Vec<Layer>> {
+pub fn read_page_v6(s: ParserInput) -> ParserResult<(Vec<Layer>, Option<Text>)> {
     let (s, blocks) = many1(read_block_v6)(s)?;
     info!("blocks length: {}", blocks.len());
     info!("remaining buffer len: {}", s.len());
 
-    let mut all_ids = HashMap::new();
-    let mut lines = Vec::new();
-    for b in blocks {
-        if let Some(b) = b {
-            for id in [b.parent_id, b.item_id, b.left_id, b.right_id] {
-                if all_ids.contains_key(&id) {
-                    let count = all_ids.get_mut(&id).unwrap();
-                    *count += 1;
-                } else {
-                    all_ids.insert(id, 1u64);
-                }
+    let mut tree = SceneTree::default();
+    let mut text = None;
+    for b in blocks.into_iter().flatten() {
+        match b {
+            ParsedBlock::SceneTree(node) => tree.add_node(node.node_id, node.parent_id),
+            ParsedBlock::TreeNode(node) => {
+                tree.set_node_properties(node.node_id, node.label, node.visible)
+            }
+            ParsedBlock::Group(b) => {
+                let (parent_id, child) = b.into_child(SceneChild::Group);
+                tree.add_child(parent_id, child);
+            }
+            ParsedBlock::Line(b) => {
+                let (parent_id, child) = b.into_child(|line| {
+                    SceneChild::Line(Line {
+                        brush_type: line.brush_type,
+                        color: line.color,
+                        brush_size: line.thickness_scale as f32,
+                        points: line.points,
+                        deleted: false,
+                    })
+                });
+                tree.add_child(parent_id, child);
+            }
+            ParsedBlock::Tombstone(b) => {
+                let (parent_id, child) = b.into_child(|_| SceneChild::Tombstone);
+                tree.add_child(parent_id, child);
+            }
+            ParsedBlock::Text(t) => {
+                trace!("found text with {} paragraphs", t.paragraphs.len());
+                text = Some(t);
             }
-
-            lines.push(Line {
-                brush_type: b.subblock.brush_type,
-                color: b.subblock.color,
-                brush_size: b.subblock.thickness_scale as f32,
-                points: b.subblock.points,
-            });
         }
     }
 
-    info!("found {} IDs: {all_ids:?}", all_ids.len());
+    let layers = tree.into_layers();
+    info!(
+        "resolved {} layers: {:?}",
+        layers.len(),
+        layers.iter().map(|l| &l.name).collect::<Vec<_>>()
+    );
 
-    Ok((s, vec![Layer { lines }]))
+    Ok((s, (layers, text)))
 }
 
 #[cfg(test)]
@@ -498,4 +856,122 @@ mod tests {
         let (_s, parsed) = varuint(bytes).unwrap();
         assert_eq!(parsed, expected);
     }
+
+    fn text_run(part2: u64, left: u64, text: &str) -> SequenceItem<TextRun> {
+        SequenceItem {
+            item_id: CrdtId::new(1, part2),
+            left_id: if left == 0 {
+                END_MARKER
+            } else {
+                CrdtId::new(1, left)
+            },
+            right_id: END_MARKER,
+            deleted_length: 0,
+            value: TextRun::Text(text.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_text_paragraphs() {
+        // "Title\nbody" followed by "\nitem", inserted out of order
+        let items = vec![text_run(20, 19, "\nitem"), text_run(10, 0, "Title\nbody")];
+        let formats = vec![
+            TextFormat {
+                char_id: END_MARKER,
+                timestamp: CrdtId::new(1, 1),
+                style: ParagraphStyle::Heading,
+            },
+            TextFormat {
+                char_id: CrdtId::new(1, 20),
+                timestamp: CrdtId::new(1, 2),
+                style: ParagraphStyle::Plain,
+            },
+            TextFormat {
+                char_id: CrdtId::new(1, 20),
+                timestamp: CrdtId::new(1, 3),
+                style: ParagraphStyle::Bullet,
+            },
+        ];
+
+        let paragraphs: Vec<_> = text_paragraphs(items, formats)
+            .into_iter()
+            .map(|p| (p.style, p.contents))
+            .collect();
+        assert_eq!(
+            paragraphs,
+            vec![
+                (ParagraphStyle::Heading, "Title".to_string()),
+                (ParagraphStyle::Plain, "body".to_string()),
+                (ParagraphStyle::Bullet, "item".to_string()),
+            ]
+        );
+    }
+
+    fn line_item_bytes(color_id: u32, argb: Option<u32>) -> Vec<u8> {
+        let mut bytes = vec![0x14];
+        bytes.extend(0x0Fu32.to_le_bytes()); // ballpoint
+        bytes.push(0x24);
+        bytes.extend(color_id.to_le_bytes());
+        bytes.push(0x38);
+        bytes.extend(1.0f64.to_le_bytes());
+        bytes.push(0x44);
+        bytes.extend(0.0f32.to_le_bytes());
+        bytes.push(0x5C);
+        bytes.extend(14u32.to_le_bytes());
+        bytes.extend(10.0f32.to_le_bytes());
+        bytes.extend(20.0f32.to_le_bytes());
+        bytes.extend([1, 0, 2, 0, 3, 4]);
+        bytes.extend([0x6F, 0x01, 0x2A]);
+        if let Some(argb) = argb {
+            bytes.extend([0x84, 0x01]);
+            bytes.extend(argb.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[rstest]
+    #[case(line_item_bytes(6, None), Color::Blue)]
+    #[case(line_item_bytes(12, None), Color::Magenta)]
+    #[case(line_item_bytes(99, None), Color::Black)]
+    #[case(line_item_bytes(9, Some(0x80FBF719)), Color::Rgba([0xFB, 0xF7, 0x19, 0x80]))]
+    fn test_line_item_color(#[case] bytes: Vec<u8>, #[case] expected: Color) {
+        let (rem, line) = line_item_subblock(2)(&bytes).unwrap();
+        assert!(rem.is_empty());
+        assert_eq!(line.color, expected);
+        assert_eq!(line.points.len(), 1);
+    }
+
+    #[test]
+    fn test_line_item_unknown_version() {
+        let bytes = line_item_bytes(0, None);
+        assert!(matches!(
+            line_item_subblock(3)(&bytes),
+            Err(nom::Err::Failure(_))
+        ));
+    }
+
+    const FINELINER: &[u8] = include_bytes!("../../tests/fixtures/v6_fineliner.rm");
+
+    #[test]
+    fn test_parse_v6_fixture() {
+        let (rem, (version, layers, text)) = crate::parser::parse(FINELINER).unwrap();
+        assert!(rem.is_empty());
+        assert_eq!(version, Version::V6);
+        assert!(text.is_none());
+
+        assert_eq!(layers.len(), 1);
+        assert_eq!(layers[0].name.as_deref(), Some("Layer 1"));
+        assert_eq!(layers[0].lines.len(), 2);
+
+        // version 2 lines store widths in quarter units, and version 1 lines as floats
+        let (v2, v1) = (&layers[0].lines[0], &layers[0].lines[1]);
+        assert!(matches!(v2.brush_type, BrushType::Fineliner));
+        assert_eq!(v2.points.len(), 11);
+        assert!(v2
+            .points
+            .iter()
+            .all(|p| p.width == 6.0 && p.pressure == 1.0));
+        assert_eq!(v2.points[0].x, (model::WIDTH_PIXELS / 2) as f32 - 100.0);
+        assert!(v1.points.iter().all(|p| p.width == 3.0));
+    }
 }
@@ -0,0 +1,41 @@
+//! Keeps log lines from being drawn into a progress bar.
+//!
+//! The bar and the logs both go to stderr, so a log line written while the
+//! bar is shown would be overwritten by the next update.  Logs are written
+//! through `LogWriter`, which hides the bar that is shown while it writes.
+use std::io::Write;
+use std::sync::Mutex;
+
+use indicatif::ProgressBar;
+
+/// The bar that is shown, if any.
+static SHOWN: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+/// Shows `bar`, with log lines written above it.
+pub fn show(bar: &ProgressBar) {
+    *SHOWN.lock().unwrap() = Some(bar.clone());
+}
+
+/// Removes `bar` once it is done.
+pub fn finish(bar: &ProgressBar) {
+    bar.finish_and_clear();
+    SHOWN.lock().unwrap().take();
+}
+
+/// Writes logs to stderr around the bar that is shown.
+pub struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let shown = SHOWN.lock().unwrap().clone();
+        match shown {
+            Some(bar) => bar.suspend(|| std::io::stderr().write_all(buf))?,
+            None => std::io::stderr().write_all(buf)?,
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stderr().flush()
+    }
+}
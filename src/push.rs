@@ -0,0 +1,266 @@
+//! Adds PDF and EPUB documents to the device's library.
+//!
+//! A document is stored by xochitl as `<uuid>.pdf` or `<uuid>.epub` next to a
+//! `<uuid>.metadata` file with its name and folder, and a `<uuid>.content`
+//! file with its type.  xochitl fills in the rest the first time it opens the
+//! document, and only reads new files when it starts.
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::device::Remarkable;
+use crate::model::fs::{serde::FileType, Notebooks, Parent};
+
+/// The `.metadata` of a new document or folder.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Metadata<'a> {
+    deleted: bool,
+    /// Milliseconds since the Unix epoch, as a string.
+    last_modified: String,
+    metadatamodified: bool,
+    modified: bool,
+    parent: &'a str,
+    pinned: bool,
+    synced: bool,
+    #[serde(rename = "type")]
+    element_type: &'a str,
+    version: u32,
+    visible_name: &'a str,
+}
+
+fn metadata(name: &str, element_type: &str, parent: &Parent, now: SystemTime) -> Result<String> {
+    let parent = match parent {
+        Parent::Root => "",
+        Parent::Trash => "trash",
+        Parent::Collection(id) => id,
+    };
+    let millis = now.duration_since(UNIX_EPOCH)?.as_millis();
+    Ok(serde_json::to_string_pretty(&Metadata {
+        deleted: false,
+        last_modified: millis.to_string(),
+        metadatamodified: false,
+        modified: false,
+        parent,
+        pinned: false,
+        synced: false,
+        element_type,
+        version: 0,
+        visible_name: name,
+    })?)
+}
+
+/// The `.content` of a new document, or with no file type, of a folder.
+fn content(file_type: Option<FileType>) -> String {
+    match file_type {
+        Some(FileType::Pdf) => r#"{"fileType": "pdf", "tags": []}"#.to_string(),
+        Some(FileType::Epub) => r#"{"fileType": "epub", "tags": []}"#.to_string(),
+        Some(FileType::Notebook) | None => r#"{"tags": []}"#.to_string(),
+    }
+}
+
+/// The type of a document to push, by its extension.
+fn file_type(file: &Path) -> Result<(FileType, &'static str)> {
+    let extension = file.extension().and_then(OsStr::to_str).unwrap_or_default();
+    match extension.to_ascii_lowercase().as_str() {
+        "pdf" => Ok((FileType::Pdf, "pdf")),
+        "epub" => Ok((FileType::Epub, "epub")),
+        _ => Err(anyhow!(
+            "only PDF and EPUB documents can be pushed, not {file:?}"
+        )),
+    }
+}
+
+/// A folder to create on the device.
+#[derive(Debug, PartialEq)]
+struct NewFolder {
+    id: String,
+    name: String,
+    parent: Parent,
+}
+
+/// Finds the folder at `path`, a `/`-separated list of folder names, in the
+/// library.  Returns the folder along with those of its ancestors that have
+/// to be created first, outermost first.
+fn resolve_folder(
+    library: &Notebooks,
+    path: &str,
+    new_id: &mut dyn FnMut() -> String,
+) -> (Parent, Vec<NewFolder>) {
+    let mut parent = Parent::Root;
+    let mut new_folders = Vec::new();
+
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        // folders of the same name are told apart by their ID, so that the
+        // same one is picked every time
+        let existing = if new_folders.is_empty() {
+            library
+                .collections
+                .iter()
+                .filter(|(_, c)| c.parent == parent && c.name == name)
+                .map(|(id, _)| id)
+                .min()
+        } else {
+            None
+        };
+        parent = match existing {
+            Some(id) => Parent::Collection(id.clone()),
+            None => {
+                let id = new_id();
+                new_folders.push(NewFolder {
+                    id: id.clone(),
+                    name: name.to_string(),
+                    parent,
+                });
+                Parent::Collection(id)
+            }
+        };
+    }
+
+    (parent, new_folders)
+}
+
+/// Uploads `file` into `folder` on the device, creating any folders that are
+/// missing, and restarts xochitl for the document to show up if `restart`
+/// is set.  Returns the ID of the new document.
+pub fn push(rem: &Remarkable, file: &Path, folder: Option<&str>, restart: bool) -> Result<String> {
+    let (file_type, extension) = file_type(file)?;
+    let name = file
+        .file_stem()
+        .and_then(OsStr::to_str)
+        .ok_or(anyhow!("invalid file name {file:?}"))?;
+    let now = SystemTime::now();
+
+    // the folders on the device are read like those of a backup
+    let scratch_dir = tempfile::tempdir()?;
+    rem.sync_metadata_to(scratch_dir.path())?;
+    let library = crate::fs::scan(scratch_dir.path())?;
+
+    // the files are written locally first, and then uploaded together
+    let staging_dir = tempfile::tempdir()?;
+    let mut files = Vec::new();
+    let mut stage = |name: String, contents: &[u8]| -> Result<()> {
+        std::fs::write(staging_dir.path().join(&name), contents)?;
+        files.push(PathBuf::from(name));
+        Ok(())
+    };
+
+    let mut new_id = || Uuid::new_v4().to_string();
+    let (parent, new_folders) = resolve_folder(&library, folder.unwrap_or_default(), &mut new_id);
+    for new_folder in new_folders {
+        info!("creating folder {:?}", new_folder.name);
+        let metadata = metadata(&new_folder.name, "CollectionType", &new_folder.parent, now)?;
+        stage(
+            format!("{}.content", new_folder.id),
+            content(None).as_bytes(),
+        )?;
+        stage(format!("{}.metadata", new_folder.id), metadata.as_bytes())?;
+    }
+
+    let id = new_id();
+    info!("pushing {file:?} as {id}");
+    let contents = std::fs::read(file).context(format!("failed to read {file:?}"))?;
+    stage(format!("{id}.{extension}"), &contents)?;
+    stage(format!("{id}.content"), content(Some(file_type)).as_bytes())?;
+    // the metadata is written last, since xochitl lists documents by it
+    let metadata = metadata(name, "DocumentType", &parent, now)?;
+    stage(format!("{id}.metadata"), metadata.as_bytes())?;
+
+    // every file is new, so those already uploaded are removed if one fails,
+    // rather than leaving part of the document on the device
+    if let Err(e) = rem.upload_document_files(staging_dir.path(), &files) {
+        if let Err(cleanup) = rem.remove_document_files(&files) {
+            warn!("failed to remove the files uploaded so far: {cleanup:#}");
+        }
+        return Err(e);
+    }
+
+    if restart {
+        rem.restart_xochitl()?;
+    }
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::fs::{serde::NotebookMetadata, Collection};
+    use std::collections::HashMap;
+
+    fn collection(name: &str, parent: &str) -> Collection {
+        Collection {
+            name: name.to_string(),
+            parent: parent.into(),
+            last_modified: None,
+            pinned: false,
+        }
+    }
+
+    #[test]
+    fn test_resolve_folder() {
+        let library = Notebooks {
+            root: PathBuf::new(),
+            collections: HashMap::from([
+                ("b".to_string(), collection("Work", "")),
+                ("a".to_string(), collection("Work", "")),
+                ("c".to_string(), collection("Papers", "a")),
+                ("d".to_string(), collection("Old", "trash")),
+            ]),
+            notebooks: Vec::new(),
+            failed: Vec::new(),
+        };
+        let mut ids = 0;
+        let mut new_id = || {
+            ids += 1;
+            format!("new{ids}")
+        };
+
+        let (parent, new_folders) = resolve_folder(&library, "Work/Papers", &mut new_id);
+        assert_eq!(parent, Parent::Collection("c".to_string()));
+        assert!(new_folders.is_empty());
+        assert_eq!(resolve_folder(&library, "", &mut new_id).0, Parent::Root);
+
+        // folders in the trash aren't reused
+        let (parent, new_folders) = resolve_folder(&library, "Work/Old/2023", &mut new_id);
+        assert_eq!(parent, Parent::Collection("new2".to_string()));
+        assert_eq!(
+            new_folders,
+            vec![
+                NewFolder {
+                    id: "new1".to_string(),
+                    name: "Old".to_string(),
+                    parent: Parent::Collection("a".to_string()),
+                },
+                NewFolder {
+                    id: "new2".to_string(),
+                    name: "2023".to_string(),
+                    parent: Parent::Collection("new1".to_string()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_metadata() {
+        let now = UNIX_EPOCH + std::time::Duration::from_millis(1700000000123);
+        let json = metadata(
+            "Paper",
+            "DocumentType",
+            &Parent::Collection("a".into()),
+            now,
+        )
+        .unwrap();
+        let parsed: NotebookMetadata = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.visible_name, "Paper");
+        assert_eq!(parsed.parent, "a");
+        assert_eq!(parsed.last_modified.unwrap().to_system_time(), Some(now));
+
+        assert!(file_type(Path::new("paper.PDF")).is_ok());
+        assert!(file_type(Path::new("notes.txt")).is_err());
+    }
+}
@@ -0,0 +1,351 @@
+//! Overlaying rendered pages onto the PDF that a notebook annotates.
+//!
+//! The strokes are first rendered to device-sized pages as for any notebook.
+//! Each of those pages is then turned into a form XObject and drawn on top of
+//! the page of the base document it annotates, scaled the way the tablet fits
+//! the document page to its screen.
+use std::collections::HashSet;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use printpdf::lopdf::{self, Dictionary, Object, ObjectId, Stream};
+use tracing::{trace, warn};
+
+use super::PT_PER_MM;
+use crate::model;
+
+/// Resource name of the rendered strokes on each annotated page.
+const OVERLAY_NAME: &str = "RmOverlay";
+
+/// How a rendered page relates to the base document.
+#[derive(Debug)]
+pub struct OverlayPage {
+    /// Index of the page of the base document to draw the strokes on, or `None`
+    /// for a page that was inserted on the tablet.
+    pub source_page: Option<usize>,
+    /// Whether the document page is centred horizontally on the screen, as it
+    /// is for v6 pages, rather than aligned to the left.
+    pub centred: bool,
+}
+
+/// Looks up `key` on a page, following the page tree for inheritable attributes.
+fn inherited(doc: &lopdf::Document, page_id: ObjectId, key: &[u8]) -> Option<Object> {
+    let mut node = doc.get_dictionary(page_id).ok()?;
+    loop {
+        if let Ok(value) = node.get(key) {
+            return Some(value.clone());
+        }
+        let parent = node.get(b"Parent").and_then(Object::as_reference).ok()?;
+        node = doc.get_dictionary(parent).ok()?;
+    }
+}
+
+/// Resolves a reference to the dictionary it points to, or returns the
+/// dictionary itself.
+fn resolve_dict(doc: &lopdf::Document, object: &Object) -> Result<Dictionary> {
+    match object {
+        Object::Reference(id) => Ok(doc.get_dictionary(*id)?.clone()),
+        Object::Dictionary(dict) => Ok(dict.clone()),
+        other => Err(anyhow!("expected a dictionary, found {other:?}")),
+    }
+}
+
+fn media_box(doc: &lopdf::Document, page_id: ObjectId) -> Result<[f64; 4]> {
+    let object = inherited(doc, page_id, b"MediaBox").ok_or(anyhow!("page has no MediaBox"))?;
+    let values = match object {
+        Object::Reference(id) => doc.get_object(id)?.as_array()?.clone(),
+        Object::Array(values) => values,
+        other => return Err(anyhow!("invalid MediaBox {other:?}")),
+    };
+
+    let mut bounds = [0.0; 4];
+    for (bound, value) in bounds.iter_mut().zip(&values) {
+        *bound = value.as_float()?;
+    }
+    Ok(bounds)
+}
+
+/// Computes the matrix that maps the device-sized overlay page onto a base
+/// page with the given MediaBox.  The tablet scales the page to fit its screen
+/// and aligns it with the top of the screen.
+fn overlay_matrix([x0, y0, x1, y1]: [f64; 4], centred: bool) -> [f64; 6] {
+    let screen_width = model::WIDTH_PIXELS as f64;
+    let screen_height = model::HEIGHT_PIXELS as f64;
+
+    // screen pixels per base document point
+    let scale = (screen_width / (x1 - x0)).min(screen_height / (y1 - y0));
+    let offset_x = if centred {
+        (screen_width - (x1 - x0) * scale) / 2.0
+    } else {
+        0.0
+    };
+
+    // overlay pages are laid out with one screen pixel per millimetre
+    let overlay_scale = 1.0 / (PT_PER_MM * scale);
+    [
+        overlay_scale,
+        0.0,
+        0.0,
+        overlay_scale,
+        x0 - offset_x / scale,
+        y1 - screen_height / scale,
+    ]
+}
+
+fn content_stream(content: &[u8]) -> Stream {
+    Stream::new(Dictionary::new(), content.to_vec())
+}
+
+/// Turns a page of the overlay document into a form XObject.
+fn overlay_form(doc: &lopdf::Document, page_id: ObjectId, matrix: [f64; 6]) -> Result<Stream> {
+    let [x0, y0, x1, y1] = media_box(doc, page_id)?;
+    let resources = inherited(doc, page_id, b"Resources").unwrap_or(Dictionary::new().into());
+
+    let mut dict = Dictionary::new();
+    dict.set("Type", Object::Name(b"XObject".to_vec()));
+    dict.set("Subtype", Object::Name(b"Form".to_vec()));
+    dict.set(
+        "BBox",
+        vec![x0.into(), y0.into(), x1.into(), y1.into()] as Vec<Object>,
+    );
+    dict.set(
+        "Matrix",
+        matrix.iter().map(|&v| v.into()).collect::<Vec<Object>>(),
+    );
+    dict.set("Resources", resources);
+
+    let mut form = Stream::new(dict, doc.get_page_content(page_id)?);
+    // compression is an optimization, the form is valid either way
+    let _ = form.compress();
+    Ok(form)
+}
+
+/// Draws `overlay_page` on top of `page_id`, which must be a page of `doc`.
+fn annotate_page(
+    doc: &mut lopdf::Document,
+    page_id: ObjectId,
+    overlay_page: ObjectId,
+    centred: bool,
+) -> Result<()> {
+    if inherited(doc, page_id, b"Rotate")
+        .and_then(|r| r.as_i64().ok())
+        .unwrap_or(0)
+        % 360
+        != 0
+    {
+        warn!("annotations on rotated pages may be misplaced");
+    }
+
+    let matrix = overlay_matrix(media_box(doc, page_id)?, centred);
+    let form_id = doc.add_object(overlay_form(doc, overlay_page, matrix)?);
+
+    // the page's resources may be shared with other pages, so they are copied
+    // before the overlay is added to them
+    let mut resources = match inherited(doc, page_id, b"Resources") {
+        Some(resources) => resolve_dict(doc, &resources)?,
+        None => Dictionary::new(),
+    };
+    let mut xobjects = match resources.get(b"XObject") {
+        Ok(xobjects) => resolve_dict(doc, xobjects)?,
+        Err(_) => Dictionary::new(),
+    };
+    xobjects.set(OVERLAY_NAME, form_id);
+    resources.set("XObject", xobjects);
+
+    // the document's content is wrapped in a saved graphics state so that the
+    // strokes are drawn in the page's default coordinate system
+    let mut contents = vec![Object::Reference(doc.add_object(content_stream(b"q\n")))];
+    match doc.get_dictionary(page_id)?.get(b"Contents") {
+        Ok(Object::Array(streams)) => contents.extend(streams.iter().cloned()),
+        Ok(stream) => contents.push(stream.clone()),
+        Err(_) => {}
+    }
+    let draw_overlay = format!("Q\nq /{OVERLAY_NAME} Do Q\n");
+    contents.push(Object::Reference(
+        doc.add_object(content_stream(draw_overlay.as_bytes())),
+    ));
+
+    // the page moves to a new page tree, so attributes it inherited are set on
+    // the page itself
+    let inherited: Vec<_> = [&b"MediaBox"[..], b"CropBox", b"Rotate"]
+        .into_iter()
+        .filter_map(|key| inherited(doc, page_id, key).map(|value| (key, value)))
+        .collect();
+    let page = doc.get_object_mut(page_id)?.as_dict_mut()?;
+    for (key, value) in inherited {
+        page.set(key, value);
+    }
+    page.set("Resources", resources);
+    page.set("Contents", contents);
+    Ok(())
+}
+
+/// Draws each page of `overlay` on the page of the PDF at `base_path` given by
+/// `pages`, returning the annotated document.  Pages with no source page are
+/// kept as they were rendered.
+pub fn overlay_on_base(base_path: &Path, overlay: &[u8], pages: &[OverlayPage]) -> Result<Vec<u8>> {
+    let mut doc = lopdf::Document::load(base_path)
+        .context(format!("failed to load base document {base_path:?}"))?;
+    let base_pages: Vec<ObjectId> = doc.get_pages().into_values().collect();
+    // copies of pages shown more than once are made from the unannotated page
+    let originals = base_pages
+        .iter()
+        .map(|&id| doc.get_dictionary(id).cloned())
+        .collect::<lopdf::Result<Vec<_>>>()?;
+
+    // move the objects of the rendered pages into the base document
+    let mut overlay =
+        lopdf::Document::load_mem(overlay).context("failed to reload rendered PDF")?;
+    overlay.renumber_objects_with(doc.max_id + 1);
+    let overlay_pages: Vec<ObjectId> = overlay.get_pages().into_values().collect();
+    doc.max_id = overlay.max_id;
+    doc.objects.append(&mut overlay.objects);
+
+    let mut used = HashSet::new();
+    let mut kids = Vec::new();
+    for (page, &overlay_page) in pages.iter().zip(&overlay_pages) {
+        let base_page = page
+            .source_page
+            .filter(|&idx| idx < base_pages.len())
+            .map(|idx| (idx, base_pages[idx]));
+        let page_id = match (page.source_page, base_page) {
+            (_, Some((idx, base_page))) => {
+                // a document page shown more than once gets a copy for each use
+                let page_id = if used.insert(base_page) {
+                    base_page
+                } else {
+                    doc.add_object(originals[idx].clone())
+                };
+                trace!("overlaying {overlay_page:?} on {page_id:?}");
+                annotate_page(&mut doc, page_id, overlay_page, page.centred)?;
+                page_id
+            }
+            (Some(idx), None) => {
+                warn!("base document has no page {idx}, keeping the page blank");
+                overlay_page
+            }
+            (None, None) => overlay_page,
+        };
+        kids.push(page_id);
+    }
+
+    // replace the page tree with the annotated pages in notebook order
+    let pages_id = doc.new_object_id();
+    for &kid in &kids {
+        doc.get_object_mut(kid)?
+            .as_dict_mut()?
+            .set("Parent", pages_id);
+    }
+    let mut pages_dict = Dictionary::new();
+    pages_dict.set("Type", Object::Name(b"Pages".to_vec()));
+    pages_dict.set("Count", kids.len() as i64);
+    pages_dict.set(
+        "Kids",
+        kids.into_iter().map(Object::Reference).collect::<Vec<_>>(),
+    );
+    doc.objects.insert(pages_id, pages_dict.into());
+
+    let catalog_id = doc.trailer.get(b"Root")?.as_reference()?;
+    doc.get_object_mut(catalog_id)?
+        .as_dict_mut()?
+        .set("Pages", pages_id);
+    doc.prune_objects();
+
+    let mut bytes = Vec::new();
+    doc.save_to(&mut bytes)?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use printpdf::{Mm, PdfDocument};
+    use rstest::rstest;
+
+    fn apply([a, _, _, d, e, f]: [f64; 6], (x, y): (f64, f64)) -> (f64, f64) {
+        (a * x + e, d * y + f)
+    }
+
+    /// Maps a screen position, in pixels from the top left, to the base page.
+    fn screen_to_base(matrix: [f64; 6], (x, y): (f64, f64)) -> (f64, f64) {
+        let overlay = (x * PT_PER_MM, (model::HEIGHT_PIXELS as f64 - y) * PT_PER_MM);
+        let (bx, by) = apply(matrix, overlay);
+        ((bx * 100.0).round() / 100.0, (by * 100.0).round() / 100.0)
+    }
+
+    #[rstest]
+    // a page with the screen's aspect ratio fills it exactly
+    #[case::same_aspect([0.0, 0.0, 702.0, 936.0], true, (1404.0, 1872.0), (702.0, 0.0))]
+    // a wider page is fitted to the screen's width and aligned to the top
+    #[case::landscape([0.0, 0.0, 936.0, 702.0], true, (1404.0, 1053.0), (936.0, 0.0))]
+    // a narrower page is fitted to the screen's height and centred
+    #[case::centred([0.0, 0.0, 351.0, 936.0], true, (1053.0, 1872.0), (351.0, 0.0))]
+    #[case::left_aligned([0.0, 0.0, 351.0, 936.0], false, (702.0, 1872.0), (351.0, 0.0))]
+    #[case::offset_media_box([10.0, 20.0, 712.0, 956.0], true, (1404.0, 1872.0), (712.0, 20.0))]
+    fn test_overlay_matrix(
+        #[case] media_box: [f64; 4],
+        #[case] centred: bool,
+        #[case] screen: (f64, f64),
+        #[case] expected: (f64, f64),
+    ) {
+        let matrix = overlay_matrix(media_box, centred);
+        assert_eq!(screen_to_base(matrix, screen), expected);
+    }
+
+    fn pdf(pages: usize, width: f64) -> Vec<u8> {
+        let (doc, _, _) = PdfDocument::new("test", Mm(width), Mm(100.0), "Layer 1");
+        for _ in 1..pages {
+            doc.add_page(Mm(width), Mm(100.0), "Layer 1");
+        }
+        doc.save_to_bytes().unwrap()
+    }
+
+    #[test]
+    fn test_overlay_on_base() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let base_path = dir.join("base.pdf");
+        std::fs::write(&base_path, pdf(3, 50.0)).unwrap();
+
+        let pages = [
+            OverlayPage {
+                source_page: Some(2),
+                centred: true,
+            },
+            OverlayPage {
+                source_page: None,
+                centred: true,
+            },
+            OverlayPage {
+                source_page: Some(0),
+                centred: true,
+            },
+            OverlayPage {
+                source_page: Some(2),
+                centred: true,
+            },
+        ];
+        let annotated = overlay_on_base(&base_path, &pdf(4, 200.0), &pages).unwrap();
+
+        let doc = lopdf::Document::load_mem(&annotated).unwrap();
+        let page_ids: Vec<_> = doc.get_pages().into_values().collect();
+        assert_eq!(page_ids.len(), 4);
+        assert_eq!(page_ids.iter().collect::<HashSet<_>>().len(), 4);
+
+        let widths: Vec<_> = page_ids
+            .iter()
+            .map(|&id| (media_box(&doc, id).unwrap()[2] / PT_PER_MM).round())
+            .collect();
+        assert_eq!(widths, vec![50.0, 200.0, 50.0, 50.0]);
+
+        for &id in &[page_ids[0], page_ids[2], page_ids[3]] {
+            let resources = inherited(&doc, id, b"Resources").unwrap();
+            let resources = resolve_dict(&doc, &resources).unwrap();
+            let xobjects = resolve_dict(&doc, resources.get(b"XObject").unwrap()).unwrap();
+            assert!(xobjects.has(OVERLAY_NAME.as_bytes()));
+
+            let content = String::from_utf8(doc.get_page_content(id).unwrap()).unwrap();
+            assert!(content.ends_with("q /RmOverlay Do Q\n"), "{content}");
+        }
+    }
+}
@@ -1,33 +1,60 @@
 use crate::model::content::Color as ModelColor;
-use anyhow::{anyhow, Result};
 use printpdf::{Color as PdfColor, Rgb};
 
 pub const PDF_BLACK: PdfColor = to_pdf_color(ModelColor::Black);
 
-// const fn to_pdf_color_unchecked(color: ModelColor) -> PdfColor {
-//     match to_pdf_color(color) {
-//         Ok(color) => color,
-//         Err(e) => panic!(),
-//     }
-// }
+/// Maps a colour to the RGB components, each in `0.0..=1.0`, used to draw ink
+/// of that colour.  Values approximate the colours on a reMarkable Paper Pro.
+pub const fn to_rgb(color: ModelColor) -> [f32; 3] {
+    match color {
+        ModelColor::Black => [0.0, 0.0, 0.0],
+        ModelColor::Grey => [0.5, 0.5, 0.5],
+        ModelColor::White => [1.0, 1.0, 1.0],
+        ModelColor::Yellow => [1.0, 1.0, 0.0],
+        ModelColor::Green => [0.0, 1.0, 0.0],
+        ModelColor::Pink => [0.94, 0.37, 0.64],
+        ModelColor::Blue => [0.0, 0.0, 1.0],
+        ModelColor::Red => [1.0, 0.0, 0.0],
+        ModelColor::GreyOverlap => [0.75, 0.75, 0.75],
+        ModelColor::Highlight => [1.0, 0.93, 0.2],
+        ModelColor::Green2 => [0.56, 0.78, 0.24],
+        ModelColor::Cyan => [0.0, 0.74, 0.87],
+        ModelColor::Magenta => [0.8, 0.2, 0.7],
+        ModelColor::Yellow2 => [0.98, 0.85, 0.2],
+        ModelColor::Rgba([r, g, b, _a]) => [r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0],
+    }
+}
 
-pub const fn to_pdf_color(color: ModelColor) -> PdfColor {
-    let (r, g, b) = match color {
-        ModelColor::Black => (0.0, 0.0, 0.0),
-        ModelColor::Grey => (0.5, 0.5, 0.5),
-        ModelColor::White => panic!("unimplemented color White"),
-        ModelColor::Yellow => (1.0, 1.0, 0.0),
-        ModelColor::Green => (0.0, 1.0, 0.0),
-        ModelColor::Pink => panic!("unimplemented color Pink"),
-        ModelColor::Blue => (0.0, 0.0, 1.0),
-        ModelColor::Red => (1.0, 0.0, 0.0),
-        ModelColor::GreyOverlap => panic!("unimplemented color GreyOverlap"),
-    };
+/// Maps a colour to the tone used for highlighter strokes, which are lighter than
+/// the corresponding ink colours so that they can be blended over ink.
+pub const fn to_highlight_rgb(color: ModelColor) -> [f32; 3] {
+    match color {
+        ModelColor::Yellow | ModelColor::Yellow2 | ModelColor::Highlight => [1.0, 0.93, 0.2],
+        ModelColor::Green | ModelColor::Green2 => [0.55, 0.95, 0.45],
+        ModelColor::Pink | ModelColor::Magenta => [1.0, 0.5, 0.8],
+        ModelColor::Blue | ModelColor::Cyan => [0.5, 0.75, 1.0],
+        ModelColor::Red => [1.0, 0.45, 0.45],
+        ModelColor::Black | ModelColor::Grey | ModelColor::White | ModelColor::GreyOverlap => {
+            [0.75, 0.75, 0.75]
+        }
+        // custom colours already carry the exact shade picked on the device
+        ModelColor::Rgba(_) => to_rgb(color),
+    }
+}
 
+const fn rgb_to_pdf([r, g, b]: [f32; 3]) -> PdfColor {
     PdfColor::Rgb(Rgb {
-        r,
-        g,
-        b,
+        r: r as f64,
+        g: g as f64,
+        b: b as f64,
         icc_profile: None,
     })
 }
+
+pub const fn to_pdf_color(color: ModelColor) -> PdfColor {
+    rgb_to_pdf(to_rgb(color))
+}
+
+pub const fn to_pdf_highlight_color(color: ModelColor) -> PdfColor {
+    rgb_to_pdf(to_highlight_rgb(color))
+}
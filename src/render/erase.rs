@@ -0,0 +1,270 @@
+//! Eraser semantics for v3 and v5 pages.
+//!
+//! Older notebooks keep erased ink and record the eraser strokes after it, so
+//! the erasers have to be replayed to get the page shown on the tablet.  Rather
+//! than painting over the ink, the erased parts are cut out of earlier lines so
+//! that whatever is drawn underneath, such as a template, stays visible.
+use crate::model::content::{BrushType, Line, Point};
+
+/// Lines are resampled to at most this spacing, in page pixels, before being
+/// cut, so that an eraser passing between two points still splits the line.
+const MAX_SPACING: f32 = 1.0;
+
+enum Eraser {
+    /// A stroke that removes everything within half its width of its path.
+    Stroke(Vec<Point>),
+    /// An area, given by its outline, that removes everything inside it.
+    Area(Vec<(f32, f32)>),
+}
+
+impl Eraser {
+    fn from_line(line: &Line) -> Option<Self> {
+        match line.brush_type {
+            BrushType::Eraser => Some(Self::Stroke(line.points.clone())),
+            BrushType::EraserArea => {
+                Some(Self::Area(line.points.iter().map(|p| (p.x, p.y)).collect()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Bounding box of the erased region as `(min_x, min_y, max_x, max_y)`.
+    fn bounds(&self) -> (f32, f32, f32, f32) {
+        let points: Vec<(f32, f32, f32)> = match self {
+            Self::Stroke(points) => points.iter().map(|p| (p.x, p.y, p.width / 2.0)).collect(),
+            Self::Area(outline) => outline.iter().map(|&(x, y)| (x, y, 0.0)).collect(),
+        };
+        points.iter().fold(
+            (f32::MAX, f32::MAX, f32::MIN, f32::MIN),
+            |(x0, y0, x1, y1), &(x, y, r)| {
+                (x0.min(x - r), y0.min(y - r), x1.max(x + r), y1.max(y + r))
+            },
+        )
+    }
+
+    fn covers(&self, x: f32, y: f32) -> bool {
+        match self {
+            Self::Stroke(points) => match points.as_slice() {
+                [] => false,
+                [point] => (x - point.x).hypot(y - point.y) <= point.width / 2.0,
+                _ => points.windows(2).any(|segment| {
+                    let radius = segment[0].width.max(segment[1].width) / 2.0;
+                    segment_distance((x, y), &segment[0], &segment[1]) <= radius
+                }),
+            },
+            Self::Area(outline) => contains(outline, x, y),
+        }
+    }
+}
+
+/// Distance from `p` to the segment between `a` and `b`.
+fn segment_distance(p: (f32, f32), a: &Point, b: &Point) -> f32 {
+    let (dx, dy) = (b.x - a.x, b.y - a.y);
+    let length_sq = dx * dx + dy * dy;
+    let t = if length_sq > 0.0 {
+        (((p.0 - a.x) * dx + (p.1 - a.y) * dy) / length_sq).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    (p.0 - (a.x + t * dx)).hypot(p.1 - (a.y + t * dy))
+}
+
+/// Even-odd test of whether the polygon `outline` contains the point.
+fn contains(outline: &[(f32, f32)], x: f32, y: f32) -> bool {
+    let mut inside = false;
+    let mut j = outline.len().wrapping_sub(1);
+    for i in 0..outline.len() {
+        let (xi, yi) = outline[i];
+        let (xj, yj) = outline[j];
+        if (yi > y) != (yj > y) && x < xi + (y - yi) * (xj - xi) / (yj - yi) {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
+fn overlaps(a: (f32, f32, f32, f32), b: (f32, f32, f32, f32)) -> bool {
+    a.0 <= b.2 && b.0 <= a.2 && a.1 <= b.3 && b.1 <= a.3
+}
+
+fn line_bounds(line: &Line) -> (f32, f32, f32, f32) {
+    line.points.iter().fold(
+        (f32::MAX, f32::MAX, f32::MIN, f32::MIN),
+        |(x0, y0, x1, y1), p| {
+            let r = p.width / 2.0;
+            (
+                x0.min(p.x - r),
+                y0.min(p.y - r),
+                x1.max(p.x + r),
+                y1.max(p.y + r),
+            )
+        },
+    )
+}
+
+fn lerp(a: &Point, b: &Point, t: f32) -> Point {
+    let mix = |from: f32, to: f32| from + (to - from) * t;
+    Point {
+        x: mix(a.x, b.x),
+        y: mix(a.y, b.y),
+        speed: mix(a.speed, b.speed),
+        direction: mix(a.direction, b.direction),
+        width: mix(a.width, b.width),
+        pressure: mix(a.pressure, b.pressure),
+    }
+}
+
+/// Inserts interpolated points so that no two consecutive points are further
+/// apart than `MAX_SPACING`.
+fn resample(points: &[Point]) -> Vec<Point> {
+    let mut resampled = Vec::with_capacity(points.len());
+    for segment in points.windows(2) {
+        let length = (segment[1].x - segment[0].x).hypot(segment[1].y - segment[0].y);
+        let steps = (length / MAX_SPACING).ceil().max(1.0) as usize;
+        resampled
+            .extend((0..steps).map(|k| lerp(&segment[0], &segment[1], k as f32 / steps as f32)));
+    }
+    resampled.extend(points.last().copied());
+    resampled
+}
+
+/// Removes the parts of `line` covered by `eraser`, returning the pieces left.
+fn cut(line: Line, eraser: &Eraser) -> Vec<Line> {
+    let points = resample(&line.points);
+    let mut pieces = Vec::new();
+    let mut run = Vec::new();
+    for point in points {
+        if eraser.covers(point.x, point.y) {
+            if run.len() > 1 {
+                pieces.push(std::mem::take(&mut run));
+            }
+            run.clear();
+        } else {
+            run.push(point);
+        }
+    }
+    if !run.is_empty() && (run.len() > 1 || pieces.is_empty()) {
+        pieces.push(run);
+    }
+
+    pieces
+        .into_iter()
+        .map(|points| Line {
+            brush_type: line.brush_type,
+            color: line.color,
+            brush_size: line.brush_size,
+            points,
+            deleted: line.deleted,
+        })
+        .collect()
+}
+
+/// Replays the eraser strokes of a layer, in order, on the lines before them.
+/// The returned lines no longer include the erasers themselves.
+pub fn apply_erasers(lines: Vec<Line>) -> Vec<Line> {
+    let mut result: Vec<Line> = Vec::with_capacity(lines.len());
+    for line in lines {
+        let Some(eraser) = Eraser::from_line(&line) else {
+            result.push(line);
+            continue;
+        };
+
+        let bounds = eraser.bounds();
+        result = result
+            .into_iter()
+            .flat_map(|line| {
+                if overlaps(bounds, line_bounds(&line)) {
+                    cut(line, &eraser)
+                } else {
+                    vec![line]
+                }
+            })
+            .collect();
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::content::Color;
+    use rstest::rstest;
+
+    fn line(brush_type: BrushType, width: f32, points: &[(f32, f32)]) -> Line {
+        Line {
+            brush_type,
+            color: Color::Black,
+            brush_size: 2.0,
+            points: points
+                .iter()
+                .map(|&(x, y)| Point {
+                    x,
+                    y,
+                    speed: 0.0,
+                    direction: 0.0,
+                    width,
+                    pressure: 0.5,
+                })
+                .collect(),
+            deleted: false,
+        }
+    }
+
+    /// Summarizes each line as its brush and the x range it covers.
+    fn extents(lines: &[Line]) -> Vec<(String, f32, f32)> {
+        lines
+            .iter()
+            .map(|l| {
+                let xs = l.points.iter().map(|p| p.x);
+                (
+                    format!("{:?}", l.brush_type),
+                    xs.clone().fold(f32::MAX, f32::min).round(),
+                    xs.fold(f32::MIN, f32::max).round(),
+                )
+            })
+            .collect()
+    }
+
+    #[rstest]
+    #[case::splits_line(
+        vec![
+            line(BrushType::Fineliner, 2.0, &[(0.0, 50.0), (100.0, 50.0)]),
+            line(BrushType::Eraser, 20.0, &[(50.5, 0.0), (50.5, 100.0)]),
+        ],
+        vec![("Fineliner", 0.0, 40.0), ("Fineliner", 61.0, 100.0)],
+    )]
+    #[case::later_lines_untouched(
+        vec![
+            line(BrushType::Eraser, 20.0, &[(50.0, 0.0), (50.0, 100.0)]),
+            line(BrushType::Ballpoint, 2.0, &[(0.0, 50.0), (100.0, 50.0)]),
+        ],
+        vec![("Ballpoint", 0.0, 100.0)],
+    )]
+    #[case::removes_whole_line(
+        vec![
+            line(BrushType::Fineliner, 2.0, &[(45.0, 50.0), (55.0, 50.0)]),
+            line(BrushType::Eraser, 30.0, &[(50.0, 0.0), (50.0, 100.0)]),
+        ],
+        vec![],
+    )]
+    #[case::area(
+        vec![
+            line(BrushType::Fineliner, 2.0, &[(0.0, 50.0), (100.0, 50.0)]),
+            line(BrushType::Pencil, 2.0, &[(0.0, 150.0), (100.0, 150.0)]),
+            line(
+                BrushType::EraserArea,
+                1.0,
+                &[(20.5, 20.0), (80.5, 20.0), (80.5, 80.0), (20.5, 80.0)],
+            ),
+        ],
+        vec![("Fineliner", 0.0, 20.0), ("Fineliner", 81.0, 100.0), ("Pencil", 0.0, 100.0)],
+    )]
+    fn test_apply_erasers(#[case] lines: Vec<Line>, #[case] expected: Vec<(&str, f32, f32)>) {
+        let expected: Vec<_> = expected
+            .into_iter()
+            .map(|(brush, min, max)| (brush.to_string(), min, max))
+            .collect();
+        assert_eq!(extents(&apply_erasers(lines)), expected);
+    }
+}
@@ -1,20 +1,173 @@
+use anyhow::{Context, Result};
 use color::to_pdf_color;
 use printpdf::*;
-use std::fs::File;
-use std::io::BufWriter;
-use std::path::Path;
-use tracing::{debug, info, trace};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, trace, warn};
 
+use crate::manifest;
 use crate::model;
 use crate::model::content::{BrushType, Version};
 
+mod base;
 mod color;
+mod erase;
+mod pdf_template;
+mod raster;
+mod stroke;
+mod svg;
+mod template;
+mod text;
+mod texture;
+mod transparency;
+
+pub use raster::{render_raster, RasterOptions, DEVICE_DPI, MAX_DPI};
+pub use svg::render_svg;
+
+/// The renderer maps one page pixel to one millimetre, while font sizes are given in points.
+const PT_PER_MM: f64 = 72.0 / 25.4;
+
+/// Opacity of highlighter strokes, which are additionally multiplied with the
+/// content underneath so that ink stays legible.
+const HIGHLIGHTER_OPACITY: f32 = 0.8;
+
+/// Options that control what is drawn when rendering a notebook.
+#[derive(Debug, Default)]
+pub struct RenderOptions {
+    /// Draw lines that were erased or undone on the device, e.g. to recover them.
+    pub include_deleted: bool,
+    /// Directory to load the page templates from, which are left out without one.
+    pub templates_dir: Option<PathBuf>,
+}
+
+/// Name of the file for the page at `idx` when a notebook is written one file per page.
+fn page_file_name(idx: usize, extension: &str) -> String {
+    format!("page-{:03}.{extension}", idx + 1)
+}
+
+/// Creates `output_dir` for a notebook written one file per page, removing the
+/// pages of an earlier conversion, which may have had pages that are now gone.
+fn create_page_dir(output_dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(output_dir)
+        .context(format!("failed to create output directory {output_dir:?}"))?;
+    let entries =
+        std::fs::read_dir(output_dir).context(format!("failed to read {output_dir:?}"))?;
+    for entry in entries {
+        let entry = entry.context(format!("failed to read {output_dir:?}"))?;
+        let is_page = entry
+            .file_name()
+            .to_str()
+            .is_some_and(manifest::is_page_file);
+        if is_page && entry.file_type()?.is_file() {
+            let path = entry.path();
+            trace!("removing earlier page {path:?}");
+            std::fs::remove_file(&path).context(format!("failed to remove {path:?}"))?;
+        }
+    }
+    Ok(())
+}
+
+/// Warns that the PDF or EPUB under an annotated notebook's pages is left out of
+/// `format` output, which only has the annotations.
+fn warn_base_document_skipped(notebook: &model::content::Notebook, format: &str) {
+    if let Some(base_document) = &notebook.base_document {
+        warn!(
+            "{format} output of {} has only the annotations, without {base_document:?} under them",
+            notebook.id
+        );
+    }
+}
+
+/// Pairs the pages accepted by `page_filter` with their index in the notebook.
+fn filtered_pages(
+    pages: Vec<model::content::Page>,
+    page_filter: Box<dyn Fn(usize) -> bool>,
+) -> Vec<(usize, model::content::Page)> {
+    pages
+        .into_iter()
+        .enumerate()
+        .filter(|(idx, _)| page_filter(*idx))
+        .collect()
+}
+
+/// Prepares the lines of a layer for drawing, in z-order.  Erasers are replayed
+/// on older pages, and lines that should not be drawn are dropped.
+fn drawable_lines(
+    version: &Version,
+    lines: Vec<model::content::Line>,
+    options: &RenderOptions,
+) -> Vec<model::content::Line> {
+    // v6 pages record erasing by splitting and deleting lines, while older
+    // pages keep the eraser strokes, which have to be replayed
+    let lines = match version {
+        Version::V3 | Version::V5 => erase::apply_erasers(lines),
+        Version::V6 => lines,
+    };
+
+    lines
+        .into_iter()
+        .filter(|line| {
+            !matches!(line.brush_type, BrushType::Eraser | BrushType::EraserArea)
+                && (options.include_deleted || !line.deleted)
+        })
+        .collect()
+}
+
+/// Whether a line is drawn translucently and blended with the content below it.
+fn is_highlight(line: &model::content::Line) -> bool {
+    matches!(line.brush_type, BrushType::Highlighter) || line.color.is_highlight()
+}
+
+/// A line with its outline computed, ready to be added to the document.
+struct PreparedLine {
+    line: model::content::Line,
+    outline: Line,
+    texture: Option<texture::Texture>,
+}
+
+/// A page with the outlines of its visible layers computed, which is most of
+/// the work of rendering it.
+struct PreparedPage {
+    /// The page, whose layers have been moved to `layers`.
+    page: model::content::Page,
+    layers: Vec<(String, Vec<PreparedLine>)>,
+}
+
+fn prepare_page(mut page: model::content::Page, options: &RenderOptions) -> PreparedPage {
+    let mut layers = Vec::new();
+    for (layer_idx, layer) in std::mem::take(&mut page.layers).into_iter().enumerate() {
+        let layer_name = layer
+            .name
+            .unwrap_or_else(|| format!("Layer {}", layer_idx + 1));
+        if !layer.visible {
+            debug!("skipping hidden layer {layer_name:?}");
+            continue;
+        }
+
+        let lines = drawable_lines(&page.version, layer.lines, options)
+            .into_iter()
+            .map(|line| PreparedLine {
+                outline: outline_shape(&line),
+                texture: (!is_highlight(&line))
+                    .then(|| texture::texture(&line))
+                    .flatten(),
+                line,
+            })
+            .collect();
+        layers.push((layer_name, lines));
+    }
+
+    PreparedPage { page, layers }
+}
 
 pub fn render_pdf<F: AsRef<Path>>(
     notebook: model::content::Notebook,
     page_filter: Box<dyn Fn(usize) -> bool>,
+    options: &RenderOptions,
     output_file: F,
-) {
+) -> Result<()> {
+    debug!("rendering notebook {} as PDF", notebook.id);
     let page_width = Mm(model::WIDTH_PIXELS as _);
     let page_height = Mm(model::HEIGHT_PIXELS as _);
     let layer_name = "Layer 1";
@@ -27,100 +180,111 @@ pub fn render_pdf<F: AsRef<Path>>(
     );
     let black = Color::Greyscale(Greyscale::new(0.0, None));
 
+    let mut current_page = page1;
     let mut current_layer = doc.get_page(page1).get_layer(layer1);
 
-    for (idx, page) in notebook.pages.into_iter().enumerate() {
+    let text_layout = text::TextLayout::default();
+    let regular_font = doc.add_external_font(text::REGULAR_FONT_BYTES)?;
+    let bold_font = doc.add_external_font(text::BOLD_FONT_BYTES)?;
+    // each template is embedded once, and drawn by every page that has it
+    let mut templates = Vec::new();
+    let mut template_indices = HashMap::new();
+    let mut template_uses = Vec::new();
+
+    // the document can only be built one page after another, so the pages
+    // are prepared in parallel up front
+    let prepared_pages: Vec<_> = filtered_pages(notebook.pages, page_filter)
+        .into_par_iter()
+        .map(|(_, page)| prepare_page(page, options))
+        .collect();
+
+    // how each rendered page maps onto the annotated document, if there is one
+    let mut overlay_pages = Vec::new();
+
+    for PreparedPage { page, layers } in prepared_pages {
         let mut cumulative_thickness = 0.0;
         let mut point_count = 0;
 
-        if !page_filter(idx) {
-            continue;
+        debug!("rendering page {} ({:?})", page.id, page.version);
+        overlay_pages.push(base::OverlayPage {
+            source_page: page.source_page,
+            centred: page.version == Version::V6,
+        });
+
+        // draw the template under everything else.  pages of an annotated
+        // document show the document instead
+        let template =
+            match (&page.template, page.source_page, &options.templates_dir) {
+                (Some(name), None, Some(dir)) => *template_indices
+                    .entry(name.clone())
+                    .or_insert_with(|| match pdf_template::load(dir, name) {
+                        Ok(template) => {
+                            templates.push(template);
+                            Some(templates.len() - 1)
+                        }
+                        Err(e) => {
+                            warn!("not drawing template {name:?}: {e:#}");
+                            None
+                        }
+                    }),
+                _ => None,
+            };
+        if let Some(index) = template {
+            pdf_template::draw(&current_layer, index, &templates[index]);
         }
+        template_uses.push(template);
 
-        // draw the lines
-        for layer in page.layers {
-            for line in layer.lines {
-                let should_draw = match line.brush_type {
-                    BrushType::Eraser | BrushType::EraserArea => false,
-                    _ => true,
+        // draw the typed text as real text so that it remains selectable, on
+        // its own layer between the template and the strokes
+        if let Some(text) = &page.text {
+            let text_layer = doc.get_page(current_page).add_layer("Text");
+            for line in text_layout.layout(text) {
+                let font = match line.weight {
+                    text::FontWeight::Regular => &regular_font,
+                    text::FontWeight::Bold => &bold_font,
                 };
-                if !should_draw {
-                    continue;
-                }
+                let y = model::HEIGHT_PIXELS as f64 - line.y as f64;
+                text_layer.use_text(
+                    line.contents,
+                    line.size as f64 * PT_PER_MM,
+                    Mm(line.x as _),
+                    Mm(y),
+                    font,
+                );
+            }
+        }
 
-                let pdf_color = to_pdf_color(line.color);
-                current_layer.set_fill_color(pdf_color.clone());
-                current_layer.set_outline_color(pdf_color.clone());
-
-                for segment in line.points.windows(2) {
-                    let x0 = segment[0].x as f64;
-                    let y0 = model::HEIGHT_PIXELS as f64 - segment[0].y as f64;
-                    let x1 = segment[1].x as f64;
-                    let y1 = model::HEIGHT_PIXELS as f64 - segment[1].y as f64;
-
-                    let points = vec![
-                        (Point::new(Mm(x0), Mm(y0)), false),
-                        (Point::new(Mm(x1), Mm(y1)), false),
-                    ];
-
-                    let line1 = Line {
-                        points: points.clone(),
-                        is_closed: true,
-                        has_fill: true,
-                        has_stroke: true,
-                        is_clipping_path: false,
-                    };
-
-                    // for V6 the width per point / line segment is
-                    // very noisy.  there are random segments that render
-                    // extremely thin, segments that do not show up in the
-                    // source or screen rendering.  it seems that there is either
-                    // some alternate interpretation of the width field for certain
-                    // points (e.g. width values below 1.0 should be ignored or something)
-                    // or screen rendering is applying some smoothing process to line
-                    // widths.  to account for this, here we naively just render each
-                    // line as the max width observed across all points.  we likely lose
-                    // some visual specificity here (you can't draw a single line that
-                    // changes in thickness through the line) but it renders much better
-                    // than rendering the point widths unmodified.
-                    //
-                    // TODO: is there a better approach to smoothing?
-                    // TODO: should this be done in parsing?
-                    let effective_thickness = match page.version {
-                        Version::V3 => segment[0].width,
-                        Version::V5 => segment[0].width,
-                        Version::V6 => {
-                            let mut max_over_points: f32 = 0.0;
-                            for point in &line.points {
-                                max_over_points = max_over_points.max(point.width);
-                            }
-                            max_over_points * 4.0
-                        }
-                    };
+        // draw the lines
+        for (layer_name, lines) in layers {
+            // each notebook layer gets its own PDF layer, stacked in z-order
+            let pdf_layer = doc.get_page(current_page).add_layer(layer_name);
 
-                    debug!(
-                        "rendering point {:?} at thickness {} / {} => {}",
-                        points, segment[0].width, segment[1].width, effective_thickness
-                    );
-                    current_layer.set_outline_thickness(effective_thickness as _);
-                    current_layer.add_shape(line1);
+            for PreparedLine {
+                line,
+                outline,
+                texture,
+            } in lines
+            {
+                if is_highlight(&line) {
+                    draw_highlight(&pdf_layer, &line, outline);
+                    continue;
+                }
 
-                    cumulative_thickness += segment[0].width;
-                    point_count += 1;
+                if let Some(texture) = texture {
+                    draw_textured(&pdf_layer, &line, outline, &texture);
+                } else {
+                    pdf_layer.set_fill_color(to_pdf_color(line.color));
+                    pdf_layer.add_shape(outline);
+                    pdf_layer.set_fill_color(color::PDF_BLACK);
                 }
 
-                current_layer.set_fill_color(color::PDF_BLACK);
-                current_layer.set_outline_color(color::PDF_BLACK);
+                cumulative_thickness += line.points.iter().map(|p| p.width).sum::<f32>();
+                point_count += line.points.len();
             }
         }
 
-        // indicate the notebook and page ID in the bottom left corner.  this is helpful
-        // for debugging.  x is from left edge, y is from bottom edge.
-        let text = format!("notebook: {}, page: {}", notebook.id, page.id);
-        let font = doc.add_builtin_font(BuiltinFont::Courier).unwrap();
-        current_layer.use_text(text, 48.0, Mm(10.0), Mm(10.0), &font);
-
         let (next_page, next_layer) = doc.add_page(page_width, page_height, layer_name);
+        current_page = next_page;
         current_layer = doc.get_page(next_page).get_layer(next_layer);
         current_layer.set_fill_color(black.clone());
         current_layer.set_outline_color(black.clone());
@@ -129,10 +293,82 @@ pub fn render_pdf<F: AsRef<Path>>(
         info!("page stats: points={point_count}, cumulative_thickness={cumulative_thickness}, avg_thickness={avg_thickness}");
     }
 
-    trace!("writing to output path: {:?}", output_file.as_ref());
-    std::fs::create_dir_all(output_file.as_ref().parent().unwrap()).unwrap();
-    doc.save(&mut BufWriter::new(
-        File::create(output_file.as_ref()).unwrap(),
-    ))
-    .unwrap();
+    let output_file = output_file.as_ref();
+    trace!("writing to output path: {output_file:?}");
+    let mut pdf = doc.save_to_bytes()?;
+    if !templates.is_empty() {
+        pdf = pdf_template::add_templates(&pdf, &templates, &template_uses)?;
+    }
+    let pdf = transparency::add_transparency_resources(&pdf)?;
+    let pdf = match &notebook.base_document {
+        Some(base_path) => base::overlay_on_base(base_path, &pdf, &overlay_pages)
+            .context(format!("failed to annotate {base_path:?}"))?,
+        None => pdf,
+    };
+    if let Some(parent) = output_file.parent() {
+        std::fs::create_dir_all(parent)
+            .context(format!("failed to create output directory {parent:?}"))?;
+    }
+    std::fs::write(output_file, pdf).context(format!("failed to write {output_file:?}"))
+}
+
+/// Builds the filled outline of a stroke, flipped into PDF coordinates.
+fn outline_shape(line: &model::content::Line) -> Line {
+    let points = stroke::outline(line)
+        .into_iter()
+        .map(|(x, y)| {
+            let y = model::HEIGHT_PIXELS as f64 - y as f64;
+            (Point::new(Mm(x as _), Mm(y)), false)
+        })
+        .collect();
+
+    Line {
+        points,
+        is_closed: true,
+        has_fill: true,
+        has_stroke: false,
+        is_clipping_path: false,
+    }
+}
+
+/// Draws a highlighter stroke as a single shape blended with the content below it.
+/// Painting the whole stroke at once means overlapping parts of the same
+/// stroke do not darken each other.
+fn draw_highlight(pdf_layer: &PdfLayerReference, line: &model::content::Line, outline: Line) {
+    pdf_layer.save_graphics_state();
+    transparency::use_highlighter_state(pdf_layer);
+    pdf_layer.set_fill_color(color::to_pdf_highlight_color(line.color));
+    pdf_layer.add_shape(outline);
+    pdf_layer.restore_graphics_state();
+}
+
+/// Draws a pencil or paintbrush stroke translucently, overlaid with its specks.
+fn draw_textured(
+    pdf_layer: &PdfLayerReference,
+    line: &model::content::Line,
+    outline: Line,
+    texture: &texture::Texture,
+) {
+    pdf_layer.save_graphics_state();
+    transparency::use_opacity_state(pdf_layer, texture.opacity);
+    pdf_layer.set_fill_color(to_pdf_color(line.color));
+    pdf_layer.add_shape(outline);
+
+    for speck in &texture.specks {
+        let half = speck.size as f64 / 2.0;
+        let x = speck.x as f64;
+        let y = model::HEIGHT_PIXELS as f64 - speck.y as f64;
+        let corners = [(-half, -half), (half, -half), (half, half), (-half, half)];
+        pdf_layer.add_shape(Line {
+            points: corners
+                .iter()
+                .map(|(dx, dy)| (Point::new(Mm(x + dx), Mm(y + dy)), false))
+                .collect(),
+            is_closed: true,
+            has_fill: true,
+            has_stroke: false,
+            is_clipping_path: false,
+        });
+    }
+    pdf_layer.restore_graphics_state();
 }
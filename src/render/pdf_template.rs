@@ -0,0 +1,397 @@
+//! Page templates in PDF output.
+//!
+//! Each template is embedded once per document as an XObject, which every page
+//! with that template draws.  `printpdf` keeps XObjects per page, so pages only
+//! refer to the template by name, and the XObjects are added to the document
+//! after it is serialized, as with the graphics states of [`super::transparency`].
+//!
+//! SVG templates are converted to PDF paths so that they stay sharp at any
+//! zoom.  Those that use more of SVG than solid fills and strokes, such as
+//! gradients or transparency, are embedded as images like PNG templates are.
+use std::fmt::Write as _;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use image::RgbImage;
+use printpdf::lopdf::{self, content::Operation, Dictionary, Object, Stream};
+use printpdf::PdfLayerReference;
+use resvg::tiny_skia::{PathSegment, Transform};
+use resvg::usvg;
+use tracing::debug;
+use usvg::{TreeParsing, TreePostProc};
+
+use super::{template, PT_PER_MM};
+use crate::model;
+
+/// A template as it is embedded in the document.
+pub enum PdfTemplate {
+    /// PDF paths in the coordinates of an SVG of the given size.
+    Form {
+        content: Vec<u8>,
+        width: f32,
+        height: f32,
+    },
+    /// An image, stretched to the page.
+    Image(RgbImage),
+}
+
+/// Loads the template named `name` from `dir`, preferring its SVG as paths.
+pub fn load(dir: &Path, name: &str) -> Result<PdfTemplate> {
+    let svg_path = dir.join(format!("{name}.svg"));
+    if svg_path.exists() {
+        let data = std::fs::read(&svg_path).context(format!("failed to read {svg_path:?}"))?;
+        let mut tree = usvg::Tree::from_data(&data, &usvg::Options::default())
+            .context(format!("invalid SVG {svg_path:?}"))?;
+        tree.postprocess(usvg::PostProcessingSteps::default());
+        match form_content(&tree) {
+            Some(content) => {
+                return Ok(PdfTemplate::Form {
+                    content,
+                    width: tree.size.width(),
+                    height: tree.size.height(),
+                })
+            }
+            None => debug!("embedding {svg_path:?} as an image, since it isn't only paths"),
+        }
+    }
+
+    let image = template::load(dir, name, model::WIDTH_PIXELS, model::HEIGHT_PIXELS)?;
+    Ok(PdfTemplate::Image(template::on_white(&image)))
+}
+
+/// Resource name of the template at `index` in the document's templates.
+fn resource_name(index: usize) -> String {
+    format!("RmTemplate{index}")
+}
+
+/// Draws the template at `index` in the document's templates across the page.
+pub fn draw(layer: &PdfLayerReference, index: usize, template: &PdfTemplate) {
+    let page_width = model::WIDTH_PIXELS as f64 * PT_PER_MM;
+    let page_height = model::HEIGHT_PIXELS as f64 * PT_PER_MM;
+    // images fill the unit square, and SVGs have their origin at the top
+    let matrix = match template {
+        PdfTemplate::Form { width, height, .. } => [
+            page_width / *width as f64,
+            0.0,
+            0.0,
+            -page_height / *height as f64,
+            0.0,
+            page_height,
+        ],
+        PdfTemplate::Image(_) => [page_width, 0.0, 0.0, page_height, 0.0, 0.0],
+    };
+
+    layer.add_operation(Operation::new("q", vec![]));
+    layer.add_operation(Operation::new(
+        "cm",
+        matrix.iter().map(|&v| Object::Real(v)).collect(),
+    ));
+    layer.add_operation(Operation::new(
+        "Do",
+        vec![Object::Name(resource_name(index).into_bytes())],
+    ));
+    layer.add_operation(Operation::new("Q", vec![]));
+}
+
+fn xobject(template: &PdfTemplate) -> Stream {
+    let mut dict = Dictionary::new();
+    dict.set("Type", Object::Name(b"XObject".to_vec()));
+    let mut stream = match template {
+        PdfTemplate::Form {
+            content,
+            width,
+            height,
+        } => {
+            dict.set("Subtype", Object::Name(b"Form".to_vec()));
+            dict.set(
+                "BBox",
+                vec![0.into(), 0.into(), (*width).into(), (*height).into()] as Vec<Object>,
+            );
+            Stream::new(dict, content.clone())
+        }
+        PdfTemplate::Image(image) => {
+            dict.set("Subtype", Object::Name(b"Image".to_vec()));
+            dict.set("Width", image.width() as i64);
+            dict.set("Height", image.height() as i64);
+            dict.set("ColorSpace", Object::Name(b"DeviceRGB".to_vec()));
+            dict.set("BitsPerComponent", 8);
+            Stream::new(dict, image.as_raw().clone())
+        }
+    };
+    // compression is an optimization, the stream is valid either way
+    let _ = stream.compress();
+    stream
+}
+
+/// Adds `templates` to the serialized document `pdf`, each once, and to the
+/// resources of the pages that draw them.  `uses` holds the index of the
+/// template drawn by each page, in page order.
+pub fn add_templates(
+    pdf: &[u8],
+    templates: &[PdfTemplate],
+    uses: &[Option<usize>],
+) -> Result<Vec<u8>> {
+    let mut doc = lopdf::Document::load_mem(pdf).context("failed to reload rendered PDF")?;
+    let ids: Vec<_> = templates
+        .iter()
+        .map(|template| doc.add_object(xobject(template)))
+        .collect();
+
+    let pages: Vec<_> = doc.get_pages().into_values().collect();
+    for (page_id, index) in pages.into_iter().zip(uses) {
+        let Some(index) = *index else {
+            continue;
+        };
+        let page = doc.get_dictionary(page_id)?;
+        let resources = match page.get(b"Resources") {
+            Ok(Object::Reference(id)) => *id,
+            Ok(Object::Dictionary(resources)) => {
+                let resources = resources.clone();
+                let id = doc.add_object(resources);
+                doc.get_object_mut(page_id)?
+                    .as_dict_mut()?
+                    .set("Resources", id);
+                id
+            }
+            _ => {
+                let id = doc.add_object(Dictionary::new());
+                doc.get_object_mut(page_id)?
+                    .as_dict_mut()?
+                    .set("Resources", id);
+                id
+            }
+        };
+
+        let xobjects = match doc.get_dictionary(resources)?.get(b"XObject") {
+            Ok(Object::Reference(id)) => *id,
+            Ok(Object::Dictionary(xobjects)) => doc.add_object(xobjects.clone()),
+            _ => doc.add_object(Dictionary::new()),
+        };
+        doc.get_object_mut(resources)?
+            .as_dict_mut()?
+            .set("XObject", xobjects);
+        let id = ids
+            .get(index)
+            .ok_or(anyhow!("no template {index} in the document"))?;
+        doc.get_object_mut(xobjects)?
+            .as_dict_mut()?
+            .set(resource_name(index), *id);
+    }
+
+    let mut bytes = Vec::new();
+    doc.save_to(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Converts the paths of an SVG document to a PDF content stream in its
+/// coordinates, or returns `None` if it uses anything else.  Text isn't drawn,
+/// as when templates are rasterized.
+fn form_content(tree: &usvg::Tree) -> Option<Vec<u8>> {
+    let mut content = String::new();
+    let view_box =
+        usvg::utils::view_box_to_transform(tree.view_box.rect, tree.view_box.aspect, tree.size);
+    writeln!(content, "q {} cm", matrix(view_box)).ok()?;
+    group_content(&tree.root, &mut content)?;
+    content.push_str("Q\n");
+    Some(content.into_bytes())
+}
+
+fn group_content(group: &usvg::Group, out: &mut String) -> Option<()> {
+    if group.opacity.get() < 1.0
+        || group.clip_path.is_some()
+        || group.mask.is_some()
+        || !group.filters.is_empty()
+    {
+        return None;
+    }
+    for node in &group.children {
+        match node {
+            usvg::Node::Group(group) => group_content(group, out)?,
+            usvg::Node::Path(path) => path_content(path, out)?,
+            usvg::Node::Image(_) => return None,
+            usvg::Node::Text(_) => {}
+        }
+    }
+    Some(())
+}
+
+fn path_content(path: &usvg::Path, out: &mut String) -> Option<()> {
+    if path.visibility != usvg::Visibility::Visible {
+        return Some(());
+    }
+    let color = |paint: &usvg::Paint, opacity: usvg::Opacity| match paint {
+        usvg::Paint::Color(c) if opacity.get() >= 1.0 => Some(format!(
+            "{} {} {}",
+            c.red as f32 / 255.0,
+            c.green as f32 / 255.0,
+            c.blue as f32 / 255.0
+        )),
+        _ => None,
+    };
+
+    let mut ops = Vec::new();
+    if let Some(fill) = &path.fill {
+        let color = color(&fill.paint, fill.opacity)?;
+        let op = match fill.rule {
+            usvg::FillRule::NonZero => "f",
+            usvg::FillRule::EvenOdd => "f*",
+        };
+        ops.push(format!("{color} rg {op}"));
+    }
+    if let Some(stroke) = &path.stroke {
+        let color = color(&stroke.paint, stroke.opacity)?;
+        let cap = match stroke.linecap {
+            usvg::LineCap::Butt => 0,
+            usvg::LineCap::Round => 1,
+            usvg::LineCap::Square => 2,
+        };
+        let join = match stroke.linejoin {
+            usvg::LineJoin::Miter | usvg::LineJoin::MiterClip => 0,
+            usvg::LineJoin::Round => 1,
+            usvg::LineJoin::Bevel => 2,
+        };
+        let dash = match &stroke.dasharray {
+            Some(dashes) => {
+                let dashes: Vec<_> = dashes.iter().map(f32::to_string).collect();
+                format!("[{}] {} d", dashes.join(" "), stroke.dashoffset)
+            }
+            None => "[] 0 d".to_string(),
+        };
+        let op = format!(
+            "{color} RG {} w {cap} J {join} j {} M {dash} S",
+            stroke.width.get(),
+            stroke.miterlimit.get()
+        );
+        match path.paint_order {
+            usvg::PaintOrder::FillAndStroke => ops.push(op),
+            usvg::PaintOrder::StrokeAndFill => ops.insert(0, op),
+        }
+    }
+
+    let segments = path_segments(&path.data);
+    writeln!(out, "q {} cm", matrix(path.abs_transform)).ok()?;
+    // each paint operation ends the path, so it is repeated for each
+    for op in ops {
+        writeln!(out, "{segments}{op}").ok()?;
+    }
+    out.push_str("Q\n");
+    Some(())
+}
+
+fn path_segments(path: &resvg::tiny_skia::Path) -> String {
+    let mut out = String::new();
+    // the current point, and the start of the current subpath
+    let (mut current, mut start) = ((0.0, 0.0), (0.0, 0.0));
+    for segment in path.segments() {
+        match segment {
+            PathSegment::MoveTo(p) => {
+                let _ = writeln!(out, "{} {} m", p.x, p.y);
+                (current, start) = ((p.x, p.y), (p.x, p.y));
+            }
+            PathSegment::LineTo(p) => {
+                let _ = writeln!(out, "{} {} l", p.x, p.y);
+                current = (p.x, p.y);
+            }
+            // a quadratic curve is a cubic one with its control points two
+            // thirds of the way to the quadratic's
+            PathSegment::QuadTo(c, p) => {
+                let towards =
+                    |(x, y): (f32, f32)| (x + (c.x - x) * 2.0 / 3.0, y + (c.y - y) * 2.0 / 3.0);
+                let (c1, c2) = (towards(current), towards((p.x, p.y)));
+                let _ = writeln!(out, "{} {} {} {} {} {} c", c1.0, c1.1, c2.0, c2.1, p.x, p.y);
+                current = (p.x, p.y);
+            }
+            PathSegment::CubicTo(c1, c2, p) => {
+                let _ = writeln!(out, "{} {} {} {} {} {} c", c1.x, c1.y, c2.x, c2.y, p.x, p.y);
+                current = (p.x, p.y);
+            }
+            PathSegment::Close => {
+                out.push_str("h\n");
+                current = start;
+            }
+        }
+    }
+    out
+}
+
+fn matrix(t: Transform) -> String {
+    format!("{} {} {} {} {} {}", t.sx, t.ky, t.kx, t.sy, t.tx, t.ty)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use printpdf::{Mm, PdfDocument};
+
+    #[test]
+    fn test_add_templates() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("P Lines.svg"),
+            r##"<svg xmlns="http://www.w3.org/2000/svg" width="100" height="200">
+                <rect x="0" y="100" width="100" height="100" fill="#000000"/>
+                <path d="M 0 50 Q 50 0 100 50" stroke="#808080" fill="none"/>
+            </svg>"##,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("P Shaded.svg"),
+            r##"<svg xmlns="http://www.w3.org/2000/svg" width="100" height="200">
+                <rect width="100" height="200" fill="#000000" fill-opacity="0.5"/>
+            </svg>"##,
+        )
+        .unwrap();
+
+        let lines = load(dir.path(), "P Lines").unwrap();
+        let PdfTemplate::Form { content, .. } = &lines else {
+            panic!("P Lines wasn't converted to paths");
+        };
+        let content = String::from_utf8_lossy(content);
+        assert!(content.contains("0 0 0 rg f"), "{content}");
+        assert!(content.contains(" c\n"), "{content}");
+        // transparency is only kept by rasterizing
+        assert!(matches!(
+            load(dir.path(), "P Shaded").unwrap(),
+            PdfTemplate::Image(_)
+        ));
+
+        let (doc, page, layer) = PdfDocument::new("test", Mm(100.0), Mm(100.0), "Layer 1");
+        draw(&doc.get_page(page).get_layer(layer), 0, &lines);
+        for _ in 0..2 {
+            let (page, layer) = doc.add_page(Mm(100.0), Mm(100.0), "Layer 1");
+            draw(&doc.get_page(page).get_layer(layer), 0, &lines);
+        }
+        doc.add_page(Mm(100.0), Mm(100.0), "Layer 1");
+        let pdf = add_templates(
+            &doc.save_to_bytes().unwrap(),
+            &[lines],
+            &[Some(0), Some(0), Some(0), None],
+        )
+        .unwrap();
+
+        // every page draws the same XObject
+        let doc = lopdf::Document::load_mem(&pdf).unwrap();
+        let template_refs: Vec<_> = doc
+            .get_pages()
+            .into_values()
+            .map(|page_id| {
+                let page = doc.get_dictionary(page_id).unwrap();
+                let resources = match page.get(b"Resources").unwrap() {
+                    Object::Reference(id) => doc.get_dictionary(*id).unwrap(),
+                    resources => resources.as_dict().unwrap(),
+                };
+                resources
+                    .get(b"XObject")
+                    .and_then(Object::as_reference)
+                    .and_then(|id| doc.get_dictionary(id))
+                    .and_then(|xobjects| xobjects.get(b"RmTemplate0"))
+                    .and_then(Object::as_reference)
+                    .ok()
+            })
+            .collect();
+        assert_eq!(template_refs.len(), 4);
+        assert!(template_refs[0].is_some());
+        assert!(template_refs[..3].iter().all(|r| *r == template_refs[0]));
+        assert_eq!(template_refs[3], None);
+    }
+}
@@ -0,0 +1,591 @@
+//! Anti-aliased raster output, written as one image per page.
+//!
+//! Shapes are filled with a scanline rasterizer that samples several
+//! sub-scanlines per pixel row and computes the exact horizontal coverage of
+//! each span, and are composited onto a premultiplied RGBA canvas so that
+//! highlighter strokes can be multiplied with the ink below them.
+//!
+//! A canvas takes 16 bytes per pixel, which is hundreds of megabytes at high
+//! resolutions, so pages wait for others to finish before they take up more
+//! than [`CANVAS_MEMORY_LIMIT`] between them.
+use std::path::Path;
+use std::sync::{Condvar, Mutex};
+
+use anyhow::{Context, Result};
+use image::{DynamicImage, ImageFormat, RgbaImage};
+use rayon::prelude::*;
+use rusttype::{point, Scale};
+use tracing::{debug, trace, warn};
+
+use super::{
+    color, create_page_dir, drawable_lines, filtered_pages, is_highlight, page_file_name, stroke,
+    template, text, texture, warn_base_document_skipped, RenderOptions, HIGHLIGHTER_OPACITY,
+};
+use crate::model;
+use crate::model::content::{Line, Page};
+
+/// Resolution of the tablet's display, at which one page pixel is one image pixel.
+pub const DEVICE_DPI: f32 = 226.0;
+
+/// Highest resolution of raster output, beyond which a page takes gigabytes
+/// to render.
+pub const MAX_DPI: f32 = 600.0;
+
+/// Memory that the canvases of the pages being rendered may take up together,
+/// across notebooks.  A page is rendered on its own if it needs more.
+const CANVAS_MEMORY_LIMIT: usize = 2 << 30;
+
+static CANVAS_MEMORY: MemoryBudget = MemoryBudget::new(CANVAS_MEMORY_LIMIT);
+
+/// Memory shared by the threads rendering pages, which block until enough of it
+/// is released.
+struct MemoryBudget {
+    limit: usize,
+    used: Mutex<usize>,
+    released: Condvar,
+}
+
+/// Memory taken from a [`MemoryBudget`], released when dropped.
+struct Reservation<'a> {
+    budget: &'a MemoryBudget,
+    bytes: usize,
+}
+
+impl MemoryBudget {
+    const fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            used: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Waits until `bytes` fit within the limit, or nothing else is reserved.
+    fn reserve(&self, bytes: usize) -> Reservation<'_> {
+        let mut used = self.used.lock().expect("failed to lock memory budget");
+        while *used > 0 && *used + bytes > self.limit {
+            used = self
+                .released
+                .wait(used)
+                .expect("failed to lock memory budget");
+        }
+        *used += bytes;
+        Reservation {
+            budget: self,
+            bytes,
+        }
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        let mut used = self
+            .budget
+            .used
+            .lock()
+            .expect("failed to lock memory budget");
+        *used -= self.bytes;
+        self.budget.released.notify_all();
+    }
+}
+
+/// Sub-scanlines sampled per pixel row.
+const SUBSAMPLES: usize = 4;
+
+/// Options specific to raster output.
+#[derive(Debug)]
+pub struct RasterOptions {
+    pub format: ImageFormat,
+    pub dpi: f32,
+    /// Leave the page background transparent instead of white, for formats
+    /// with an alpha channel.
+    pub transparent: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Blend {
+    Normal,
+    Multiply,
+}
+
+/// A premultiplied RGBA canvas with components from 0 to 1.
+struct Canvas {
+    width: usize,
+    height: usize,
+    pixels: Vec<[f32; 4]>,
+    _memory: Reservation<'static>,
+}
+
+struct Edge {
+    y0: f32,
+    y1: f32,
+    x0: f32,
+    slope: f32,
+    winding: i32,
+}
+
+impl Canvas {
+    fn new(width: usize, height: usize, background: [f32; 4]) -> Self {
+        let memory = CANVAS_MEMORY.reserve(width * height * std::mem::size_of::<[f32; 4]>());
+        Self {
+            width,
+            height,
+            pixels: vec![background; width * height],
+            _memory: memory,
+        }
+    }
+
+    /// Composites `rgb` with the given alpha onto the pixel at `x`, `y`.
+    fn blend(&mut self, x: usize, y: usize, rgb: [f32; 3], alpha: f32, mode: Blend) {
+        let dst = &mut self.pixels[y * self.width + x];
+        let dst_alpha = dst[3];
+        for c in 0..3 {
+            let src = rgb[c] * alpha;
+            dst[c] = match mode {
+                Blend::Normal => src + dst[c] * (1.0 - alpha),
+                Blend::Multiply => src * (1.0 - dst_alpha) + dst[c] * (1.0 - alpha) + src * dst[c],
+            };
+        }
+        dst[3] = alpha + dst_alpha * (1.0 - alpha);
+    }
+
+    /// Composites an image of the same size as the canvas over it.
+    fn draw_image(&mut self, image: &RgbaImage) {
+        for (idx, pixel) in image.pixels().enumerate().take(self.pixels.len()) {
+            let [r, g, b, a] = pixel.0.map(|c| c as f32 / 255.0);
+            if a > 0.0 {
+                self.blend(
+                    idx % self.width,
+                    idx / self.width,
+                    [r, g, b],
+                    a,
+                    Blend::Normal,
+                );
+            }
+        }
+    }
+
+    /// Fills the polygon `points`, given in canvas pixels, using the nonzero rule.
+    fn fill_polygon(&mut self, points: &[(f32, f32)], rgb: [f32; 3], opacity: f32, mode: Blend) {
+        if points.len() < 3 {
+            return;
+        }
+
+        let mut edges: Vec<Edge> = points
+            .iter()
+            .zip(points.iter().cycle().skip(1))
+            .filter(|(a, b)| a.1 != b.1)
+            .map(|(&(ax, ay), &(bx, by))| {
+                let (winding, (x0, y0), (x1, y1)) = if ay < by {
+                    (1, (ax, ay), (bx, by))
+                } else {
+                    (-1, (bx, by), (ax, ay))
+                };
+                Edge {
+                    y0,
+                    y1,
+                    x0,
+                    slope: (x1 - x0) / (y1 - y0),
+                    winding,
+                }
+            })
+            .collect();
+        if edges.is_empty() {
+            return;
+        }
+        edges.sort_by(|a, b| a.y0.total_cmp(&b.y0));
+
+        let min_y = edges[0].y0.floor().max(0.0) as usize;
+        let max_y = edges.iter().map(|e| e.y1).fold(f32::MIN, f32::max);
+        let max_y = (max_y.ceil().max(0.0) as usize).min(self.height);
+
+        let mut coverage = vec![0.0f32; self.width + 1];
+        let mut active: Vec<&Edge> = Vec::new();
+        let mut next_edge = 0;
+        let mut crossings: Vec<(f32, i32)> = Vec::new();
+
+        for y in min_y..max_y {
+            let (mut min_x, mut max_x) = (self.width, 0);
+            for sub in 0..SUBSAMPLES {
+                let sy = y as f32 + (sub as f32 + 0.5) / SUBSAMPLES as f32;
+                while next_edge < edges.len() && edges[next_edge].y0 <= sy {
+                    active.push(&edges[next_edge]);
+                    next_edge += 1;
+                }
+                active.retain(|e| e.y1 > sy);
+
+                crossings.clear();
+                crossings.extend(
+                    active
+                        .iter()
+                        .filter(|e| e.y0 <= sy)
+                        .map(|e| (e.x0 + (sy - e.y0) * e.slope, e.winding)),
+                );
+                crossings.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+                let mut winding = 0;
+                for pair in crossings.windows(2) {
+                    winding += pair[0].1;
+                    if winding == 0 {
+                        continue;
+                    }
+
+                    let start = pair[0].0.clamp(0.0, self.width as f32);
+                    let end = pair[1].0.clamp(0.0, self.width as f32);
+                    if end <= start {
+                        continue;
+                    }
+                    let (first, last) = (start as usize, end as usize);
+                    let weight = 1.0 / SUBSAMPLES as f32;
+                    if first == last {
+                        coverage[first] += (end - start) * weight;
+                    } else {
+                        coverage[first] += (first as f32 + 1.0 - start) * weight;
+                        for c in &mut coverage[first + 1..last] {
+                            *c += weight;
+                        }
+                        coverage[last] += (end - last as f32) * weight;
+                    }
+                    min_x = min_x.min(first);
+                    max_x = max_x.max(last + 1);
+                }
+            }
+
+            if min_x >= max_x {
+                continue;
+            }
+            let end = max_x.min(self.width);
+            for (x, cover) in (min_x..end).zip(&mut coverage[min_x..end]) {
+                if *cover > 0.0 {
+                    self.blend(x, y, rgb, cover.min(1.0) * opacity, mode);
+                }
+                *cover = 0.0;
+            }
+            // spans ending on the right edge of the canvas cover nothing beyond it
+            coverage[end] = 0.0;
+        }
+    }
+
+    fn into_image(self) -> RgbaImage {
+        let channel = |c: f32| (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+        let mut image = RgbaImage::new(self.width as u32, self.height as u32);
+        for (pixel, [r, g, b, a]) in image.pixels_mut().zip(self.pixels) {
+            let unmultiply = |c: f32| if a > 0.0 { c / a } else { 0.0 };
+            pixel.0 = [
+                channel(unmultiply(r)),
+                channel(unmultiply(g)),
+                channel(unmultiply(b)),
+                channel(a),
+            ];
+        }
+        image
+    }
+}
+
+fn draw_line(canvas: &mut Canvas, scale: f32, line: &Line) {
+    let to_canvas = |points: Vec<(f32, f32)>| -> Vec<(f32, f32)> {
+        points
+            .into_iter()
+            .map(|(x, y)| (x * scale, y * scale))
+            .collect()
+    };
+    let outline = to_canvas(stroke::outline(line));
+
+    if is_highlight(line) {
+        let rgb = color::to_highlight_rgb(line.color);
+        canvas.fill_polygon(&outline, rgb, HIGHLIGHTER_OPACITY, Blend::Multiply);
+        return;
+    }
+
+    let rgb = color::to_rgb(line.color);
+    match texture::texture(line) {
+        Some(texture) => {
+            canvas.fill_polygon(&outline, rgb, texture.opacity, Blend::Normal);
+            for speck in &texture.specks {
+                let half = speck.size / 2.0;
+                let corners = [(-half, -half), (half, -half), (half, half), (-half, half)];
+                let speck = to_canvas(
+                    corners
+                        .map(|(dx, dy)| (speck.x + dx, speck.y + dy))
+                        .to_vec(),
+                );
+                canvas.fill_polygon(&speck, rgb, texture.opacity, Blend::Normal);
+            }
+        }
+        None => canvas.fill_polygon(&outline, rgb, 1.0, Blend::Normal),
+    }
+}
+
+fn draw_text(canvas: &mut Canvas, scale: f32, page: &Page, text_layout: &text::TextLayout) {
+    let Some(text) = &page.text else {
+        return;
+    };
+
+    let ink = color::to_rgb(model::content::Color::Black);
+    for line in text_layout.layout(text) {
+        let font = text_layout.font(line.weight);
+        let glyphs = font.layout(
+            &line.contents,
+            Scale::uniform(line.size * scale),
+            point(line.x * scale, line.y * scale),
+        );
+        for glyph in glyphs {
+            let Some(bounds) = glyph.pixel_bounding_box() else {
+                continue;
+            };
+            glyph.draw(|gx, gy, coverage| {
+                let x = bounds.min.x + gx as i32;
+                let y = bounds.min.y + gy as i32;
+                if x >= 0 && y >= 0 && (x as usize) < canvas.width && (y as usize) < canvas.height {
+                    canvas.blend(x as usize, y as usize, ink, coverage, Blend::Normal);
+                }
+            });
+        }
+    }
+}
+
+/// Size of the rendered pages in image pixels.
+fn image_size(raster: &RasterOptions) -> (usize, usize) {
+    let scale = raster.dpi / DEVICE_DPI;
+    let width = (model::WIDTH_PIXELS as f32 * scale).round() as usize;
+    let height = (model::HEIGHT_PIXELS as f32 * scale).round() as usize;
+    (width, height)
+}
+
+/// Renders a single page to an image at the given resolution, on top of its
+/// template if it has one.
+fn page_image(
+    page: Page,
+    template: Option<&RgbaImage>,
+    options: &RenderOptions,
+    raster: &RasterOptions,
+    text_layout: &text::TextLayout,
+) -> RgbaImage {
+    let scale = raster.dpi / DEVICE_DPI;
+    let (width, height) = image_size(raster);
+    let background = if raster.transparent && has_alpha(raster.format) {
+        [0.0; 4]
+    } else {
+        [1.0; 4]
+    };
+
+    let mut canvas = Canvas::new(width, height, background);
+    if let Some(template) = template {
+        canvas.draw_image(template);
+    }
+    // typed text is drawn under the strokes, as on the device
+    draw_text(&mut canvas, scale, &page, text_layout);
+    for layer in page.layers {
+        if !layer.visible {
+            debug!("skipping hidden layer {:?}", layer.name);
+            continue;
+        }
+
+        for line in drawable_lines(&page.version, layer.lines, options) {
+            draw_line(&mut canvas, scale, &line);
+        }
+    }
+
+    canvas.into_image()
+}
+
+/// Whether images of `format` can be transparent.
+fn has_alpha(format: ImageFormat) -> bool {
+    !matches!(format, ImageFormat::Jpeg)
+}
+
+/// Renders each page of `notebook` accepted by `page_filter` to its own image
+/// file in `output_dir`, named by its 1-based page number.
+pub fn render_raster<P: AsRef<Path>>(
+    notebook: model::content::Notebook,
+    page_filter: Box<dyn Fn(usize) -> bool>,
+    options: &RenderOptions,
+    raster: &RasterOptions,
+    output_dir: P,
+) -> Result<()> {
+    let output_dir = output_dir.as_ref();
+    create_page_dir(output_dir)?;
+
+    let extension = raster.format.extensions_str()[0];
+    warn_base_document_skipped(&notebook, &extension.to_uppercase());
+    let has_alpha = has_alpha(raster.format);
+    if raster.transparent && !has_alpha {
+        warn!("{extension} images have no alpha channel, using a white background");
+    }
+
+    let text_layout = text::TextLayout::default();
+    let (width, height) = image_size(raster);
+    let templates =
+        template::Templates::new(options.templates_dir.as_deref(), width as _, height as _);
+    filtered_pages(notebook.pages, page_filter)
+        .into_par_iter()
+        .try_for_each(|(idx, page)| {
+            let output_file = output_dir.join(page_file_name(idx, extension));
+            trace!("writing page {} to {output_file:?}", page.id);
+            let template = page
+                .template
+                .as_deref()
+                .and_then(|name| templates.get(name));
+            let image = page_image(page, template.as_deref(), options, raster, &text_layout);
+            let image = DynamicImage::ImageRgba8(image);
+            let image = if has_alpha {
+                image
+            } else {
+                DynamicImage::ImageRgb8(image.to_rgb8())
+            };
+            image
+                .save_with_format(&output_file, raster.format)
+                .context(format!("failed to write {output_file:?}"))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::content::{BrushType, Color, Layer, Point, Version};
+    use rstest::rstest;
+
+    fn line(brush_type: BrushType, color: Color, width: f32) -> Line {
+        Line {
+            brush_type,
+            color,
+            brush_size: 2.0,
+            points: (0..=10)
+                .map(|i| Point {
+                    x: 100.0 + i as f32 * 20.0,
+                    y: 100.0,
+                    speed: 0.0,
+                    direction: 0.0,
+                    width,
+                    pressure: 0.5,
+                })
+                .collect(),
+            deleted: false,
+        }
+    }
+
+    #[rstest]
+    #[case::square(vec![(1.0, 1.0), (3.0, 1.0), (3.0, 3.0), (1.0, 3.0)], 4.0)]
+    #[case::half_pixels(vec![(0.5, 0.5), (2.5, 0.5), (2.5, 1.5), (0.5, 1.5)], 2.0)]
+    #[case::triangle(vec![(0.0, 0.0), (4.0, 0.0), (0.0, 4.0)], 8.0)]
+    fn test_fill_polygon_coverage(#[case] polygon: Vec<(f32, f32)>, #[case] area: f32) {
+        let mut canvas = Canvas::new(8, 8, [0.0; 4]);
+        canvas.fill_polygon(&polygon, [0.0, 0.0, 0.0], 1.0, Blend::Normal);
+        let covered: f32 = canvas.pixels.iter().map(|p| p[3]).sum();
+        assert!((covered - area).abs() < 0.1, "{covered}");
+    }
+
+    #[test]
+    fn test_page_image() {
+        let page = Page {
+            id: "page".to_string(),
+            version: Version::V6,
+            layers: vec![Layer {
+                name: None,
+                visible: true,
+                lines: vec![
+                    line(BrushType::Fineliner, Color::Red, 4.0),
+                    line(BrushType::Highlighter, Color::Yellow, 20.0),
+                ],
+            }],
+            text: None,
+            source_page: None,
+            template: None,
+        };
+        let raster = RasterOptions {
+            format: ImageFormat::Png,
+            dpi: DEVICE_DPI / 2.0,
+            transparent: false,
+        };
+
+        let mut template = RgbaImage::new(702, 936);
+        template.put_pixel(20, 20, image::Rgba([128, 128, 128, 255]));
+
+        let image = page_image(
+            page,
+            Some(&template),
+            &RenderOptions::default(),
+            &raster,
+            &text::TextLayout::default(),
+        );
+        assert_eq!(image.dimensions(), (702, 936));
+        // red ink multiplied with the yellow highlighter stays red
+        assert_eq!(image.get_pixel(100, 50).0, [255, 0, 0, 255]);
+        // highlighter over the white page
+        let highlight = image.get_pixel(100, 47).0;
+        assert!(highlight[2] < 128 && highlight[0] > 200, "{highlight:?}");
+        assert_eq!(image.get_pixel(10, 10).0, [255, 255, 255, 255]);
+        assert_eq!(image.get_pixel(20, 20).0, [128, 128, 128, 255]);
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        static BUDGET: MemoryBudget = MemoryBudget::new(100);
+
+        // a reservation beyond the limit is only granted while nothing else is
+        let large = BUDGET.reserve(150);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let waiting = std::thread::spawn(move || {
+            let _small = BUDGET.reserve(60);
+            sender.send(()).unwrap();
+        });
+        assert!(receiver
+            .recv_timeout(std::time::Duration::from_millis(100))
+            .is_err());
+        drop(large);
+        receiver.recv().unwrap();
+        waiting.join().unwrap();
+
+        let _first = BUDGET.reserve(60);
+        let _second = BUDGET.reserve(40);
+        assert_eq!(*BUDGET.used.lock().unwrap(), 100);
+    }
+
+    #[test]
+    fn test_transparent_jpeg() {
+        let dir = tempfile::tempdir().unwrap();
+        // pages from an earlier conversion with more pages are removed
+        std::fs::write(dir.path().join("page-002.jpg"), "stale").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "mine").unwrap();
+        let notebook = model::content::Notebook {
+            id: "notebook".to_string(),
+            pages: vec![Page {
+                id: "page".to_string(),
+                version: Version::V6,
+                layers: vec![Layer {
+                    name: None,
+                    visible: true,
+                    lines: vec![line(BrushType::Fineliner, Color::Black, 20.0)],
+                }],
+                text: None,
+                source_page: None,
+                template: None,
+            }],
+            base_document: None,
+        };
+        let raster = RasterOptions {
+            format: ImageFormat::Jpeg,
+            dpi: DEVICE_DPI / 4.0,
+            transparent: true,
+        };
+
+        render_raster(
+            notebook,
+            Box::new(|_| true),
+            &RenderOptions::default(),
+            &raster,
+            dir.path(),
+        )
+        .unwrap();
+        assert!(!dir.path().join("page-002.jpg").exists());
+        assert!(dir.path().join("notes.txt").exists());
+        let image = image::open(dir.path().join("page-001.jpg"))
+            .unwrap()
+            .to_rgb8();
+        // the page is white, since JPEG has no alpha channel to be transparent in
+        let background = image.get_pixel(5, 5).0;
+        assert!(background.iter().all(|&c| c > 250), "{background:?}");
+        let ink = image.get_pixel(50, 25).0;
+        assert!(ink.iter().all(|&c| c < 128), "{ink:?}");
+    }
+}
@@ -0,0 +1,308 @@
+//! Geometry of variable-width strokes, independent of the output format.
+//!
+//! Every brush derives a width for each point of a stroke from the pressure,
+//! speed and direction recorded by the tablet.  The widths and the centre line
+//! are smoothed, and the stroke is turned into a single closed outline with
+//! round caps that backends fill in one go.
+use std::f32::consts::{FRAC_PI_4, PI, TAU};
+
+use crate::model::content::{BrushType, Line, Point};
+
+/// Strokes are never drawn thinner than this, in page pixels.
+const MIN_WIDTH: f32 = 0.5;
+
+/// Stylus speed above which the speed-sensitive brushes stop getting thinner.
+const MAX_SPEED: f32 = 50.0;
+
+/// Number of segments used to approximate a round cap.
+const CAP_SEGMENTS: usize = 8;
+
+/// Rounds of corner cutting applied to the centre line.
+const SMOOTHING_ROUNDS: usize = 2;
+
+/// A point of the centre line together with the stroke width there.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sample {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+}
+
+impl Sample {
+    fn lerp(self, other: Sample, t: f32) -> Sample {
+        Sample {
+            x: self.x + (other.x - self.x) * t,
+            y: self.y + (other.y - self.y) * t,
+            width: self.width + (other.width - self.width) * t,
+        }
+    }
+}
+
+/// Scales a width down the faster the stylus moved, by at most `amount`.
+fn speed_factor(point: &Point, amount: f32) -> f32 {
+    1.0 - amount * (point.speed / MAX_SPEED).clamp(0.0, 1.0)
+}
+
+/// Width of a brush at `point`, where `heading` is the angle of the stroke there.
+fn brush_width(brush: BrushType, point: &Point, heading: f32) -> f32 {
+    let width = point.width;
+    let pressure = point.pressure.clamp(0.0, 1.0);
+
+    let width = match brush {
+        BrushType::Ballpoint => width * (0.5 + 0.7 * pressure) * speed_factor(point, 0.3),
+        BrushType::Marker => width * speed_factor(point, 0.1),
+        BrushType::Pencil => width * (0.4 + 0.8 * pressure) * speed_factor(point, 0.3),
+        BrushType::Paintbrush => width * (0.3 + 1.2 * pressure) * speed_factor(point, 0.4),
+        // the nib is held across the stylus direction, so strokes along the nib
+        // are thin and strokes across it are broad
+        BrushType::Calligraphy => {
+            let nib = (heading - point.direction - FRAC_PI_4).sin().abs();
+            width * (0.25 + 0.75 * nib) * (0.7 + 0.5 * pressure)
+        }
+        BrushType::Fineliner
+        | BrushType::MechanicalPencil
+        | BrushType::Highlighter
+        | BrushType::Eraser
+        | BrushType::EraserArea => width,
+    };
+
+    width.max(MIN_WIDTH)
+}
+
+/// Computes the width of every point of `line` using its brush model.
+fn samples(line: &Line) -> Vec<Sample> {
+    let points = &line.points;
+    let mut samples: Vec<Sample> = points
+        .iter()
+        .enumerate()
+        .map(|(i, point)| {
+            let prev = &points[i.saturating_sub(1)];
+            let next = &points[(i + 1).min(points.len() - 1)];
+            let heading = (next.y - prev.y).atan2(next.x - prev.x);
+            Sample {
+                x: point.x,
+                y: point.y,
+                width: brush_width(line.brush_type, point, heading),
+            }
+        })
+        .collect();
+
+    // the sampled widths are noisy, so average each with its neighbours
+    let widths: Vec<f32> = samples.iter().map(|s| s.width).collect();
+    for (i, sample) in samples.iter_mut().enumerate() {
+        let window = &widths[i.saturating_sub(1)..(i + 2).min(widths.len())];
+        sample.width = window.iter().sum::<f32>() / window.len() as f32;
+    }
+
+    samples
+}
+
+/// Drops samples that coincide with the previous one, as they have no direction.
+fn dedup(samples: Vec<Sample>) -> Vec<Sample> {
+    let mut result: Vec<Sample> = Vec::with_capacity(samples.len());
+    for sample in samples {
+        match result.last_mut() {
+            Some(last) if (sample.x - last.x).hypot(sample.y - last.y) < 1e-3 => {
+                last.width = last.width.max(sample.width);
+            }
+            _ => result.push(sample),
+        }
+    }
+    result
+}
+
+/// Smooths the centre line by Chaikin corner cutting, keeping the end points.
+fn smooth(mut samples: Vec<Sample>) -> Vec<Sample> {
+    for _ in 0..SMOOTHING_ROUNDS {
+        if samples.len() < 3 {
+            break;
+        }
+
+        let mut smoothed = Vec::with_capacity(samples.len() * 2);
+        smoothed.push(samples[0]);
+        for segment in samples.windows(2) {
+            smoothed.push(segment[0].lerp(segment[1], 0.25));
+            smoothed.push(segment[0].lerp(segment[1], 0.75));
+        }
+        smoothed.push(samples[samples.len() - 1]);
+        samples = smoothed;
+    }
+    samples
+}
+
+/// Appends the interior points of an arc of `sweep` radians starting at `start`.
+fn arc(outline: &mut Vec<(f32, f32)>, centre: Sample, start: f32, sweep: f32, segments: usize) {
+    let radius = centre.width / 2.0;
+    for k in 1..segments {
+        let angle = start + sweep * k as f32 / segments as f32;
+        outline.push((
+            centre.x + radius * angle.cos(),
+            centre.y + radius * angle.sin(),
+        ));
+    }
+}
+
+/// Computes the smoothed centre line of `line`, with the brush width at each sample.
+pub fn centre_line(line: &Line) -> Vec<Sample> {
+    smooth(dedup(samples(line)))
+}
+
+/// Computes unit normals to the left of the direction of travel at each sample.
+pub fn normals(samples: &[Sample]) -> Vec<(f32, f32)> {
+    let mut normals = Vec::with_capacity(samples.len());
+    let mut normal = (0.0, 1.0);
+    for i in 0..samples.len() {
+        let prev = samples[i.saturating_sub(1)];
+        let next = samples[(i + 1).min(samples.len() - 1)];
+        let (dx, dy) = (next.x - prev.x, next.y - prev.y);
+        let length = dx.hypot(dy);
+        if length > f32::EPSILON {
+            normal = (-dy / length, dx / length);
+        }
+        normals.push(normal);
+    }
+    normals
+}
+
+/// Computes the closed outline of `line` in page pixels.  The outline is empty
+/// for a line without points and a circle for a line with a single position.
+pub fn outline(line: &Line) -> Vec<(f32, f32)> {
+    let samples = centre_line(line);
+
+    let mut outline = Vec::new();
+    match samples.as_slice() {
+        [] => {}
+        [dot] => {
+            outline.push((dot.x + dot.width / 2.0, dot.y));
+            arc(&mut outline, *dot, 0.0, TAU, 2 * CAP_SEGMENTS);
+        }
+        _ => {
+            let normals = normals(&samples);
+            let offset = |sample: &Sample, normal: &(f32, f32), side: f32| {
+                let radius = side * sample.width / 2.0;
+                (sample.x + normal.0 * radius, sample.y + normal.1 * radius)
+            };
+
+            outline.extend(
+                samples
+                    .iter()
+                    .zip(&normals)
+                    .map(|(sample, normal)| offset(sample, normal, 1.0)),
+            );
+
+            let last = samples.len() - 1;
+            let end_angle = normals[last].1.atan2(normals[last].0);
+            arc(&mut outline, samples[last], end_angle, -PI, CAP_SEGMENTS);
+
+            outline.extend(
+                samples
+                    .iter()
+                    .zip(&normals)
+                    .rev()
+                    .map(|(sample, normal)| offset(sample, normal, -1.0)),
+            );
+
+            let start_angle = normals[0].1.atan2(normals[0].0);
+            arc(
+                &mut outline,
+                samples[0],
+                start_angle + PI,
+                -PI,
+                CAP_SEGMENTS,
+            );
+        }
+    }
+
+    outline
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::content::Color;
+    use rstest::rstest;
+
+    fn point(x: f32, y: f32, width: f32, pressure: f32) -> Point {
+        Point {
+            x,
+            y,
+            speed: 0.0,
+            direction: 0.0,
+            width,
+            pressure,
+        }
+    }
+
+    fn line(brush_type: BrushType, points: Vec<Point>) -> Line {
+        Line {
+            brush_type,
+            color: Color::Black,
+            brush_size: 2.0,
+            points,
+            deleted: false,
+        }
+    }
+
+    fn bounds(outline: &[(f32, f32)]) -> (f32, f32, f32, f32) {
+        outline.iter().fold(
+            (f32::MAX, f32::MAX, f32::MIN, f32::MIN),
+            |(x0, y0, x1, y1), &(x, y)| (x0.min(x), y0.min(y), x1.max(x), y1.max(y)),
+        )
+    }
+
+    #[test]
+    fn test_outline_straight_line() {
+        let points = (0..=10).map(|i| point(i as f32 * 10.0, 50.0, 4.0, 0.5));
+        let outline = outline(&line(BrushType::Fineliner, points.collect()));
+
+        let (x0, y0, x1, y1) = bounds(&outline);
+        assert!((x0 - -2.0).abs() < 0.01, "{x0}");
+        assert!((x1 - 102.0).abs() < 0.01, "{x1}");
+        assert!((y0 - 48.0).abs() < 0.01, "{y0}");
+        assert!((y1 - 52.0).abs() < 0.01, "{y1}");
+    }
+
+    #[rstest]
+    #[case::no_points(vec![], 0.0)]
+    #[case::single_point(vec![point(10.0, 10.0, 6.0, 0.5)], 6.0)]
+    #[case::repeated_point(vec![point(10.0, 10.0, 6.0, 0.5), point(10.0, 10.0, 6.0, 0.5)], 6.0)]
+    fn test_outline_dot(#[case] points: Vec<Point>, #[case] diameter: f32) {
+        let outline = outline(&line(BrushType::Fineliner, points));
+        if diameter == 0.0 {
+            assert!(outline.is_empty());
+            return;
+        }
+
+        let (x0, _, x1, _) = bounds(&outline);
+        assert!((x1 - x0 - diameter).abs() < 0.1, "{}", x1 - x0);
+    }
+
+    #[rstest]
+    #[case::ballpoint(BrushType::Ballpoint, true)]
+    #[case::pencil(BrushType::Pencil, true)]
+    #[case::paintbrush(BrushType::Paintbrush, true)]
+    #[case::fineliner(BrushType::Fineliner, false)]
+    #[case::mechanical_pencil(BrushType::MechanicalPencil, false)]
+    fn test_pressure_sensitivity(#[case] brush: BrushType, #[case] sensitive: bool) {
+        let light = brush_width(brush, &point(0.0, 0.0, 4.0, 0.1), 0.0);
+        let heavy = brush_width(brush, &point(0.0, 0.0, 4.0, 0.9), 0.0);
+        assert_eq!(heavy > light, sensitive, "{light} vs {heavy}");
+    }
+
+    #[test]
+    fn test_v6_fixture_width() {
+        // a fineliner stroke recorded 6 pixels wide, in quarter units
+        let fixture = include_bytes!("../../tests/fixtures/v6_fineliner.rm");
+        let (_, (_, layers, _)) = crate::parser::parse(fixture).unwrap();
+        let (_, y0, _, y1) = bounds(&outline(&layers[0].lines[0]));
+        assert!((y1 - y0 - 6.0).abs() < 0.01, "{}", y1 - y0);
+    }
+
+    #[test]
+    fn test_calligraphy_direction() {
+        let p = point(0.0, 0.0, 8.0, 0.5);
+        let along_nib = brush_width(BrushType::Calligraphy, &p, FRAC_PI_4);
+        let across_nib = brush_width(BrushType::Calligraphy, &p, -FRAC_PI_4);
+        assert!(across_nib > along_nib * 2.0, "{along_nib} vs {across_nib}");
+    }
+}
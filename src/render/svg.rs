@@ -0,0 +1,371 @@
+//! Vector SVG output, written as one document per page.
+use std::fmt::Write as _;
+use std::io::Cursor;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use base64::Engine;
+use image::{ImageOutputFormat, RgbaImage};
+use rayon::prelude::*;
+use resvg::usvg::{self, TreeParsing};
+use tracing::{debug, trace};
+
+use super::{
+    color, create_page_dir, drawable_lines, filtered_pages, is_highlight, page_file_name, stroke,
+    template, text, texture, warn_base_document_skipped, RenderOptions, HIGHLIGHTER_OPACITY,
+};
+use crate::model;
+use crate::model::content::{Line, Page};
+
+/// Font families for typed text, falling back to whatever sans-serif font the
+/// viewer has if the tablet's font is not installed.
+const FONT_FAMILY: &str = "'Amazon Ember', sans-serif";
+
+fn hex_color([r, g, b]: [f32; 3]) -> String {
+    let channel = |c: f32| (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+    format!("#{:02x}{:02x}{:02x}", channel(r), channel(g), channel(b))
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Appends a closed subpath through `points` to the path data `d`.
+fn push_polygon(d: &mut String, points: impl IntoIterator<Item = (f32, f32)>) {
+    for (i, (x, y)) in points.into_iter().enumerate() {
+        let command = if i == 0 { 'M' } else { 'L' };
+        write!(d, "{command}{x:.2},{y:.2}").unwrap();
+    }
+    d.push('Z');
+}
+
+fn write_line(svg: &mut String, line: &Line) {
+    let mut outline = String::new();
+    push_polygon(&mut outline, stroke::outline(line));
+    if outline.len() <= 1 {
+        return;
+    }
+
+    if is_highlight(line) {
+        let fill = hex_color(color::to_highlight_rgb(line.color));
+        writeln!(
+            svg,
+            r#"    <path d="{outline}" fill="{fill}" fill-opacity="{HIGHLIGHTER_OPACITY}" style="mix-blend-mode:multiply"/>"#
+        )
+        .unwrap();
+        return;
+    }
+
+    let fill = hex_color(color::to_rgb(line.color));
+    match texture::texture(line) {
+        Some(texture) => {
+            let mut specks = String::new();
+            for speck in &texture.specks {
+                let half = speck.size / 2.0;
+                let corners = [(-half, -half), (half, -half), (half, half), (-half, half)];
+                push_polygon(
+                    &mut specks,
+                    corners.map(|(dx, dy)| (speck.x + dx, speck.y + dy)),
+                );
+            }
+
+            writeln!(
+                svg,
+                r#"    <g fill="{fill}" opacity="{:.2}">"#,
+                texture.opacity
+            )
+            .unwrap();
+            writeln!(svg, r#"      <path d="{outline}"/>"#).unwrap();
+            if !specks.is_empty() {
+                writeln!(svg, r#"      <path d="{specks}"/>"#).unwrap();
+            }
+            writeln!(svg, "    </g>").unwrap();
+        }
+        None => writeln!(svg, r#"    <path d="{outline}" fill="{fill}"/>"#).unwrap(),
+    }
+}
+
+/// A template as it is embedded in each page.
+enum SvgTemplate {
+    /// The markup of an SVG template, from its root `<svg>` element, and its size.
+    Vector {
+        markup: String,
+        width: f32,
+        height: f32,
+    },
+    /// A PNG template, scaled to the page.
+    Image(RgbaImage),
+}
+
+/// Loads the template named `name` from `dir`, keeping SVG templates as they are.
+fn load_template(dir: &Path, name: &str, width: u32, height: u32) -> Result<SvgTemplate> {
+    let svg_path = dir.join(format!("{name}.svg"));
+    if svg_path.exists() {
+        debug!("loading template {svg_path:?}");
+        let data =
+            std::fs::read_to_string(&svg_path).context(format!("failed to read {svg_path:?}"))?;
+        let tree = usvg::Tree::from_str(&data, &usvg::Options::default())
+            .context(format!("invalid SVG {svg_path:?}"))?;
+        // drop the XML declaration and doctype, which can't be nested
+        let start = data
+            .find("<svg")
+            .ok_or(anyhow!("no <svg> element in {svg_path:?}"))?;
+        return Ok(SvgTemplate::Vector {
+            markup: data[start..].trim_end().to_string(),
+            width: tree.size.width(),
+            height: tree.size.height(),
+        });
+    }
+
+    Ok(SvgTemplate::Image(template::load(
+        dir, name, width, height,
+    )?))
+}
+
+/// Embeds a template covering the page, as a nested SVG document or a PNG image.
+fn write_template(svg: &mut String, template: &SvgTemplate) -> Result<()> {
+    let width = model::WIDTH_PIXELS;
+    let height = model::HEIGHT_PIXELS;
+    match template {
+        SvgTemplate::Vector {
+            markup,
+            width: template_width,
+            height: template_height,
+        } => {
+            // the template's own viewport is stretched to the page, as it is
+            // when it is rasterized
+            writeln!(
+                svg,
+                r#"  <svg id="template" width="{width}" height="{height}" viewBox="0 0 {template_width} {template_height}" preserveAspectRatio="none">"#
+            )?;
+            writeln!(svg, "{markup}")?;
+            writeln!(svg, "  </svg>")?;
+        }
+        SvgTemplate::Image(image) => {
+            let mut png = Cursor::new(Vec::new());
+            image.write_to(&mut png, ImageOutputFormat::Png)?;
+            writeln!(
+                svg,
+                r#"  <image id="template" width="{width}" height="{height}" href="data:image/png;base64,{}"/>"#,
+                base64::engine::general_purpose::STANDARD.encode(png.into_inner())
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Renders a single page to an SVG document, on top of its template if it has one.
+fn page_svg(
+    page: Page,
+    template: Option<&SvgTemplate>,
+    options: &RenderOptions,
+    text_layout: &text::TextLayout,
+) -> Result<String> {
+    let width = model::WIDTH_PIXELS;
+    let height = model::HEIGHT_PIXELS;
+
+    let mut svg = String::new();
+    writeln!(svg, r#"<?xml version="1.0" encoding="UTF-8"?>"#).unwrap();
+    writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" xmlns:inkscape="http://www.inkscape.org/namespaces/inkscape" width="{width}" height="{height}" viewBox="0 0 {width} {height}">"#
+    )
+    .unwrap();
+
+    if let Some(template) = template {
+        write_template(&mut svg, template)?;
+    }
+
+    // typed text is drawn under the strokes, as on the device
+    if let Some(text) = &page.text {
+        writeln!(svg, r#"  <g id="text" font-family="{FONT_FAMILY}">"#).unwrap();
+        for line in text_layout.layout(text) {
+            let weight = match line.weight {
+                text::FontWeight::Regular => "normal",
+                text::FontWeight::Bold => "bold",
+            };
+            writeln!(
+                svg,
+                r#"    <text x="{:.2}" y="{:.2}" font-size="{}" font-weight="{weight}" xml:space="preserve">{}</text>"#,
+                line.x,
+                line.y,
+                line.size,
+                escape(&line.contents)
+            )
+            .unwrap();
+        }
+        writeln!(svg, "  </g>").unwrap();
+    }
+
+    for (layer_idx, layer) in page.layers.into_iter().enumerate() {
+        let layer_name = layer
+            .name
+            .unwrap_or_else(|| format!("Layer {}", layer_idx + 1));
+        if !layer.visible {
+            debug!("skipping hidden layer {layer_name:?}");
+            continue;
+        }
+
+        // layers are marked up the way Inkscape expects, so that they can be
+        // toggled when the page is edited
+        writeln!(
+            svg,
+            r#"  <g id="layer{}" inkscape:groupmode="layer" inkscape:label="{}">"#,
+            layer_idx + 1,
+            escape(&layer_name)
+        )
+        .unwrap();
+        for line in drawable_lines(&page.version, layer.lines, options) {
+            write_line(&mut svg, &line);
+        }
+        writeln!(svg, "  </g>").unwrap();
+    }
+
+    writeln!(svg, "</svg>").unwrap();
+    Ok(svg)
+}
+
+/// Renders each page of `notebook` accepted by `page_filter` to its own SVG
+/// file in `output_dir`, named by its 1-based page number.
+pub fn render_svg<P: AsRef<Path>>(
+    notebook: model::content::Notebook,
+    page_filter: Box<dyn Fn(usize) -> bool>,
+    options: &RenderOptions,
+    output_dir: P,
+) -> Result<()> {
+    let output_dir = output_dir.as_ref();
+    create_page_dir(output_dir)?;
+    warn_base_document_skipped(&notebook, "SVG");
+
+    let text_layout = text::TextLayout::default();
+    let templates = template::Templates::with_loader(
+        options.templates_dir.as_deref(),
+        model::WIDTH_PIXELS,
+        model::HEIGHT_PIXELS,
+        load_template,
+    );
+    filtered_pages(notebook.pages, page_filter)
+        .into_par_iter()
+        .try_for_each(|(idx, page)| {
+            let output_file = output_dir.join(page_file_name(idx, "svg"));
+            trace!("writing page {} to {output_file:?}", page.id);
+            let template = page
+                .template
+                .as_deref()
+                .and_then(|name| templates.get(name));
+            let svg = page_svg(page, template.as_deref(), options, &text_layout)?;
+            std::fs::write(&output_file, svg).context(format!("failed to write {output_file:?}"))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::content::{BrushType, Color, Layer, Point, Version};
+
+    fn line(brush_type: BrushType, color: Color) -> Line {
+        Line {
+            brush_type,
+            color,
+            brush_size: 2.0,
+            points: (0..5)
+                .map(|i| Point {
+                    x: 100.0 + i as f32 * 10.0,
+                    y: 100.0,
+                    speed: 0.0,
+                    direction: 0.0,
+                    width: 4.0,
+                    pressure: 0.5,
+                })
+                .collect(),
+            deleted: false,
+        }
+    }
+
+    #[test]
+    fn test_page_svg() {
+        let page = Page {
+            id: "page".to_string(),
+            version: Version::V6,
+            layers: vec![
+                Layer {
+                    name: Some("Ink & notes".to_string()),
+                    visible: true,
+                    lines: vec![
+                        line(BrushType::Fineliner, Color::Red),
+                        line(BrushType::Highlighter, Color::Yellow),
+                        line(BrushType::Pencil, Color::Black),
+                    ],
+                },
+                Layer {
+                    name: Some("Hidden".to_string()),
+                    visible: false,
+                    lines: vec![line(BrushType::Fineliner, Color::Black)],
+                },
+            ],
+            text: None,
+            source_page: None,
+            template: None,
+        };
+
+        let template = SvgTemplate::Image(RgbaImage::new(4, 4));
+        let svg = page_svg(
+            page,
+            Some(&template),
+            &RenderOptions::default(),
+            &text::TextLayout::default(),
+        )
+        .unwrap();
+        assert!(svg.contains(
+            r#"<image id="template" width="1404" height="1872" href="data:image/png;base64,"#
+        ));
+        assert!(svg.contains(r#"inkscape:label="Ink &amp; notes""#));
+        assert!(!svg.contains("Hidden"));
+        assert!(svg.contains(r##"fill="#ff0000""##));
+        assert!(svg.contains("mix-blend-mode:multiply"));
+        assert!(svg.contains(r##"<g fill="#000000" opacity="##));
+        assert_eq!(svg.matches("<path").count(), 4);
+    }
+
+    #[test]
+    fn test_vector_template() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("P Lines.svg"),
+            r##"<?xml version="1.0" encoding="UTF-8"?>
+<svg xmlns="http://www.w3.org/2000/svg" width="702" height="936">
+  <line x1="0" y1="100" x2="702" y2="100" stroke="#000000"/>
+</svg>
+"##,
+        )
+        .unwrap();
+
+        let template = load_template(dir.path(), "P Lines", 1404, 1872).unwrap();
+        let page = Page {
+            id: "page".to_string(),
+            version: Version::V6,
+            layers: Vec::new(),
+            text: None,
+            source_page: None,
+            template: Some("P Lines".to_string()),
+        };
+        let svg = page_svg(
+            page,
+            Some(&template),
+            &RenderOptions::default(),
+            &text::TextLayout::default(),
+        )
+        .unwrap();
+        assert!(svg.contains(
+            r#"<svg id="template" width="1404" height="1872" viewBox="0 0 702 936" preserveAspectRatio="none">"#
+        ));
+        assert!(svg.contains(r##"<line x1="0" y1="100" x2="702" y2="100" stroke="#000000"/>"##));
+        assert!(!svg.contains("base64"));
+        assert_eq!(svg.matches("<?xml").count(), 1);
+        let tree = usvg::Tree::from_str(&svg, &usvg::Options::default()).unwrap();
+        assert_eq!(tree.size.width(), 1404.0);
+    }
+}
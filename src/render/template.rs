@@ -0,0 +1,153 @@
+//! Page templates, such as lines, grids and planners, drawn under the strokes.
+//!
+//! Templates are looked up by name in a directory laid out like the tablet's
+//! `/usr/share/remarkable/templates`, preferring `<name>.svg`, which scales
+//! to any resolution, over `<name>.png`.
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Context, Result};
+use image::{imageops::FilterType, RgbaImage};
+use resvg::{tiny_skia, usvg};
+use tracing::{debug, warn};
+use usvg::{TreeParsing, TreePostProc};
+
+/// Loads templates from a directory by name and size.
+pub type Loader<T> = fn(&Path, &str, u32, u32) -> Result<T>;
+
+/// Loads templates at a fixed size, caching them for the pages that share one.
+/// Pages rendered in parallel can share a loader.
+pub struct Templates<T = RgbaImage> {
+    dir: Option<PathBuf>,
+    width: u32,
+    height: u32,
+    load: Loader<T>,
+    cache: Mutex<HashMap<String, Option<Arc<T>>>>,
+}
+
+impl Templates {
+    /// Creates a loader for templates in `dir`, if any, scaled to `width` by `height` pixels.
+    pub fn new(dir: Option<&Path>, width: u32, height: u32) -> Self {
+        Self::with_loader(dir, width, height, load)
+    }
+}
+
+impl<T> Templates<T> {
+    /// Creates a loader for templates in `dir`, if any, that are loaded by `load`.
+    pub fn with_loader(dir: Option<&Path>, width: u32, height: u32, load: Loader<T>) -> Self {
+        Self {
+            dir: dir.map(Path::to_path_buf),
+            width,
+            height,
+            load,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the template named `name`, or `None` if it can't be loaded.
+    /// Failures are only reported the first time a template is requested.
+    pub fn get(&self, name: &str) -> Option<Arc<T>> {
+        let dir = self.dir.as_ref()?;
+        // the lock is held while loading, so that each template is only loaded once
+        let mut cache = self.cache.lock().expect("failed to lock template cache");
+        cache
+            .entry(name.to_string())
+            .or_insert_with(|| match (self.load)(dir, name, self.width, self.height) {
+                Ok(image) => Some(Arc::new(image)),
+                Err(e) => {
+                    warn!("not drawing template {name:?}: {e:#}");
+                    None
+                }
+            })
+            .clone()
+    }
+}
+
+pub fn load(dir: &Path, name: &str, width: u32, height: u32) -> Result<RgbaImage> {
+    let svg_path = dir.join(format!("{name}.svg"));
+    if svg_path.exists() {
+        debug!("loading template {svg_path:?}");
+        let data = std::fs::read(&svg_path).context(format!("failed to read {svg_path:?}"))?;
+        return rasterize_svg(&data, width, height).context(format!("invalid SVG {svg_path:?}"));
+    }
+
+    let png_path = dir.join(format!("{name}.png"));
+    if png_path.exists() {
+        debug!("loading template {png_path:?}");
+        let image = image::open(&png_path).context(format!("failed to read {png_path:?}"))?;
+        return Ok(image
+            .resize_exact(width, height, FilterType::Triangle)
+            .to_rgba8());
+    }
+
+    Err(anyhow!("no {name}.svg or {name}.png in {dir:?}"))
+}
+
+/// Renders an SVG document stretched to `width` by `height` pixels.  Text in
+/// the document is not drawn.
+fn rasterize_svg(data: &[u8], width: u32, height: u32) -> Result<RgbaImage> {
+    let mut tree = usvg::Tree::from_data(data, &usvg::Options::default())?;
+    tree.postprocess(usvg::PostProcessingSteps::default());
+
+    let mut pixmap =
+        tiny_skia::Pixmap::new(width, height).ok_or(anyhow!("invalid size {width}x{height}"))?;
+    let transform = tiny_skia::Transform::from_scale(
+        width as f32 / tree.size.width(),
+        height as f32 / tree.size.height(),
+    );
+    resvg::render(&tree, transform, &mut pixmap.as_mut());
+
+    let mut image = RgbaImage::new(width, height);
+    for (pixel, src) in image.pixels_mut().zip(pixmap.pixels()) {
+        let src = src.demultiply();
+        pixel.0 = [src.red(), src.green(), src.blue(), src.alpha()];
+    }
+    Ok(image)
+}
+
+/// Flattens a template onto a white page, for outputs without transparency.
+pub fn on_white(template: &RgbaImage) -> image::RgbImage {
+    image::RgbImage::from_fn(template.width(), template.height(), |x, y| {
+        let [r, g, b, a] = template.get_pixel(x, y).0;
+        let blend = |c: u8| {
+            let alpha = a as u32;
+            ((c as u32 * alpha + 255 * (255 - alpha) + 127) / 255) as u8
+        };
+        image::Rgb([blend(r), blend(g), blend(b)])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_templates() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        std::fs::write(
+            dir.join("P Lines.svg"),
+            r##"<svg xmlns="http://www.w3.org/2000/svg" width="100" height="200">
+                <rect x="0" y="100" width="100" height="100" fill="#000000"/>
+            </svg>"##,
+        )
+        .unwrap();
+        RgbaImage::from_pixel(4, 4, image::Rgba([255, 0, 0, 128]))
+            .save(dir.join("P Grid.png"))
+            .unwrap();
+
+        let templates = Templates::new(Some(dir), 10, 20);
+        let lines = templates.get("P Lines").unwrap();
+        assert_eq!(lines.dimensions(), (10, 20));
+        assert_eq!(lines.get_pixel(5, 5).0[3], 0);
+        assert_eq!(lines.get_pixel(5, 15).0, [0, 0, 0, 255]);
+
+        let grid = templates.get("P Grid").unwrap();
+        assert_eq!(grid.dimensions(), (10, 20));
+        assert_eq!(on_white(&grid).get_pixel(3, 3).0, [255, 127, 127]);
+
+        assert!(templates.get("P Dots").is_none());
+        assert!(Templates::new(None, 10, 20).get("P Lines").is_none());
+    }
+}
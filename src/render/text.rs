@@ -0,0 +1,167 @@
+//! Layout of typed text into positioned lines, independent of the output format.
+use rusttype::{point, Font, Scale};
+
+use crate::model::content::{ParagraphStyle, Text};
+
+pub const REGULAR_FONT_BYTES: &[u8] = include_bytes!("../../static/AmazonEmber_Lt.ttf");
+pub const BOLD_FONT_BYTES: &[u8] = include_bytes!("../../static/Amazon-Ember-Medium.ttf");
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FontWeight {
+    Regular,
+    Bold,
+}
+
+/// A single line of laid out text.  Coordinates are in page pixels, with `y`
+/// being the baseline measured from the top of the page.
+#[derive(Debug)]
+pub struct TextLine {
+    pub x: f32,
+    pub y: f32,
+    /// Font size in page pixels.
+    pub size: f32,
+    pub weight: FontWeight,
+    pub contents: String,
+}
+
+struct StyleMetrics {
+    size: f32,
+    line_height: f32,
+    weight: FontWeight,
+    indent: f32,
+    marker: Option<&'static str>,
+}
+
+fn style_metrics(style: ParagraphStyle) -> StyleMetrics {
+    let plain = StyleMetrics {
+        size: 32.0,
+        line_height: 71.0,
+        weight: FontWeight::Regular,
+        indent: 0.0,
+        marker: None,
+    };
+
+    match style {
+        ParagraphStyle::Basic | ParagraphStyle::Plain => plain,
+        ParagraphStyle::Heading => StyleMetrics {
+            size: 48.0,
+            line_height: 100.0,
+            weight: FontWeight::Bold,
+            ..plain
+        },
+        ParagraphStyle::Bold => StyleMetrics {
+            weight: FontWeight::Bold,
+            ..plain
+        },
+        ParagraphStyle::Bullet => StyleMetrics {
+            indent: 48.0,
+            marker: Some("•"),
+            ..plain
+        },
+        ParagraphStyle::Bullet2 => StyleMetrics {
+            indent: 96.0,
+            marker: Some("–"),
+            ..plain
+        },
+        ParagraphStyle::Checkbox => StyleMetrics {
+            indent: 64.0,
+            marker: Some("[ ]"),
+            ..plain
+        },
+        ParagraphStyle::CheckboxChecked => StyleMetrics {
+            indent: 64.0,
+            marker: Some("[x]"),
+            ..plain
+        },
+    }
+}
+
+pub struct TextLayout {
+    regular: Font<'static>,
+    bold: Font<'static>,
+}
+
+impl Default for TextLayout {
+    fn default() -> Self {
+        Self {
+            regular: Font::try_from_bytes(REGULAR_FONT_BYTES).expect("embedded font is valid"),
+            bold: Font::try_from_bytes(BOLD_FONT_BYTES).expect("embedded font is valid"),
+        }
+    }
+}
+
+impl TextLayout {
+    pub fn font(&self, weight: FontWeight) -> &Font<'static> {
+        match weight {
+            FontWeight::Regular => &self.regular,
+            FontWeight::Bold => &self.bold,
+        }
+    }
+
+    fn measure(&self, weight: FontWeight, size: f32, s: &str) -> f32 {
+        self.font(weight)
+            .layout(s, Scale::uniform(size), point(0.0, 0.0))
+            .last()
+            .map(|g| g.position().x + g.unpositioned().h_metrics().advance_width)
+            .unwrap_or(0.0)
+    }
+
+    /// Greedily wraps `contents` on word boundaries so that each line fits in `max_width`.
+    fn wrap(&self, weight: FontWeight, size: f32, max_width: f32, contents: &str) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut current = String::new();
+        for word in contents.split(' ') {
+            let candidate = if current.is_empty() {
+                word.to_string()
+            } else {
+                format!("{current} {word}")
+            };
+
+            if current.is_empty() || self.measure(weight, size, &candidate) <= max_width {
+                current = candidate;
+            } else {
+                lines.push(std::mem::replace(&mut current, word.to_string()));
+            }
+        }
+        lines.push(current);
+        lines
+    }
+
+    pub fn layout(&self, text: &Text) -> Vec<TextLine> {
+        let mut lines = Vec::new();
+        let mut top = text.y;
+
+        for paragraph in &text.paragraphs {
+            let metrics = style_metrics(paragraph.style);
+            let x = text.x + metrics.indent;
+            let baseline_offset = (metrics.line_height + metrics.size) / 2.0;
+
+            if let Some(marker) = metrics.marker {
+                lines.push(TextLine {
+                    x: text.x + metrics.indent / 4.0,
+                    y: top + baseline_offset,
+                    size: metrics.size,
+                    weight: metrics.weight,
+                    contents: marker.to_string(),
+                });
+            }
+
+            let max_width = (text.width - metrics.indent).max(metrics.size);
+            for contents in self.wrap(metrics.weight, metrics.size, max_width, &paragraph.contents)
+            {
+                if !contents.is_empty() {
+                    lines.push(TextLine {
+                        x,
+                        y: top + baseline_offset,
+                        size: metrics.size,
+                        weight: metrics.weight,
+                        contents,
+                    });
+                }
+                top += metrics.line_height;
+            }
+        }
+
+        lines
+    }
+}
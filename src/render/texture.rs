@@ -0,0 +1,210 @@
+//! Simulated texture of the graphite and paint brushes, independent of the
+//! output format.
+//!
+//! Textured strokes are drawn translucently, with an opacity that follows the
+//! pressure of the stroke, and are overlaid with small specks of the same
+//! colour.  Pencils scatter their specks across the stroke to imitate grain,
+//! while the paintbrush lines them up in lanes to imitate bristle streaks.  The
+//! specks are placed pseudo-randomly but deterministically, so that rendering
+//! a notebook twice gives identical output.
+use crate::model::content::{BrushType, Line};
+
+use super::stroke;
+
+/// A square speck of pigment, centred at `x`, `y` in page pixels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Speck {
+    pub x: f32,
+    pub y: f32,
+    pub size: f32,
+}
+
+#[derive(Debug)]
+pub struct Texture {
+    /// Opacity of the stroke outline and its specks, from 0 to 1.
+    pub opacity: f32,
+    pub specks: Vec<Speck>,
+}
+
+struct Grain {
+    /// Opacity of a stroke drawn with no pressure.
+    base_opacity: f32,
+    /// Opacity added at full pressure.
+    pressure_opacity: f32,
+    /// Distance along the stroke between rows of specks, in page pixels.
+    spacing: f32,
+    /// Specks tried per row at full pressure.
+    per_row: usize,
+    speck_size: f32,
+    /// Number of bristle lanes the specks are aligned to, if any.
+    lanes: Option<usize>,
+}
+
+fn grain(brush: BrushType) -> Option<Grain> {
+    match brush {
+        BrushType::Pencil => Some(Grain {
+            base_opacity: 0.3,
+            pressure_opacity: 0.55,
+            spacing: 1.5,
+            per_row: 3,
+            speck_size: 0.8,
+            lanes: None,
+        }),
+        BrushType::MechanicalPencil => Some(Grain {
+            base_opacity: 0.55,
+            pressure_opacity: 0.35,
+            spacing: 1.5,
+            per_row: 2,
+            speck_size: 0.5,
+            lanes: None,
+        }),
+        BrushType::Paintbrush => Some(Grain {
+            base_opacity: 0.55,
+            pressure_opacity: 0.4,
+            spacing: 2.0,
+            per_row: 5,
+            speck_size: 1.2,
+            lanes: Some(5),
+        }),
+        _ => None,
+    }
+}
+
+/// A small xorshift generator, so that the texture does not depend on a
+/// source of randomness.
+struct Rng(u32);
+
+impl Rng {
+    fn for_line(line: &Line) -> Self {
+        let seed = line
+            .points
+            .first()
+            .map(|p| p.x.to_bits() ^ p.y.to_bits().rotate_left(16))
+            .unwrap_or_default();
+        Self(seed | 1)
+    }
+
+    /// Returns a number in [0, 1).
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        (self.0 >> 8) as f32 / (1 << 24) as f32
+    }
+}
+
+/// Computes the texture of `line`, or `None` if its brush draws solid strokes.
+pub fn texture(line: &Line) -> Option<Texture> {
+    let grain = grain(line.brush_type)?;
+
+    let pressure = if line.points.is_empty() {
+        0.0
+    } else {
+        line.points
+            .iter()
+            .map(|p| p.pressure.clamp(0.0, 1.0))
+            .sum::<f32>()
+            / line.points.len() as f32
+    };
+    let opacity = (grain.base_opacity + grain.pressure_opacity * pressure).min(1.0);
+
+    let samples = stroke::centre_line(line);
+    let normals = stroke::normals(&samples);
+    let mut rng = Rng::for_line(line);
+    let mut specks = Vec::new();
+
+    // harder strokes deposit more pigment
+    let density = 0.3 + 0.7 * pressure;
+    let mut travelled = grain.spacing;
+    for i in 0..samples.len() {
+        let sample = samples[i];
+        if i > 0 {
+            let prev = samples[i - 1];
+            travelled += (sample.x - prev.x).hypot(sample.y - prev.y);
+        }
+        if travelled < grain.spacing {
+            continue;
+        }
+        travelled = 0.0;
+
+        let (nx, ny) = normals[i];
+        for _ in 0..grain.per_row {
+            if rng.next() > density {
+                continue;
+            }
+
+            let across = match grain.lanes {
+                Some(lanes) => (rng.next() * lanes as f32).floor() / (lanes - 1).max(1) as f32,
+                None => rng.next(),
+            };
+            let offset = (across - 0.5) * (sample.width - grain.speck_size).max(0.0);
+            // jitter along the stroke so that rows do not line up
+            let along = (rng.next() - 0.5) * grain.spacing;
+            specks.push(Speck {
+                x: sample.x + nx * offset + ny * along,
+                y: sample.y + ny * offset - nx * along,
+                size: grain.speck_size * (0.5 + rng.next()),
+            });
+        }
+    }
+
+    Some(Texture { opacity, specks })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::content::{Color, Point};
+    use rstest::rstest;
+
+    fn line(brush_type: BrushType, pressure: f32) -> Line {
+        Line {
+            brush_type,
+            color: Color::Black,
+            brush_size: 2.0,
+            points: (0..=20)
+                .map(|i| Point {
+                    x: 100.0 + i as f32 * 5.0,
+                    y: 200.0,
+                    speed: 0.0,
+                    direction: 0.0,
+                    width: 6.0,
+                    pressure,
+                })
+                .collect(),
+            deleted: false,
+        }
+    }
+
+    #[rstest]
+    #[case::pencil(BrushType::Pencil)]
+    #[case::mechanical_pencil(BrushType::MechanicalPencil)]
+    #[case::paintbrush(BrushType::Paintbrush)]
+    fn test_pressure_opacity(#[case] brush: BrushType) {
+        let light = texture(&line(brush, 0.1)).unwrap();
+        let heavy = texture(&line(brush, 0.9)).unwrap();
+        assert!(light.opacity < heavy.opacity);
+        assert!(heavy.opacity <= 1.0);
+        assert!(light.specks.len() < heavy.specks.len());
+    }
+
+    #[rstest]
+    #[case::fineliner(BrushType::Fineliner)]
+    #[case::ballpoint(BrushType::Ballpoint)]
+    #[case::highlighter(BrushType::Highlighter)]
+    fn test_solid_brushes(#[case] brush: BrushType) {
+        assert!(texture(&line(brush, 0.5)).is_none());
+    }
+
+    #[test]
+    fn test_specks_within_stroke() {
+        let first = texture(&line(BrushType::Pencil, 0.5)).unwrap();
+        let second = texture(&line(BrushType::Pencil, 0.5)).unwrap();
+        assert_eq!(first.specks, second.specks);
+
+        for speck in first.specks {
+            assert!((96.0..=204.0).contains(&speck.x), "{speck:?}");
+            assert!((197.0..=203.0).contains(&speck.y), "{speck:?}");
+        }
+    }
+}
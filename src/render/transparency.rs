@@ -0,0 +1,219 @@
+//! Transparency support for the PDF renderer.
+//!
+//! `printpdf` has no way to register an extended graphics state with a fill
+//! alpha, so highlighter and textured strokes reference a named graphics state
+//! from their content stream and the states themselves are added to each page's
+//! resources after the document is serialized.  Pages also get a transparency
+//! group so that viewers composite the blended strokes consistently.
+use anyhow::{Context, Result};
+use printpdf::lopdf::{self, content::Operation, Dictionary, Object};
+use printpdf::PdfLayerReference;
+
+use super::HIGHLIGHTER_OPACITY;
+
+/// Resource name of the graphics state used for highlighter strokes.
+const HIGHLIGHTER_STATE: &str = "RmHighlighter";
+
+/// Switches the layer to the highlighter graphics state.  Callers should wrap
+/// this in a saved graphics state so that later strokes are drawn opaque.
+pub fn use_highlighter_state(layer: &PdfLayerReference) {
+    layer.add_operation(Operation::new(
+        "gs",
+        vec![Object::Name(HIGHLIGHTER_STATE.as_bytes().to_vec())],
+    ));
+}
+
+/// Opacities of textured strokes are rounded to this many steps, each with its
+/// own graphics state.
+const OPACITY_STEPS: u32 = 20;
+
+fn opacity_state_name(step: u32) -> String {
+    format!("RmOpacity{step}")
+}
+
+/// Switches the layer to a graphics state with the given fill opacity.  Callers
+/// should wrap this in a saved graphics state so that later strokes are drawn opaque.
+pub fn use_opacity_state(layer: &PdfLayerReference, opacity: f32) {
+    let step = (opacity.clamp(0.0, 1.0) * OPACITY_STEPS as f32).round() as u32;
+    layer.add_operation(Operation::new(
+        "gs",
+        vec![Object::Name(opacity_state_name(step).into_bytes())],
+    ));
+}
+
+fn highlighter_state() -> Dictionary {
+    let mut state = Dictionary::new();
+    state.set("Type", Object::Name(b"ExtGState".to_vec()));
+    state.set("BM", Object::Name(b"Multiply".to_vec()));
+    state.set("ca", Object::Real(HIGHLIGHTER_OPACITY as f64));
+    state.set("CA", Object::Real(HIGHLIGHTER_OPACITY as f64));
+    state
+}
+
+fn opacity_state(alpha: f64) -> Dictionary {
+    let mut state = Dictionary::new();
+    state.set("Type", Object::Name(b"ExtGState".to_vec()));
+    state.set("ca", Object::Real(alpha));
+    state.set("CA", Object::Real(alpha));
+    state
+}
+
+/// Adds every graphics state used by the renderer to `states`.
+fn add_states(states: &mut Dictionary) {
+    states.set(HIGHLIGHTER_STATE, highlighter_state());
+    for step in 0..=OPACITY_STEPS {
+        states.set(
+            opacity_state_name(step),
+            opacity_state(step as f64 / OPACITY_STEPS as f64),
+        );
+    }
+}
+
+fn transparency_group() -> Dictionary {
+    let mut group = Dictionary::new();
+    group.set("Type", Object::Name(b"Group".to_vec()));
+    group.set("S", Object::Name(b"Transparency".to_vec()));
+    group.set("CS", Object::Name(b"DeviceRGB".to_vec()));
+    group
+}
+
+/// Adds the renderer's graphics states to a page's `resources`, next to those
+/// already there, which may be in a separate object of `doc`.
+fn add_resource_states(doc: &mut lopdf::Document, resources: &mut Dictionary) -> Result<()> {
+    match resources.get(b"ExtGState") {
+        Ok(Object::Reference(id)) => add_states(doc.get_object_mut(*id)?.as_dict_mut()?),
+        Ok(Object::Dictionary(_)) => add_states(resources.get_mut(b"ExtGState")?.as_dict_mut()?),
+        _ => {
+            let mut states = Dictionary::new();
+            add_states(&mut states);
+            resources.set("ExtGState", states);
+        }
+    }
+    Ok(())
+}
+
+/// Adds the renderer's graphics states and a transparency group to every page
+/// of the serialized document `pdf`.
+pub fn add_transparency_resources(pdf: &[u8]) -> Result<Vec<u8>> {
+    let mut doc = lopdf::Document::load_mem(pdf).context("failed to reload rendered PDF")?;
+
+    for (_, page_id) in doc.get_pages() {
+        let page = doc.get_object_mut(page_id)?.as_dict_mut()?;
+        page.set("Group", transparency_group());
+
+        // pages either refer to their resources or hold them inline, and the
+        // fonts and images in them have to be kept either way
+        match page.get(b"Resources") {
+            Ok(Object::Reference(id)) => {
+                let id = *id;
+                let mut resources = doc.get_dictionary(id)?.clone();
+                add_resource_states(&mut doc, &mut resources)?;
+                doc.objects.insert(id, Object::Dictionary(resources));
+            }
+            resources => {
+                let mut resources = match resources {
+                    Ok(Object::Dictionary(resources)) => resources.clone(),
+                    _ => Dictionary::new(),
+                };
+                add_resource_states(&mut doc, &mut resources)?;
+                doc.get_object_mut(page_id)?
+                    .as_dict_mut()?
+                    .set("Resources", resources);
+            }
+        }
+    }
+
+    let mut bytes = Vec::new();
+    doc.save_to(&mut bytes)?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use printpdf::{Mm, PdfDocument};
+
+    #[test]
+    fn test_add_transparency_resources() {
+        let (doc, page, layer) = PdfDocument::new("test", Mm(100.0), Mm(100.0), "Layer 1");
+        let layer = doc.get_page(page).get_layer(layer);
+        layer.save_graphics_state();
+        use_highlighter_state(&layer);
+        use_opacity_state(&layer, 0.42);
+        layer.restore_graphics_state();
+
+        let pdf = add_transparency_resources(&doc.save_to_bytes().unwrap()).unwrap();
+
+        let doc = lopdf::Document::load_mem(&pdf).unwrap();
+        let page_id = *doc.get_pages().values().next().unwrap();
+        let page = doc.get_dictionary(page_id).unwrap();
+        assert!(page.has(b"Group"));
+
+        let resources = doc
+            .get_dictionary(page.get(b"Resources").unwrap().as_reference().unwrap())
+            .unwrap();
+        let states = resources
+            .get(b"ExtGState")
+            .and_then(Object::as_dict)
+            .unwrap();
+        let state = states
+            .get(HIGHLIGHTER_STATE.as_bytes())
+            .and_then(Object::as_dict)
+            .unwrap();
+        assert_eq!(state.get(b"BM").unwrap().as_name_str().unwrap(), "Multiply");
+
+        // 0.42 rounds to the 0.4 opacity step
+        let state = states.get(b"RmOpacity8").and_then(Object::as_dict).unwrap();
+        assert_eq!(state.get(b"ca").unwrap().as_f64().unwrap(), 0.4);
+    }
+
+    #[test]
+    fn test_inline_resources() {
+        let mut doc = lopdf::Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let font_id = doc.add_object(Dictionary::new());
+        let mut fonts = Dictionary::new();
+        fonts.set("F1", Object::Reference(font_id));
+        let mut states = Dictionary::new();
+        states.set("GS1", Dictionary::new());
+        let mut resources = Dictionary::new();
+        resources.set("Font", fonts);
+        resources.set("ExtGState", states);
+        let mut page = Dictionary::new();
+        page.set("Type", Object::Name(b"Page".to_vec()));
+        page.set("Parent", Object::Reference(pages_id));
+        page.set("Resources", resources);
+        let page_id = doc.add_object(page);
+        let mut pages = Dictionary::new();
+        pages.set("Type", Object::Name(b"Pages".to_vec()));
+        pages.set("Kids", vec![Object::Reference(page_id)]);
+        pages.set("Count", 1);
+        doc.objects.insert(pages_id, Object::Dictionary(pages));
+        let mut catalog = Dictionary::new();
+        catalog.set("Type", Object::Name(b"Catalog".to_vec()));
+        catalog.set("Pages", Object::Reference(pages_id));
+        let catalog_id = doc.add_object(catalog);
+        doc.trailer.set("Root", Object::Reference(catalog_id));
+        let mut pdf = Vec::new();
+        doc.save_to(&mut pdf).unwrap();
+
+        let pdf = add_transparency_resources(&pdf).unwrap();
+
+        let doc = lopdf::Document::load_mem(&pdf).unwrap();
+        let page_id = *doc.get_pages().values().next().unwrap();
+        let resources = doc
+            .get_dictionary(page_id)
+            .unwrap()
+            .get(b"Resources")
+            .and_then(Object::as_dict)
+            .unwrap();
+        let fonts = resources.get(b"Font").and_then(Object::as_dict).unwrap();
+        assert!(fonts.has(b"F1"));
+        let states = resources
+            .get(b"ExtGState")
+            .and_then(Object::as_dict)
+            .unwrap();
+        assert!(states.has(b"GS1"));
+        assert!(states.has(HIGHLIGHTER_STATE.as_bytes()));
+    }
+}
@@ -0,0 +1,126 @@
+//! Summarizes which notebooks a conversion wrote, and what went wrong with
+//! the others.
+use std::fmt;
+
+use crate::parser::PageError;
+
+/// What came of converting a notebook.
+#[derive(Debug)]
+pub enum Outcome {
+    /// All pages were written.
+    Converted,
+    /// The notebook was written without the pages that failed.
+    Partial(Vec<PageError>),
+    /// Nothing was written.
+    Failed(anyhow::Error),
+    /// Not attempted, because an earlier notebook failed in strict mode.
+    Skipped,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Outcomes by notebook, in the order that they are reported in.
+    pub notebooks: Vec<(String, Outcome)>,
+    /// Number of notebooks that were already up to date.
+    pub unchanged: usize,
+}
+
+impl Report {
+    pub fn add(&mut self, name: String, outcome: Outcome) {
+        self.notebooks.push((name, outcome));
+    }
+
+    fn count(&self, matches: fn(&Outcome) -> bool) -> usize {
+        self.notebooks.iter().filter(|(_, o)| matches(o)).count()
+    }
+
+    /// Number of notebooks that failed, partially or completely.
+    pub fn failed(&self) -> usize {
+        self.count(|o| matches!(o, Outcome::Partial(_) | Outcome::Failed(_)))
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let section = |f: &mut fmt::Formatter<'_>, title, matches: fn(&Outcome) -> bool| {
+            if self.notebooks.iter().any(|(_, o)| matches(o)) {
+                writeln!(f, "{title}:")?;
+            }
+            for (name, outcome) in self.notebooks.iter().filter(|(_, o)| matches(o)) {
+                match outcome {
+                    Outcome::Converted | Outcome::Skipped => writeln!(f, "  {name}")?,
+                    Outcome::Partial(errors) => {
+                        let plural = if errors.len() == 1 { "" } else { "s" };
+                        writeln!(f, "  {name}: {} page{plural} failed", errors.len())?;
+                        for e in errors {
+                            writeln!(f, "    page {} ({}): {:#}", e.index + 1, e.id, e.error)?;
+                        }
+                    }
+                    Outcome::Failed(e) => writeln!(f, "  {name}: {e:#}")?,
+                }
+            }
+            Ok(())
+        };
+
+        section(f, "Converted", |o| matches!(o, Outcome::Converted))?;
+        section(f, "Partially converted", |o| {
+            matches!(o, Outcome::Partial(_))
+        })?;
+        section(f, "Failed", |o| matches!(o, Outcome::Failed(_)))?;
+        section(f, "Skipped", |o| matches!(o, Outcome::Skipped))?;
+
+        write!(
+            f,
+            "{} converted, {} partially converted, {} failed, {} unchanged",
+            self.count(|o| matches!(o, Outcome::Converted)),
+            self.count(|o| matches!(o, Outcome::Partial(_))),
+            self.count(|o| matches!(o, Outcome::Failed(_))),
+            self.unchanged,
+        )?;
+        let skipped = self.count(|o| matches!(o, Outcome::Skipped));
+        if skipped > 0 {
+            write!(f, ", {skipped} skipped")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_report() {
+        let mut report = Report {
+            unchanged: 4,
+            ..Default::default()
+        };
+        report.add("Work/Notes".to_string(), Outcome::Converted);
+        report.add(
+            "Diary".to_string(),
+            Outcome::Partial(vec![PageError {
+                index: 2,
+                id: "abc".to_string(),
+                error: anyhow!("invalid page data at byte 43: Nom(Tag)"),
+            }]),
+        );
+        report.add(
+            "Paper".to_string(),
+            Outcome::Failed(anyhow!("disk full").context("failed to write Paper.pdf")),
+        );
+
+        assert_eq!(report.failed(), 2);
+        assert_eq!(
+            report.to_string(),
+            "Converted:\n  \
+             Work/Notes\n\
+             Partially converted:\n  \
+             Diary: 1 page failed\n    \
+             page 3 (abc): invalid page data at byte 43: Nom(Tag)\n\
+             Failed:\n  \
+             Paper: failed to write Paper.pdf: disk full\n\
+             1 converted, 1 partially converted, 1 failed, 4 unchanged"
+        );
+    }
+}
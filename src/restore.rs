@@ -0,0 +1,392 @@
+//! Copies documents from a backup written by `sync` back to the device.
+//!
+//! A document is restored with every file named after its ID: the
+//! `.metadata`, `.content` and `.pagedata` files, the original PDF or EPUB,
+//! and the directories of its pages and thumbnails.  The folders that it is
+//! in are restored along with it if they are missing on the device.
+//!
+//! xochitl is stopped while the files are written, since it rewrites the
+//! files of the documents it has open, and reads new ones when it starts.
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use serde::de::DeserializeOwned;
+use tracing::{info, warn};
+
+use crate::device::Remarkable;
+use crate::model::fs::serde::{NotebookContent, NotebookContentRaw};
+use crate::model::fs::{Notebook, Notebooks, Parent};
+use crate::sync::{FileStat, RemoteFile};
+
+/// How a document in the backup compares with the device.
+#[derive(Debug, PartialEq)]
+enum Status {
+    /// Not on the device.
+    Missing,
+    /// On the device as it is in the backup.
+    Unchanged,
+    /// On the device, but different from the backup.
+    Conflict,
+}
+
+/// Keys of the `.metadata` file that xochitl changes without the document
+/// changing, such as when it is opened or synced with the cloud.  The
+/// `lastModified` time is kept, since it only changes with the document.
+const VOLATILE_METADATA: [&str; 5] = [
+    "lastOpened",
+    "lastOpenedPage",
+    "metadatamodified",
+    "modified",
+    "synced",
+];
+
+/// Reads the file `name` in `dir` as JSON, or `None` if there is none.
+fn read_json<T: DeserializeOwned>(dir: &Path, name: &str) -> Result<Option<T>> {
+    let path = dir.join(name);
+    match std::fs::read(&path) {
+        Ok(contents) => Ok(Some(
+            serde_json::from_slice(&contents).context(format!("invalid {path:?}"))?,
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).context(format!("failed to read {path:?}")),
+    }
+}
+
+/// The `.metadata` of `id` in `dir`, without the keys that change by themselves.
+fn metadata(dir: &Path, id: &str) -> Result<Option<serde_json::Value>> {
+    let mut metadata = read_json(dir, &format!("{id}.metadata"))?;
+    if let Some(serde_json::Value::Object(map)) = &mut metadata {
+        for key in VOLATILE_METADATA {
+            map.remove(key);
+        }
+    }
+    Ok(metadata)
+}
+
+/// The IDs of the pages listed in the `.content` of `id` in `dir`.
+fn page_ids(dir: &Path, id: &str) -> Result<Option<Vec<String>>> {
+    let content: Option<NotebookContentRaw> = read_json(dir, &format!("{id}.content"))?;
+    Ok(content
+        .map(NotebookContent::from)
+        .and_then(|content| content.pages)
+        .map(|pages| pages.into_iter().map(|page| page.id).collect()))
+}
+
+/// Compares the document or folder `id` in the backup with the device, whose
+/// `.metadata` and `.content` files are copied to `device_dir`, and whose
+/// page files are listed by `device_pages`.  The page files are compared by
+/// size and modification time, which `sync` gives the copies in the backup.
+fn status(
+    backup_dir: &Path,
+    device_dir: &Path,
+    id: &str,
+    device_pages: impl FnOnce() -> Result<Vec<RemoteFile>>,
+) -> Result<Status> {
+    let device_metadata = metadata(device_dir, id)?;
+    if device_metadata.is_none() {
+        return Ok(Status::Missing);
+    }
+    // the files are compared as JSON, since xochitl may format them differently
+    if metadata(backup_dir, id)? != device_metadata
+        || page_ids(backup_dir, id)? != page_ids(device_dir, id)?
+    {
+        return Ok(Status::Conflict);
+    }
+
+    let mut backup_pages = BTreeMap::new();
+    if backup_dir.join(id).is_dir() {
+        let mut files = Vec::new();
+        list_files(backup_dir, Path::new(id), &mut files)?;
+        for file in files {
+            let meta = std::fs::metadata(backup_dir.join(&file))?;
+            backup_pages.insert(
+                file.strip_prefix(id)?.to_path_buf(),
+                FileStat::of_local(&meta)?,
+            );
+        }
+    }
+    let device_pages: BTreeMap<_, _> = device_pages()?
+        .into_iter()
+        .map(|file| (file.path, file.stat))
+        .collect();
+    if backup_pages != device_pages {
+        return Ok(Status::Conflict);
+    }
+    Ok(Status::Unchanged)
+}
+
+/// Lists the files below `dir`, relative to `root`.
+fn list_files(root: &Path, dir: &Path, out: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries: Vec<_> = std::fs::read_dir(root.join(dir))
+        .context(format!("failed to read {:?}", root.join(dir)))?
+        .collect::<std::io::Result<_>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let path = dir.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            list_files(root, &path, out)?;
+        } else {
+            out.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// The files of the document or folder `id` in the backup, relative to it,
+/// with the `.metadata` last, since xochitl lists documents by it.
+fn document_files(backup_dir: &Path, id: &str) -> Result<Vec<PathBuf>> {
+    let prefix = format!("{id}.");
+    let mut files = Vec::new();
+    let mut entries: Vec<_> = std::fs::read_dir(backup_dir)
+        .context(format!("failed to read {backup_dir:?}"))?
+        .collect::<std::io::Result<_>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if name != id && !name.starts_with(&prefix) {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            list_files(backup_dir, Path::new(name), &mut files)?;
+        } else {
+            files.push(PathBuf::from(name));
+        }
+    }
+
+    let metadata = PathBuf::from(format!("{id}.metadata"));
+    files.retain(|path| *path != metadata);
+    files.push(metadata);
+    Ok(files)
+}
+
+/// Whether `notebook` is the one named `name`, either by its name or by its
+/// path in the library.
+fn is_named(library: &Notebooks, notebook: &Notebook, name: &str) -> bool {
+    notebook.name == name || library.path(notebook) == Path::new(name)
+}
+
+/// How `restore` treats documents.
+#[derive(Debug, Default)]
+pub struct RestoreOptions {
+    /// Replace the documents that were changed on the device since the backup.
+    pub overwrite: bool,
+    /// Only print the documents to restore, without restoring them.
+    pub dry_run: bool,
+}
+
+/// Uploads the notebooks named `notebook` from `backup_dir`, or all of them
+/// that aren't in the trash, to the device.  Fails without restoring anything
+/// if a document on the device differs from the backup, unless overwriting.
+pub fn restore(
+    rem: &Remarkable,
+    backup_dir: &Path,
+    notebook: Option<&str>,
+    options: &RestoreOptions,
+) -> Result<()> {
+    let library = crate::fs::scan(backup_dir)
+        .context(format!("failed to read the backup in {backup_dir:?}"))?;
+    let notebooks: Vec<_> = library
+        .notebooks
+        .iter()
+        .filter(|nb| match notebook {
+            Some(name) => is_named(&library, nb, name),
+            None => !library.is_trashed(nb),
+        })
+        .collect();
+    if let Some(name) = notebook {
+        if notebooks.is_empty() {
+            return Err(anyhow!(
+                "no notebook {name:?} in the backup in {backup_dir:?}"
+            ));
+        }
+    }
+
+    // the documents on the device are read like those of a backup
+    let scratch_dir = tempfile::tempdir()?;
+    rem.sync_metadata_to(scratch_dir.path())?;
+    let device_dir = scratch_dir.path();
+
+    let mut to_restore = Vec::new();
+    let mut conflicts = Vec::new();
+    for nb in notebooks {
+        let path = library.path(nb);
+        match status(backup_dir, device_dir, &nb.id, || {
+            rem.document_page_files(&nb.id)
+        })? {
+            Status::Missing => to_restore.push(nb),
+            Status::Unchanged => info!("{path:?} is already on the device"),
+            Status::Conflict if options.overwrite => {
+                warn!("overwriting {path:?}, which was changed on the device");
+                to_restore.push(nb);
+            }
+            Status::Conflict => conflicts.push(path),
+        }
+    }
+    if !conflicts.is_empty() {
+        let names: Vec<_> = conflicts
+            .iter()
+            .map(|p| format!("  {}", p.display()))
+            .collect();
+        return Err(anyhow!(
+            "these notebooks were changed on the device since the backup, restore them \
+             with --overwrite or leave them out with --notebook:\n{}",
+            names.join("\n")
+        ));
+    }
+    if to_restore.is_empty() {
+        info!("nothing to restore");
+        return Ok(());
+    }
+
+    // the folders missing on the device, found by walking up from each
+    // notebook until a folder that is there
+    let mut folders = BTreeSet::new();
+    for nb in &to_restore {
+        let mut parent = &nb.parent;
+        while let Parent::Collection(id) = parent {
+            let Some(collection) = library.collections.get(id) else {
+                warn!("folder {id} of {:?} isn't in the backup", nb.name);
+                break;
+            };
+            if metadata(device_dir, id)?.is_some() || !folders.insert(id) {
+                break;
+            }
+            parent = &collection.parent;
+        }
+    }
+
+    if options.dry_run {
+        for id in &folders {
+            println!(
+                "restore folder {}",
+                library
+                    .folder(&Parent::Collection(id.to_string()))
+                    .display()
+            );
+        }
+        for nb in &to_restore {
+            println!("restore {}", library.path(nb).display());
+        }
+        return Ok(());
+    }
+
+    let mut files = Vec::new();
+    for id in folders {
+        files.extend(document_files(backup_dir, id)?);
+    }
+    for nb in &to_restore {
+        info!("restoring {:?}", library.path(nb));
+        files.extend(document_files(backup_dir, &nb.id)?);
+    }
+
+    // xochitl is started again even if the upload fails, to not leave the
+    // device without its UI
+    let uploaded = rem
+        .stop_xochitl()
+        .and_then(|()| rem.upload_document_files(backup_dir, &files));
+    let started = rem.start_xochitl();
+    uploaded?;
+    started?;
+    info!("restored {} notebooks", to_restore.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status() {
+        let dir = tempfile::tempdir().unwrap();
+        let backup = dir.path().join("backup");
+        let device = dir.path().join("device");
+        std::fs::create_dir_all(backup.join("nb")).unwrap();
+        std::fs::create_dir_all(backup.join("nb.thumbnails")).unwrap();
+        std::fs::create_dir_all(&device).unwrap();
+        for (dir, name, contents) in [
+            (&backup, "nb.metadata", r#"{"visibleName": "Notes"}"#),
+            (&backup, "nb.content", r#"{"pages": ["page"]}"#),
+            (&backup, "nb.pagedata", "Blank"),
+            (&backup, "nb/page.rm", "lines"),
+            (&backup, "nb/page-metadata.json", "{}"),
+            (&backup, "nb.thumbnails/page.png", "png"),
+            (&backup, "nbx.metadata", "{}"),
+            (&device, "nb.metadata", "{\n  \"visibleName\": \"Notes\"\n}"),
+            (&device, "nb.content", r#"{"pages": ["page"]}"#),
+        ] {
+            std::fs::write(dir.join(name), contents).unwrap();
+        }
+
+        assert_eq!(
+            document_files(&backup, "nb").unwrap(),
+            [
+                "nb/page-metadata.json",
+                "nb/page.rm",
+                "nb.content",
+                "nb.pagedata",
+                "nb.thumbnails/page.png",
+                "nb.metadata",
+            ]
+            .map(PathBuf::from)
+        );
+
+        // sync gives the copies in the backup the device's modification time
+        let mtime = filetime::FileTime::from_unix_time(1_700_000_000, 0);
+        for page in ["nb/page.rm", "nb/page-metadata.json"] {
+            filetime::set_file_mtime(backup.join(page), mtime).unwrap();
+        }
+        let page_files = |size| {
+            move || {
+                Ok(vec![
+                    RemoteFile {
+                        path: PathBuf::from("page-metadata.json"),
+                        stat: FileStat {
+                            size: 2,
+                            mtime: 1_700_000_000,
+                        },
+                    },
+                    RemoteFile {
+                        path: PathBuf::from("page.rm"),
+                        stat: FileStat {
+                            size,
+                            mtime: 1_700_000_000,
+                        },
+                    },
+                ])
+            }
+        };
+        let status = |id| status(&backup, &device, id, page_files(5)).unwrap();
+
+        assert_eq!(status("nb"), Status::Unchanged);
+        assert_eq!(status("nbx"), Status::Missing);
+
+        // opening the notebook on the device isn't a change
+        std::fs::write(
+            device.join("nb.metadata"),
+            r#"{"visibleName": "Notes", "lastOpened": "1700000000000"}"#,
+        )
+        .unwrap();
+        assert_eq!(status("nb"), Status::Unchanged);
+
+        // nor is a new format of the .content file with the same pages
+        std::fs::write(
+            device.join("nb.content"),
+            r#"{"cPages": {"pages": [{"id": "page"}]}, "formatVersion": 2}"#,
+        )
+        .unwrap();
+        assert_eq!(status("nb"), Status::Unchanged);
+
+        // but writing on a page is
+        assert_eq!(
+            super::status(&backup, &device, "nb", page_files(6)).unwrap(),
+            Status::Conflict
+        );
+
+        std::fs::write(device.join("nb.content"), r#"{"pages": ["page", "new"]}"#).unwrap();
+        assert_eq!(status("nb"), Status::Conflict);
+    }
+}
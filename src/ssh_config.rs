@@ -0,0 +1,180 @@
+//! Reads host aliases from the OpenSSH client configuration in `~/.ssh/config`,
+//! so that the device can be reached by the same name as with `ssh`.
+//!
+//! Only `Host` sections and the `HostName`, `User`, `Port` and `IdentityFile`
+//! options are understood; `Match` sections and `Include` are ignored.
+use std::path::{Path, PathBuf};
+
+use tracing::{debug, warn};
+
+/// The options that apply to a host.
+#[derive(Debug, Default, PartialEq)]
+pub struct HostConfig {
+    pub host_name: Option<String>,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    pub identity_files: Vec<PathBuf>,
+}
+
+/// Looks up `host` in `~/.ssh/config`, which may be missing.
+pub fn load(host: &str) -> HostConfig {
+    let Some(path) = dirs::home_dir().map(|home| home.join(".ssh").join("config")) else {
+        return HostConfig::default();
+    };
+    match std::fs::read_to_string(&path) {
+        Ok(config) => lookup(&config, host),
+        Err(e) => {
+            debug!("not reading {path:?}: {e}");
+            HostConfig::default()
+        }
+    }
+}
+
+/// Looks up `host` in the contents of an SSH client configuration.  As with
+/// `ssh`, the first value of an option wins, except for identity files,
+/// which are all tried.
+pub fn lookup(config: &str, host: &str) -> HostConfig {
+    let mut result = HostConfig::default();
+    // options before the first section apply to all hosts
+    let mut active = true;
+
+    for line in config.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (keyword, value) = match line.split_once(|c: char| c.is_whitespace() || c == '=') {
+            Some((keyword, value)) => (keyword, value.trim_start_matches([' ', '\t', '=']).trim()),
+            None => (line, ""),
+        };
+        match keyword.to_ascii_lowercase().as_str() {
+            "host" => active = host_matches(value, host),
+            "match" => active = false,
+            "hostname" if active => {
+                result
+                    .host_name
+                    .get_or_insert_with(|| unquote(value).to_string());
+            }
+            "user" if active => {
+                result
+                    .user
+                    .get_or_insert_with(|| unquote(value).to_string());
+            }
+            "port" if active && result.port.is_none() => match value.parse() {
+                Ok(port) => result.port = Some(port),
+                Err(_) => warn!("ignoring invalid port {value:?} for {host}"),
+            },
+            "identityfile" if active => {
+                result
+                    .identity_files
+                    .push(expand_path(unquote(value), host));
+            }
+            _ => {}
+        }
+    }
+
+    result
+}
+
+/// Whether a `Host` line's patterns match `host`.  A match of any negated
+/// pattern excludes the host.
+fn host_matches(patterns: &str, host: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns.split_whitespace().map(unquote) {
+        match pattern.strip_prefix('!') {
+            Some(pattern) if glob_matches(pattern, host) => return false,
+            Some(_) => {}
+            None => matched |= glob_matches(pattern, host),
+        }
+    }
+    matched
+}
+
+/// Matches `text` against a pattern of `*` and `?` wildcards.
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    fn matches(pattern: &[char], text: &[char]) -> bool {
+        match pattern.split_first() {
+            None => text.is_empty(),
+            Some(('*', rest)) => (0..=text.len()).any(|i| matches(rest, &text[i..])),
+            Some(('?', rest)) => !text.is_empty() && matches(rest, &text[1..]),
+            Some((c, rest)) => text
+                .first()
+                .is_some_and(|t| t.eq_ignore_ascii_case(c) && matches(rest, &text[1..])),
+        }
+    }
+    let pattern: Vec<_> = pattern.chars().collect();
+    let text: Vec<_> = text.chars().collect();
+    matches(&pattern, &text)
+}
+
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+/// Expands a leading `~` and the `%d` (home directory) and `%h` (host) tokens.
+pub fn expand_path(path: &str, host: &str) -> PathBuf {
+    let home = dirs::home_dir().unwrap_or_default();
+    let path = path
+        .replace("%d", &home.to_string_lossy())
+        .replace("%h", host);
+    match path.strip_prefix("~/") {
+        Some(rest) => home.join(rest),
+        None => Path::new(&path).to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        # the tablet over USB and Wi-Fi
+        Host remarkable rm
+            HostName 10.11.99.1
+            User root
+            IdentityFile /keys/remarkable
+
+        Host rm-wifi
+            HostName=192.168.1.42
+            Port 2222
+
+        Host *.local !printer.local
+            User pi
+
+        Match exec "true"
+            User nobody
+
+        Host *
+            User fallback
+            IdentityFile "/keys/default"
+    "#;
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(
+            lookup(CONFIG, "rm"),
+            HostConfig {
+                host_name: Some("10.11.99.1".to_string()),
+                user: Some("root".to_string()),
+                port: None,
+                identity_files: vec![PathBuf::from("/keys/remarkable"), "/keys/default".into()],
+            }
+        );
+
+        let wifi = lookup(CONFIG, "rm-wifi");
+        assert_eq!(wifi.host_name.as_deref(), Some("192.168.1.42"));
+        assert_eq!(wifi.port, Some(2222));
+        assert_eq!(wifi.user.as_deref(), Some("fallback"));
+
+        assert_eq!(lookup(CONFIG, "pi.local").user.as_deref(), Some("pi"));
+        assert_eq!(
+            lookup(CONFIG, "printer.local").user.as_deref(),
+            Some("fallback")
+        );
+        assert_eq!(lookup(CONFIG, "10.11.99.1").host_name, None);
+    }
+}
@@ -9,7 +9,7 @@ use image::{DynamicImage, ImageBuffer, ImageFormat, Luma};
 use show_image::{create_window, WindowOptions};
 use tracing::{debug, info};
 
-use crate::device::RemarkableStreamer;
+use crate::device::{Connection, RemarkableStreamer};
 
 const WIDTH: usize = 1872;
 const HEIGHT: usize = 1404;
@@ -26,10 +26,10 @@ const MIN_DURATION_PER_FRAME: Duration = Duration::from_millis(100);
 ///
 /// Inspired by:
 /// https://blog.owulveryck.info/2021/03/30/streaming-the-remarkable-2.html
-pub async fn stream(show_diagnostics: bool) -> Result<()> {
+pub async fn stream(connection: Connection, show_diagnostics: bool) -> Result<()> {
     info!("streaming reMarkable tablet");
 
-    let rem = crate::device::Remarkable::open()?;
+    let rem = crate::device::Remarkable::open(connection)?;
 
     let window = create_window(
         "reMarkable device stream",
@@ -90,8 +90,8 @@ pub async fn stream(show_diagnostics: bool) -> Result<()> {
     }
 }
 
-pub async fn grab_frame(dest_file: impl AsRef<Path>) -> Result<()> {
-    let rem = crate::device::Remarkable::open()?;
+pub async fn grab_frame(connection: Connection, dest_file: impl AsRef<Path>) -> Result<()> {
+    let rem = crate::device::Remarkable::open(connection)?;
     let streamer = rem.streamer().await?;
     let mut frame_buffer = vec![0u8; HEIGHT * WIDTH];
     let image = get_frame(&streamer, &mut frame_buffer).await?;
@@ -107,7 +107,7 @@ pub async fn grab_frame(dest_file: impl AsRef<Path>) -> Result<()> {
 
 async fn get_frame(
     streamer: &RemarkableStreamer<'_>,
-    frame_buffer: &mut Vec<u8>,
+    frame_buffer: &mut [u8],
 ) -> Result<ImageBuffer<image::Rgb<u8>, Vec<u8>>> {
     let bytes = streamer.frame_buffer().await?;
 
@@ -149,7 +149,7 @@ async fn get_frame(
     );
 
     let buffer =
-        ImageBuffer::<Luma<u8>, Vec<u8>>::from_vec(WIDTH as _, HEIGHT as _, frame_buffer.clone())
+        ImageBuffer::<Luma<u8>, Vec<u8>>::from_vec(WIDTH as _, HEIGHT as _, frame_buffer.to_vec())
             .unwrap();
     let image = DynamicImage::ImageLuma8(buffer)
         .rotate270()
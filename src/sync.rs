@@ -0,0 +1,457 @@
+//! Plans the changes that mirror the files on the device in a local directory,
+//! and applies those that don't need the device.
+//!
+//! The size and modification time of each file on the device are recorded in
+//! a state file in the local directory when it is downloaded, and the local
+//! copy is given the same modification time.  A file is downloaded again once
+//! either the device's or the local copy no longer matches the record, so
+//! that neither clock skew nor a truncated download is mistaken for a file
+//! that is up to date.
+//!
+//! Files are downloaded into a directory of partial downloads and only moved
+//! into place once complete.  A download of the same version that was
+//! interrupted is resumed by the next sync.
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::Local;
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+/// Name of the state file in the local directory.
+const STATE_FILE: &str = ".remarkers-sync.json";
+
+/// Share of the local files above which deleting them needs `force`.
+const MAX_DELETE_FRACTION: f64 = 0.5;
+
+/// Name of the directory of partial downloads in the local directory.
+const PARTIAL_DIR: &str = ".remarkers-partial";
+
+/// How `sync` treats local files.
+#[derive(Debug, Default)]
+pub struct SyncOptions {
+    /// Remove local files that no longer exist on the device.
+    pub delete: bool,
+    /// Only print the changes, without making them.
+    pub dry_run: bool,
+    /// Move removed files into a dated directory in here instead of deleting
+    /// them.
+    pub snapshot_dir: Option<PathBuf>,
+    /// Delete even when that removes most of the local files.
+    pub force: bool,
+    /// Number of connections to download files over at once.
+    pub jobs: usize,
+    /// Show a progress bar of the downloads.
+    pub progress: bool,
+}
+
+/// A file on the device, relative to the synced directory.
+#[derive(Debug)]
+pub struct RemoteFile {
+    pub path: PathBuf,
+    pub stat: FileStat,
+}
+
+/// What identifies a version of a file.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct FileStat {
+    pub size: u64,
+    /// Modification time in seconds since the Unix epoch.
+    pub mtime: u64,
+}
+
+impl FileStat {
+    pub fn of_local(meta: &std::fs::Metadata) -> Result<Self> {
+        Ok(FileStat {
+            size: meta.len(),
+            mtime: meta.modified()?.duration_since(UNIX_EPOCH)?.as_secs(),
+        })
+    }
+}
+
+/// The files that were downloaded into a local directory, as they were on the
+/// device at the time.
+#[derive(Debug)]
+pub struct SyncState {
+    path: PathBuf,
+    files: BTreeMap<PathBuf, FileStat>,
+}
+
+impl SyncState {
+    /// Loads the state of `local_dir`.  A missing or unreadable state file is
+    /// treated as empty, in which case files are compared with the device's
+    /// directly.
+    pub fn load(local_dir: &Path) -> Self {
+        let path = local_dir.join(STATE_FILE);
+        let files = match std::fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents).unwrap_or_else(|e| {
+                warn!("ignoring invalid sync state {path:?}: {e}");
+                BTreeMap::new()
+            }),
+            Err(_) => BTreeMap::new(),
+        };
+        Self { path, files }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&self.path, serde_json::to_vec_pretty(&self.files)?)
+            .context(format!("failed to write sync state {:?}", self.path))
+    }
+
+    /// Records that `path` was downloaded, giving the local copy the device's
+    /// modification time.
+    pub fn record(&mut self, local_dir: &Path, path: &Path, stat: FileStat) -> Result<()> {
+        let mtime = FileTime::from_unix_time(stat.mtime as i64, 0);
+        filetime::set_file_mtime(local_dir.join(path), mtime)
+            .context(format!("failed to set the modification time of {path:?}"))?;
+        self.files.insert(path.to_path_buf(), stat);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, path: &Path) {
+        self.files.remove(path);
+    }
+
+    /// Forgets the files that aren't on the device anymore.
+    pub fn retain(&mut self, remote: &[RemoteFile]) {
+        let remote_paths: HashSet<_> = remote.iter().map(|f| f.path.as_path()).collect();
+        self.files
+            .retain(|path, _| remote_paths.contains(path.as_path()));
+    }
+
+    /// Whether the local copy of `file` is the same version as on the device.
+    fn is_current(&self, file: &RemoteFile, local: FileStat) -> bool {
+        match self.files.get(&file.path) {
+            Some(recorded) => *recorded == file.stat && local == *recorded,
+            // files from before the state file are kept if they match
+            None => local == file.stat,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    /// Download a file that is missing locally.
+    Create(PathBuf),
+    /// Download a file that changed on the device.
+    Update(PathBuf),
+    /// Remove a local file that is gone from the device.
+    Delete(PathBuf),
+}
+
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Change::Create(path) => write!(f, "create {}", path.display()),
+            Change::Update(path) => write!(f, "update {}", path.display()),
+            Change::Delete(path) => write!(f, "delete {}", path.display()),
+        }
+    }
+}
+
+/// Plans the changes to `local_dir` for it to match the `remote` files.  With
+/// `delete`, local files accepted by `filter` that aren't on the device are
+/// removed, except for those below `keep`, such as a snapshot directory.
+pub fn plan(
+    remote: &[RemoteFile],
+    local_dir: &Path,
+    state: &SyncState,
+    filter: &dyn Fn(&Path) -> bool,
+    delete: bool,
+    keep: Option<&Path>,
+) -> Result<Vec<Change>> {
+    let mut changes = Vec::new();
+    for file in remote {
+        let local_path = local_dir.join(&file.path);
+        match std::fs::metadata(&local_path) {
+            Ok(meta) => {
+                let local = FileStat::of_local(&meta)?;
+                debug!("Sync encountered remote={:?}, local={local:?}", file.stat);
+                if state.is_current(file, local) {
+                    debug!("Skipping unchanged {:?}", file.path);
+                } else {
+                    changes.push(Change::Update(file.path.clone()));
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                changes.push(Change::Create(file.path.clone()));
+            }
+            Err(e) => return Err(e).context(format!("failed to read {local_path:?}")),
+        }
+    }
+
+    if delete {
+        let remote_paths: HashSet<_> = remote.iter().map(|f| f.path.as_path()).collect();
+        let keep = keep.and_then(|keep| keep.canonicalize().ok());
+        let mut local_files = Vec::new();
+        list_local(local_dir, Path::new(""), keep.as_deref(), &mut local_files)?;
+        changes.extend(
+            local_files
+                .into_iter()
+                .filter(|path| path != Path::new(STATE_FILE) && !path.starts_with(PARTIAL_DIR))
+                .filter(|path| filter(path) && !remote_paths.contains(path.as_path()))
+                .map(Change::Delete),
+        );
+    }
+
+    Ok(changes)
+}
+
+/// Refuses the deletions among `changes` unless `force` is set, if they look
+/// like the files on the device couldn't be listed, e.g. because the device
+/// was reset, rather than like they were removed: when nothing is on the
+/// device, or when most of the local files would be deleted.
+pub fn check_deletions(changes: &[Change], remote: &[RemoteFile], force: bool) -> Result<()> {
+    let count = |matches: fn(&Change) -> bool| changes.iter().filter(|c| matches(c)).count();
+    let deleted = count(|c| matches!(c, Change::Delete(_)));
+    if deleted == 0 {
+        return Ok(());
+    }
+    // the local files are those that are deleted, and those on the device
+    // that aren't created
+    let local = deleted + remote.len() - count(|c| matches!(c, Change::Create(_)));
+    info!("{deleted} of {local} local files are gone from the device");
+
+    if force {
+        return Ok(());
+    }
+    if remote.is_empty() {
+        return Err(anyhow!(
+            "refusing to delete all {local} local files, since there are no files on the \
+             device; pass --force to delete them anyway"
+        ));
+    }
+    if deleted as f64 > local as f64 * MAX_DELETE_FRACTION {
+        return Err(anyhow!(
+            "refusing to delete {deleted} of {local} local files, which is more than {:.0}% \
+             of them; pass --force to delete them anyway",
+            MAX_DELETE_FRACTION * 100.0
+        ));
+    }
+    Ok(())
+}
+
+/// Lists the files below `dir`, relative to `root`, skipping the directory `keep`.
+fn list_local(root: &Path, dir: &Path, keep: Option<&Path>, out: &mut Vec<PathBuf>) -> Result<()> {
+    let entries = match std::fs::read_dir(root.join(dir)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).context(format!("failed to read {:?}", root.join(dir))),
+    };
+    let mut entries: Vec<_> = entries.collect::<std::io::Result<_>>()?;
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        let path = dir.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            if keep.is_some_and(|keep| entry.path().canonicalize().is_ok_and(|p| p == keep)) {
+                continue;
+            }
+            list_local(root, &path, keep, out)?;
+        } else {
+            out.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Where the version `stat` of `path` is downloaded to until it is complete.
+/// The version is told by both size and modification time, since a file
+/// can change within the second that the time is recorded in.
+pub fn partial_path(local_dir: &Path, path: &Path, stat: FileStat) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}-{}", stat.size, stat.mtime));
+    local_dir.join(PARTIAL_DIR).join(name)
+}
+
+/// Removes the partial downloads, once a sync has completed all of them.
+pub fn remove_partial(local_dir: &Path) -> Result<()> {
+    let partial_dir = local_dir.join(PARTIAL_DIR);
+    match std::fs::remove_dir_all(&partial_dir) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).context(format!("failed to remove {partial_dir:?}"))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The directory that files removed by this sync are moved into, named after
+/// the time of the sync.
+pub fn snapshot_dir(base: &Path) -> PathBuf {
+    base.join(Local::now().format("%Y-%m-%d_%H%M%S").to_string())
+}
+
+/// Removes the file at `path` below `local_dir`, moving it below `snapshot`
+/// if given, and then the folders that it leaves empty.
+pub fn remove(local_dir: &Path, path: &Path, snapshot: Option<&Path>) -> Result<()> {
+    let local_path = local_dir.join(path);
+    match snapshot {
+        Some(snapshot) => {
+            let snapshot_path = snapshot.join(path);
+            info!("moving {local_path:?} to {snapshot_path:?}");
+            if let Some(parent) = snapshot_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            // renaming fails across file systems
+            if std::fs::rename(&local_path, &snapshot_path).is_err() {
+                std::fs::copy(&local_path, &snapshot_path).context(format!(
+                    "failed to copy {local_path:?} to {snapshot_path:?}"
+                ))?;
+                std::fs::remove_file(&local_path)?;
+            }
+        }
+        None => {
+            info!("deleting {local_path:?}");
+            std::fs::remove_file(&local_path)
+                .context(format!("failed to delete {local_path:?}"))?;
+        }
+    }
+
+    // removing a folder fails while it still has content
+    for folder in path.ancestors().skip(1) {
+        if folder.as_os_str().is_empty() || std::fs::remove_dir(local_dir.join(folder)).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let local = dir.join("backup");
+        let snapshots = local.join(".snapshots");
+        std::fs::create_dir_all(local.join("nb")).unwrap();
+        std::fs::create_dir_all(local.join("gone")).unwrap();
+        std::fs::create_dir_all(&snapshots).unwrap();
+        std::fs::write(local.join("nb.metadata"), "{}").unwrap();
+        std::fs::write(local.join("nb.content"), "{}").unwrap();
+        std::fs::write(local.join("nb").join("page.rm"), "v1").unwrap();
+        std::fs::write(local.join("gone").join("page.rm"), "v1").unwrap();
+        std::fs::write(local.join("gone.metadata"), "{}").unwrap();
+        std::fs::write(snapshots.join("old.metadata"), "{}").unwrap();
+        let partial = partial_path(
+            &local,
+            Path::new("nb/big.rm"),
+            FileStat { size: 9, mtime: 1 },
+        );
+        std::fs::create_dir_all(partial.parent().unwrap()).unwrap();
+        std::fs::write(&partial, "part").unwrap();
+        assert_ne!(
+            partial,
+            partial_path(
+                &local,
+                Path::new("nb/big.rm"),
+                FileStat { size: 10, mtime: 1 },
+            )
+        );
+
+        let remote_file = |path: &str, size, mtime| RemoteFile {
+            path: path.into(),
+            stat: FileStat { size, mtime },
+        };
+        let remote = vec![
+            remote_file("nb.content", 2, 1000),
+            remote_file("nb.metadata", 2, 1000),
+            remote_file("nb/page.rm", 2, 1000),
+            remote_file("new.metadata", 2, 1000),
+        ];
+
+        // the content was synced, and the metadata is from a backup without
+        // state, and was changed on the device since
+        let mut state = SyncState::load(&local);
+        state
+            .record(&local, Path::new("nb.content"), remote[0].stat)
+            .unwrap();
+        filetime::set_file_mtime(local.join("nb.metadata"), FileTime::from_unix_time(1000, 0))
+            .unwrap();
+        // an older version of the page was synced after it changed on the device
+        state
+            .record(
+                &local,
+                Path::new("nb/page.rm"),
+                FileStat {
+                    size: 2,
+                    mtime: 900,
+                },
+            )
+            .unwrap();
+        filetime::set_file_mtime(local.join("nb/page.rm"), FileTime::from_unix_time(1000, 0))
+            .unwrap();
+        state.save().unwrap();
+
+        let state = SyncState::load(&local);
+        let all = |_: &Path| true;
+        assert_eq!(
+            plan(&remote, &local, &state, &all, false, None).unwrap(),
+            vec![
+                Change::Update("nb/page.rm".into()),
+                Change::Create("new.metadata".into()),
+            ]
+        );
+
+        // a local copy that doesn't match the record is downloaded again
+        std::fs::write(local.join("nb.content"), "{").unwrap();
+        filetime::set_file_mtime(local.join("nb.content"), FileTime::from_unix_time(1000, 0))
+            .unwrap();
+        assert_eq!(
+            plan(&remote, &local, &state, &all, false, None).unwrap()[0],
+            Change::Update("nb.content".into())
+        );
+
+        // neither the state file nor partial downloads are ever deleted
+        let changes = plan(&remote, &local, &state, &all, true, Some(&snapshots)).unwrap();
+        assert_eq!(
+            changes[3..],
+            [
+                Change::Delete("gone/page.rm".into()),
+                Change::Delete("gone.metadata".into()),
+            ]
+        );
+
+        // only files accepted by the filter are deleted
+        let metadata_only = |p: &Path| p.extension().is_some_and(|e| e == "metadata");
+        let changes = plan(
+            &remote,
+            &local,
+            &state,
+            &metadata_only,
+            true,
+            Some(&snapshots),
+        )
+        .unwrap();
+        assert_eq!(changes[3..], [Change::Delete("gone.metadata".into())]);
+
+        // deleting most of the local files needs force
+        let few_remote = &remote[..1];
+        let changes = plan(few_remote, &local, &state, &all, true, Some(&snapshots)).unwrap();
+        assert!(check_deletions(&changes, few_remote, false).is_err());
+        assert!(check_deletions(&changes, few_remote, true).is_ok());
+        let changes = plan(&[], &local, &state, &all, true, Some(&snapshots)).unwrap();
+        assert!(check_deletions(&changes, &[], false).is_err());
+        let changes = plan(&remote, &local, &state, &all, true, Some(&snapshots)).unwrap();
+        assert!(check_deletions(&changes, &remote, false).is_ok());
+
+        let snapshot = snapshot_dir(&snapshots);
+        remove(&local, Path::new("gone/page.rm"), Some(&snapshot)).unwrap();
+        remove(&local, Path::new("gone.metadata"), None).unwrap();
+        assert!(!local.join("gone").exists());
+        assert!(!local.join("gone.metadata").exists());
+        assert!(snapshot.join("gone").join("page.rm").exists());
+
+        remove_partial(&local).unwrap();
+        assert!(!partial.exists());
+        remove_partial(&local).unwrap();
+    }
+}